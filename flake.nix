@@ -27,7 +27,7 @@
         {
           default = pkgs.mkShell rec {
             buildInputs =
-              [ toolchain pkgs.pkg-config pkgs.sqlite ]
+              [ toolchain pkgs.pkg-config pkgs.sqlite pkgs.postgresql ]
               ++
               pkgs.lib.optionals
                 (pkgs.stdenv.hostPlatform.system == "aarch64-darwin"
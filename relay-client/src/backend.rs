@@ -0,0 +1,1781 @@
+//! The task talking to the server on behalf of the frontend.
+//!
+//! Failed requests never end the task: commands report them as a notice,
+//! polls are simply retried, and posts are polled while the stream is down.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::{SinkExt, StreamExt};
+use lay::{
+    bundle::{Bundle, BundleEntry, BundleRequest, PreKey},
+    channel::{Channel, ChannelRequest, ENCRYPTED_KEY},
+    crypto::{random_secret, EncryptionKeyPair, KeyPair, SharedKey},
+    direct::{DirectEntry, DirectMessage, DirectRequest, PreKeyRef, PRE_KEY_KEY},
+    group::{epoch_of, is_leave, is_leaving, GroupKey, GroupKeyRequest, EPOCH_KEY, LEFT_KEY},
+    profile::{Profile, ProfileRequest},
+    resource::Resource,
+    rotation::{KeyStatusRequest, Revocation, Rotation},
+    text::{
+        Cursor, Edit, Post, PostEntry, PostRequest, Reaction, Retraction, Subscribe, RESOURCES_KEY,
+    },
+    Error, ErrorCode, Signed,
+};
+use reqwest::{
+    multipart::{Form, Part},
+    Client, Method,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Map, Value};
+use tokio::{
+    net::TcpStream,
+    sync::mpsc::{Receiver, Sender},
+};
+use tokio_tungstenite::{
+    connect_async, tungstenite::Message as WsMessage, MaybeTlsStream, WebSocketStream,
+};
+
+use crate::{
+    identity,
+    keystore::{Anchor, KeyStore},
+    FrontendCommand, Message, ProfileDisplay, DEFAULT_CHANNEL,
+};
+
+/// How long to wait for pushed posts, or between polls without a stream.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long to wait before trying to reopen a dropped stream.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// How far back a poll asks for retractions before the previous poll, as
+/// their timestamps come from the clock of whoever retracted.
+const RETRACTION_OVERLAP: u64 = 60_000;
+/// How often open direct conversations are polled, they have no stream.
+const DIRECT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Shown instead of content that does not decrypt.
+const UNREADABLE: &str = "(could not decrypt)";
+/// Number of pre-keys uploaded at once.
+const PRE_KEY_BATCH: u32 = 20;
+/// A new batch of pre-keys is uploaded on startup once fewer are left.
+const PRE_KEY_MIN: u64 = 5;
+/// Most rotations followed from a key, each one is a request.
+const MAX_ROTATIONS: usize = 16;
+
+pub enum BackendCommand {
+    Exit,
+    /// Creates the channel if it does not exist, end-to-end encrypted if
+    /// `encrypted` is set.
+    Join {
+        channel: String,
+        encrypted: bool,
+    },
+    Part {
+        channel: String,
+    },
+    LoadHistory {
+        channel: String,
+    },
+    SendMessage {
+        channel: String,
+        content: String,
+        reply_to: Option<String>,
+    },
+    Edit {
+        channel: String,
+        target: String,
+        content: String,
+    },
+    Retract {
+        target: String,
+    },
+    React {
+        target: String,
+        emoji: String,
+        remove: bool,
+    },
+    Attach {
+        channel: String,
+        path: PathBuf,
+    },
+    SendProfile {
+        name: String,
+    },
+    RequestProfile {
+        target: String,
+    },
+    OpenDirect {
+        peer: String,
+    },
+    CloseDirect {
+        peer: String,
+    },
+    SendDirect {
+        peer: String,
+        content: String,
+    },
+    ChangeMembers {
+        channel: String,
+        member: String,
+        remove: bool,
+    },
+    /// Moves to a new identity written to `path`.
+    Rotate {
+        path: PathBuf,
+    },
+    Revoke {
+        reason: Option<String>,
+    },
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Why a request failed. Only [`RequestError::Server`] carries a protocol
+/// [`ErrorCode`], the others never got a usable answer.
+#[derive(Debug)]
+enum RequestError {
+    /// Preparing the request failed before anything was sent.
+    Local(String),
+    /// The server could not be reached or did not finish its answer.
+    Transport(String),
+    /// The answer is neither what was asked for nor an [`Error`].
+    Decode(String),
+    /// The server refused the request.
+    Server(Error),
+}
+
+impl RequestError {
+    /// Whether the server refused the request with `status`.
+    fn is(&self, status: ErrorCode) -> bool {
+        matches!(self, Self::Server(error) if error.status == status)
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Local(message) | Self::Transport(message) | Self::Decode(message) => {
+                f.write_str(message)
+            }
+            Self::Server(error) => f.write_str(&error.message),
+        }
+    }
+}
+
+/// Decodes a response body, which is either a `T` or an [`Error`].
+fn decode<T: DeserializeOwned>(body: &str) -> Result<T, RequestError> {
+    if let Ok(error) = serde_json::from_str::<Error>(body) {
+        return Err(RequestError::Server(error));
+    }
+
+    serde_json::from_str(body)
+        .map_err(|_| RequestError::Decode("Unexpected response from the server!".to_string()))
+}
+
+/// Signs requests with our key and sends them to the server.
+struct Api {
+    client: Client,
+    server: String,
+    key_pair: KeyPair,
+    /// Public key of `key_pair`.
+    own_key: String,
+    last_timestamp: u64,
+}
+
+impl Api {
+    /// Current time in milliseconds, kept strictly increasing because the
+    /// server rejects a timestamp it has already seen for our key.
+    fn next_timestamp(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        self.last_timestamp = now.max(self.last_timestamp + 1);
+        self.last_timestamp
+    }
+
+    fn sign<T: Clone + Serialize>(&mut self, data: T) -> Result<Signed<T>, RequestError> {
+        let timestamp = self.next_timestamp();
+
+        Signed::new(&self.key_pair, self.server.clone(), timestamp, data)
+            .ok_or_else(|| RequestError::Local("Failed to sign the request!".to_string()))
+    }
+
+    /// Sends `body` to `path`, which answers with a `T` or an [`Error`].
+    async fn send<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: &impl Serialize,
+    ) -> Result<T, RequestError> {
+        let body = serde_json::to_string(body)
+            .map_err(|_| RequestError::Local("Failed to encode the request!".to_string()))?;
+
+        let resp = self
+            .client
+            .request(method, format!("{}/{path}", self.server))
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await
+            .map_err(|_| RequestError::Transport("Could not reach the server!".to_string()))?
+            .text()
+            .await
+            .map_err(|_| {
+                RequestError::Transport("The server did not finish its answer!".to_string())
+            })?;
+
+        decode(&resp)
+    }
+
+    /// Signs `request` and fetches the answer from `path`.
+    async fn get<R: Clone + Serialize, T: DeserializeOwned>(
+        &mut self,
+        path: &str,
+        request: R,
+    ) -> Result<T, RequestError> {
+        let req = self.sign(request)?;
+
+        self.send(Method::GET, path, &req).await
+    }
+
+    /// Signs `data` and publishes it at `path`.
+    async fn post<T: Clone + Serialize>(
+        &mut self,
+        path: &str,
+        data: T,
+    ) -> Result<(), RequestError> {
+        let req = self.sign(data)?;
+
+        self.send::<Value>(Method::POST, path, &req)
+            .await
+            .map(|_| ())
+    }
+
+    /// Opens `/stream` and subscribes to `channels`, returns once the server
+    /// acknowledged the subscription.
+    async fn connect_stream(&mut self, channels: Vec<String>) -> Option<Socket> {
+        let url = format!("{}/stream", self.server.replacen("http", "ws", 1));
+        let (mut socket, _) = connect_async(url).await.ok()?;
+
+        let handshake = self
+            .sign(Subscribe {
+                channels,
+                metadata: None,
+            })
+            .ok()?;
+
+        socket
+            .send(WsMessage::Text(serde_json::to_string(&handshake).ok()?))
+            .await
+            .ok()?;
+
+        let ack = tokio::time::timeout(RECONNECT_INTERVAL, socket.next())
+            .await
+            .ok()??
+            .ok()?;
+
+        match ack {
+            WsMessage::Text(ack) if serde_json::from_str::<Subscribe>(&ack).is_ok() => Some(socket),
+            _ => None,
+        }
+    }
+
+    /// Fetches the profile of `target`, which is not verified yet.
+    async fn fetch_profile(&mut self, target: &str) -> Result<Signed<Profile>, RequestError> {
+        self.get(
+            "profile",
+            ProfileRequest {
+                target_key: target.to_string(),
+            },
+        )
+        .await
+    }
+
+    /// Fetches the rotation or revocation of `target` from `path`. `None` if
+    /// there is none, it is not signed by `target` or it could not be fetched.
+    async fn fetch_key_status<T: Clone + Serialize + DeserializeOwned>(
+        &mut self,
+        path: &str,
+        target: &str,
+    ) -> Option<Signed<T>> {
+        self.get::<_, Signed<T>>(
+            path,
+            KeyStatusRequest {
+                target_key: target.to_string(),
+            },
+        )
+        .await
+        .ok()
+        .filter(|it| it.key == target && it.verify())
+    }
+
+    /// Follows the rotations starting at `target` to the key in use now, and
+    /// tells whether `target` itself has been revoked.
+    ///
+    /// Rotations out of a revoked key and into one are not followed, whoever
+    /// holds it might not be who held it before. Neither are ones the new key
+    /// did not countersign, anyone could claim to succeed someone otherwise.
+    async fn follow_rotations(&mut self, target: &str) -> (String, bool) {
+        let revoked = self
+            .fetch_key_status::<Revocation>("revocation", target)
+            .await
+            .is_some();
+
+        let mut current = target.to_string();
+        let mut seen = HashSet::from([current.clone()]);
+
+        while !revoked && seen.len() <= MAX_ROTATIONS {
+            let rotation = self
+                .fetch_key_status::<Rotation>("rotation", &current)
+                .await
+                .filter(|it| it.verify_countersignature());
+
+            let Some(rotation) = rotation else {
+                break;
+            };
+
+            let next = rotation.data.new_key;
+
+            let next_revoked = self
+                .fetch_key_status::<Revocation>("revocation", &next)
+                .await
+                .is_some();
+
+            if next_revoked || !seen.insert(next.clone()) {
+                break;
+            }
+
+            current = next;
+        }
+
+        (current, revoked)
+    }
+
+    /// Fetches the bundle of `target`, claiming a pre-key if `claim_pre_key`
+    /// is set.
+    async fn fetch_bundle(
+        &mut self,
+        target: &str,
+        claim_pre_key: bool,
+    ) -> Result<BundleEntry, RequestError> {
+        let entry: BundleEntry = self
+            .get(
+                "bundle",
+                BundleRequest {
+                    target_key: target.to_string(),
+                    claim_pre_key,
+                },
+            )
+            .await?;
+
+        if !entry.verify(target) {
+            return Err(RequestError::Decode(
+                "Bundle is not signed by its key!".to_string(),
+            ));
+        }
+
+        Ok(entry)
+    }
+
+    async fn fetch_channel(&mut self, name: &str) -> Result<Signed<Channel>, RequestError> {
+        self.get(
+            "channel",
+            ChannelRequest {
+                name: Some(name.to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Makes sure `name` exists on the server, creating it if it does not.
+    async fn ensure_channel(&mut self, name: &str) -> Result<Signed<Channel>, RequestError> {
+        match self.fetch_channel(name).await {
+            Err(error) if error.is(ErrorCode::ChannelNotFound) => {}
+            result => return result,
+        }
+
+        let channel = Channel {
+            name: name.to_string(),
+            topic: None,
+            metadata: None,
+        };
+
+        // losing a race against another client is fine, the channel exists either way
+        match self.post("channel", channel).await {
+            Err(error) if !error.is(ErrorCode::ChannelExists) => return Err(error),
+            _ => {}
+        }
+
+        self.fetch_channel(name).await
+    }
+
+    /// Fetches the group key of `epoch` in `channel`, the latest one without
+    /// `epoch`.
+    async fn fetch_group_key(
+        &mut self,
+        channel: &str,
+        epoch: Option<u64>,
+    ) -> Result<Signed<GroupKey>, RequestError> {
+        self.get(
+            "group",
+            GroupKeyRequest {
+                channel: channel.to_string(),
+                epoch,
+                metadata: None,
+            },
+        )
+        .await
+    }
+
+    /// Uploads the file at `path`, returns its resource id.
+    async fn upload_resource(&mut self, path: &Path) -> Result<String, String> {
+        let data = tokio::fs::read(path).await.map_err(|it| it.to_string())?;
+
+        let id = Resource::id_of(&data);
+
+        let name = path
+            .file_name()
+            .map(|it| it.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let req = self
+            .sign(Resource {
+                id: id.clone(),
+                metadata: Some(Map::from_iter([("name".to_string(), json!(name))])),
+            })
+            .map_err(|it| it.to_string())?;
+
+        let form = Form::new()
+            .text(
+                "resource",
+                serde_json::to_string(&req).map_err(|it| it.to_string())?,
+            )
+            .part("file", Part::bytes(data).file_name(name));
+
+        let body = self
+            .client
+            .post(format!("{}/resource", self.server))
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|it| it.to_string())?
+            .text()
+            .await
+            .map_err(|it| it.to_string())?;
+
+        match decode::<Value>(&body) {
+            Ok(_) => Ok(id),
+            Err(error) if error.is(ErrorCode::ResourceTooLarge) => {
+                Err("file is larger than the server allows".to_string())
+            }
+            Err(error) => Err(error.to_string()),
+        }
+    }
+}
+
+/// Pagination state of a joined channel.
+#[derive(Default)]
+struct ChannelCursors {
+    oldest: Option<Cursor>,
+    newest: Option<Cursor>,
+    /// Timestamp of the last poll, retractions since then are asked for.
+    polled: Option<u64>,
+    /// Secrets of an encrypted channel.
+    group: Option<Group>,
+}
+
+/// Secrets of a joined encrypted channel.
+struct Group {
+    channel: String,
+    /// Creator of the channel, group keys signed by anyone else are ignored.
+    owner: String,
+    /// Key shared with `owner`, which our copy of every secret is sealed with.
+    /// `None` if the owner has not published a bundle.
+    pairwise: Option<SharedKey>,
+    /// Opened secrets by epoch, `None` for epochs we are not a member of.
+    secrets: HashMap<u64, Option<SharedKey>>,
+}
+
+impl Group {
+    /// `None` unless `channel` is encrypted, an error unless its creator
+    /// signed it, as the server could otherwise hand out a channel of its own.
+    async fn of(
+        api: &mut Api,
+        encryption_key: &EncryptionKeyPair,
+        channel: &Signed<Channel>,
+    ) -> Result<Option<Self>, String> {
+        if !channel.verify() {
+            return Err(format!(
+                "#{} is not signed by its creator",
+                channel.data.name
+            ));
+        }
+
+        if !channel.data.is_encrypted() {
+            return Ok(None);
+        }
+
+        let pairwise = api
+            .fetch_bundle(&channel.key, false)
+            .await
+            .ok()
+            .and_then(|it| encryption_key.shared_key(&it.bundle.data.identity_key));
+
+        Ok(Some(Self {
+            channel: channel.data.name.clone(),
+            owner: channel.key.clone(),
+            pairwise,
+            secrets: HashMap::new(),
+        }))
+    }
+
+    /// Looks up the secret of `epoch`, or of the latest epoch without one,
+    /// and returns which epoch that is.
+    async fn load(&mut self, api: &mut Api, epoch: Option<u64>) -> Option<u64> {
+        if let Some(epoch) = epoch.filter(|it| self.secrets.contains_key(it)) {
+            return Some(epoch);
+        }
+
+        let group_key = api
+            .fetch_group_key(&self.channel, epoch)
+            .await
+            .ok()
+            .filter(|it| it.key == self.owner && it.verify())?;
+
+        let secret = group_key
+            .data
+            .keys
+            .get(&api.own_key)
+            .and_then(|it| self.pairwise.as_ref()?.open(it))
+            .and_then(|it| SharedKey::from_secret(&it));
+
+        self.secrets.insert(group_key.data.epoch, secret);
+
+        Some(group_key.data.epoch)
+    }
+
+    /// Seals `content` for the latest epoch, together with the metadata
+    /// naming it. `None` once we are no longer a member.
+    async fn seal(&mut self, api: &mut Api, content: &str) -> Option<(String, Map<String, Value>)> {
+        let epoch = self.load(api, None).await?;
+        let sealed = self
+            .secrets
+            .get(&epoch)?
+            .as_ref()?
+            .seal(content.as_bytes())?;
+
+        Some((
+            sealed,
+            Map::from_iter([(EPOCH_KEY.to_string(), json!(epoch))]),
+        ))
+    }
+
+    /// Opens the content of `entries` and their edits in place, looking up the
+    /// secrets of epochs seen for the first time.
+    async fn open(&mut self, api: &mut Api, entries: &mut [PostEntry]) {
+        let epochs: BTreeSet<u64> = entries
+            .iter()
+            .flat_map(|entry| {
+                std::iter::once(&entry.post.data.metadata)
+                    .chain(entry.edits.iter().map(|it| &it.data.metadata))
+                    .filter_map(epoch_of)
+            })
+            .collect();
+
+        for epoch in epochs {
+            self.load(api, Some(epoch)).await;
+        }
+
+        let open = |content: &str, metadata: &Option<Map<String, Value>>| {
+            epoch_of(metadata)
+                .and_then(|it| self.secrets.get(&it)?.as_ref())
+                .and_then(|it| it.open(content))
+                .and_then(|it| String::from_utf8(it).ok())
+                .unwrap_or_else(|| UNREADABLE.to_string())
+        };
+
+        // tombstones have nothing left to open
+        for entry in entries.iter_mut().filter(|it| !it.is_retracted()) {
+            entry.post.data.content = open(&entry.post.data.content, &entry.post.data.metadata);
+
+            for edit in &mut entry.edits {
+                edit.data.content = open(&edit.data.content, &edit.data.metadata);
+            }
+        }
+    }
+}
+
+/// State of an open direct conversation.
+struct Conversation {
+    /// Encryption key of the peer, `None` while they have not published one.
+    identity: Option<String>,
+    /// Pre-key what we send is sealed to, until then one of the peer's is
+    /// claimed with the first message.
+    pre_key: Option<PreKeyRef>,
+    newest: Option<Cursor>,
+}
+
+/// Replaces the channel set of an open stream, no signature is needed once
+/// the socket is authenticated.
+async fn resubscribe(socket: &mut Socket, channels: Vec<String>) -> bool {
+    let subscribe = Subscribe {
+        channels,
+        metadata: None,
+    };
+
+    let Ok(text) = serde_json::to_string(&subscribe) else {
+        return false;
+    };
+
+    socket.send(WsMessage::Text(text)).await.is_ok()
+}
+
+/// Everything a post is passed on again for when it changes.
+type SeenKey = (String, usize, bool, BTreeMap<String, u64>);
+
+struct Backend {
+    api: Api,
+    frontend: Sender<FrontendCommand>,
+    encryption_key: EncryptionKeyPair,
+    key_store: KeyStore,
+    channels: HashMap<String, ChannelCursors>,
+    conversations: HashMap<String, Conversation>,
+    /// Posts passed on to the frontend, see [`Backend::append`].
+    seen: HashSet<SeenKey>,
+    socket: Option<Socket>,
+    last_connect: Option<Instant>,
+    last_direct_poll: Option<Instant>,
+    /// Whether to poll once before waiting on the stream again.
+    catch_up: bool,
+}
+
+impl Backend {
+    /// Passes `command` on to the frontend.
+    async fn show(&self, command: FrontendCommand) {
+        // a closed frontend ends the loop in `run`
+        let _ = self.frontend.send(command).await;
+    }
+
+    async fn notice(&self, text: String) {
+        self.show(FrontendCommand::Notice { text }).await;
+    }
+
+    async fn run(&mut self, mut commands: Receiver<BackendCommand>) {
+        if let Err(error) = self.ensure_bundle().await {
+            let text = format!("Failed to publish encryption keys: {error}");
+            self.notice(text).await;
+        }
+
+        self.join(DEFAULT_CHANNEL.to_string(), false).await;
+
+        loop {
+            while let Ok(command) = commands.try_recv() {
+                if let BackendCommand::Exit = command {
+                    return;
+                }
+
+                self.handle(command).await;
+            }
+
+            if self.frontend.is_closed() {
+                return;
+            }
+
+            self.poll_direct().await;
+            self.connect().await;
+
+            let messages = self.receive().await;
+            self.append(messages).await;
+        }
+    }
+
+    async fn handle(&mut self, command: BackendCommand) {
+        match command {
+            BackendCommand::Exit => {}
+            BackendCommand::Join { channel, encrypted } => self.join(channel, encrypted).await,
+            BackendCommand::Part { channel } => self.part(channel).await,
+            BackendCommand::LoadHistory { channel } => self.load_history(channel).await,
+            BackendCommand::SendMessage {
+                channel,
+                content,
+                reply_to,
+            } => self.send_message(channel, content, reply_to).await,
+            BackendCommand::Edit {
+                channel,
+                target,
+                content,
+            } => self.edit(channel, target, content).await,
+            BackendCommand::Retract { target } => self.retract(target).await,
+            BackendCommand::React {
+                target,
+                emoji,
+                remove,
+            } => self.react(target, emoji, remove).await,
+            BackendCommand::Attach { channel, path } => self.attach(channel, path).await,
+            BackendCommand::SendProfile { name } => self.send_profile(name).await,
+            BackendCommand::RequestProfile { target } => self.request_profile(target).await,
+            BackendCommand::OpenDirect { peer } => self.open_direct(peer).await,
+            BackendCommand::CloseDirect { peer } => {
+                self.conversations.remove(&peer);
+            }
+            BackendCommand::SendDirect { peer, content } => self.send_direct(peer, content).await,
+            BackendCommand::ChangeMembers {
+                channel,
+                member,
+                remove,
+            } => {
+                let text = self.change_members(&channel, member, remove).await;
+                self.notice(text).await;
+            }
+            BackendCommand::Rotate { path } => self.rotate(path).await,
+            BackendCommand::Revoke { reason } => self.revoke(reason).await,
+        }
+    }
+
+    /// Publishes our bundle unless the server has a current one with enough
+    /// pre-keys left.
+    async fn ensure_bundle(&mut self) -> Result<(), RequestError> {
+        let identity_key = self.encryption_key.public_key().ok_or_else(|| {
+            RequestError::Local("Failed to derive the encryption key!".to_string())
+        })?;
+
+        let own_key = self.api.own_key.clone();
+        let published = match self.api.fetch_bundle(&own_key, false).await {
+            Ok(entry) => Some(entry),
+            Err(error) if error.is(ErrorCode::BundleNotFound) => None,
+            Err(error) => return Err(error),
+        };
+
+        if published.as_ref().is_some_and(|it| {
+            it.bundle.data.identity_key == identity_key && it.remaining >= PRE_KEY_MIN
+        }) {
+            return Ok(());
+        }
+
+        // continue after every id handed out so far, a claimed pre-key is
+        // never reused
+        let first = published
+            .iter()
+            .flat_map(|it| &it.bundle.data.pre_keys)
+            .map(|it| it.id + 1)
+            .chain([self.key_store.next_pre_key_id()])
+            .max()
+            .unwrap_or(0);
+
+        let mut pre_keys = Vec::new();
+
+        for id in first..first + PRE_KEY_BATCH {
+            let secret = EncryptionKeyPair::generate()
+                .ok_or_else(|| RequestError::Local("Failed to generate a pre-key!".to_string()))?;
+            let key = secret
+                .public_key()
+                .ok_or_else(|| RequestError::Local("Failed to generate a pre-key!".to_string()))?;
+
+            self.key_store.insert_pre_key(id, &secret);
+            pre_keys.push(PreKey { id, key });
+        }
+
+        // nothing may be sealed to a pre-key whose secret we could lose
+        self.key_store
+            .save()
+            .map_err(|it| RequestError::Local(format!("Failed to store pre-keys: {it}")))?;
+
+        self.api
+            .post(
+                "bundle",
+                Bundle {
+                    identity_key,
+                    pre_keys,
+                    metadata: None,
+                },
+            )
+            .await
+    }
+
+    /// Creates the encrypted channel `name` with us as its only member.
+    async fn create_private_channel(&mut self, name: &str) -> Result<Signed<Channel>, String> {
+        let channel = self
+            .api
+            .sign(Channel {
+                name: name.to_string(),
+                topic: None,
+                metadata: Some(Map::from_iter([(ENCRYPTED_KEY.to_string(), json!(true))])),
+            })
+            .map_err(|it| it.to_string())?;
+
+        match self
+            .api
+            .send::<Value>(Method::POST, "channel", &channel)
+            .await
+        {
+            Ok(_) => {}
+            Err(error) if error.is(ErrorCode::ChannelExists) => {
+                return Err(format!("#{name} already exists"));
+            }
+            Err(error) => return Err(error.to_string()),
+        }
+
+        let members = BTreeSet::from([channel.key.clone()]);
+        self.publish_group_key(name, 0, members).await?;
+
+        Ok(channel)
+    }
+
+    /// Seals a new secret to the bundles of `members` and publishes it as
+    /// `epoch` of `channel`.
+    async fn publish_group_key(
+        &mut self,
+        channel: &str,
+        epoch: u64,
+        members: BTreeSet<String>,
+    ) -> Result<(), String> {
+        let secret = random_secret().ok_or("failed to generate a secret")?;
+
+        let mut keys = BTreeMap::new();
+
+        for member in members {
+            let bundle = self
+                .api
+                .fetch_bundle(&member, false)
+                .await
+                .map_err(|it| format!("{}: {it}", &member[..8]))?;
+
+            let sealed = self
+                .encryption_key
+                .shared_key(&bundle.bundle.data.identity_key)
+                .and_then(|it| it.seal(&secret));
+
+            let Some(sealed) = sealed else {
+                return Err(format!(
+                    "{} has not published an encryption key",
+                    &member[..8]
+                ));
+            };
+
+            keys.insert(member, sealed);
+        }
+
+        let group_key = GroupKey {
+            channel: channel.to_string(),
+            epoch,
+            keys,
+            metadata: None,
+        };
+
+        self.api
+            .post("group", group_key)
+            .await
+            .map_err(|it| it.to_string())
+    }
+
+    /// Seals `content` if `channel` is encrypted, together with the metadata
+    /// naming the epoch. `None` once we are no longer a member, or if we never
+    /// joined, as we cannot tell whether it is encrypted then.
+    async fn seal(
+        &mut self,
+        channel: &str,
+        content: String,
+    ) -> Option<(String, Option<Map<String, Value>>)> {
+        let Some(cursors) = self.channels.get_mut(channel) else {
+            self.notice(format!("You have not joined #{channel}")).await;
+            return None;
+        };
+
+        let Some(group) = cursors.group.as_mut() else {
+            return Some((content, None));
+        };
+
+        let sealed = group.seal(&mut self.api, &content).await;
+
+        if sealed.is_none() {
+            self.notice(format!("You are not a member of #{channel}"))
+                .await;
+        }
+
+        sealed.map(|(sealed, metadata)| (sealed, Some(metadata)))
+    }
+
+    async fn join(&mut self, channel: String, encrypted: bool) {
+        let mut created = None;
+
+        if encrypted {
+            match self.create_private_channel(&channel).await {
+                Ok(info) => created = Some(info),
+                Err(text) => self.notice(text).await,
+            }
+        }
+
+        // joins whatever is there if creating it failed
+        let info = match created {
+            Some(info) => Ok(info),
+            None => self.api.ensure_channel(&channel).await,
+        };
+
+        let group = match &info {
+            Ok(info) => {
+                let group = Group::of(&mut self.api, &self.encryption_key, info)
+                    .await
+                    .and_then(|group| self.pin_owner(&channel, info).map(|_| group));
+
+                match group {
+                    Ok(group) => group,
+                    Err(text) => return self.notice(format!("Not joining: {text}")).await,
+                }
+            }
+            Err(error) => {
+                let text = format!("Failed to join #{channel}: {error}");
+                self.notice(text).await;
+                None
+            }
+        };
+
+        if group.is_some() {
+            self.show(FrontendCommand::Encrypted {
+                channel: channel.clone(),
+            })
+            .await;
+        }
+
+        self.channels.insert(
+            channel,
+            ChannelCursors {
+                group,
+                ..Default::default()
+            },
+        );
+
+        self.resubscribe().await;
+
+        // load the newest page of the new channel
+        self.catch_up = true;
+    }
+
+    /// Remembers the creator of `channel` the first time we join it, and
+    /// refuses it once the server hands out one signed by anyone else.
+    fn pin_owner(&mut self, channel: &str, info: &Signed<Channel>) -> Result<(), String> {
+        if info.data.name != channel {
+            return Err(format!(
+                "the server sent #{} for #{channel}",
+                info.data.name
+            ));
+        }
+
+        match self.key_store.channel_owner(channel) {
+            Some(owner) if owner == info.key => Ok(()),
+            Some(_) => Err(format!("#{channel} has a different creator than before")),
+            None => {
+                self.key_store
+                    .insert_channel_owner(channel.to_string(), info.key.clone());
+
+                self.key_store
+                    .save()
+                    .map_err(|it| format!("failed to remember the creator of #{channel}: {it}"))
+            }
+        }
+    }
+
+    async fn part(&mut self, channel: String) {
+        let group = self
+            .channels
+            .remove(&channel)
+            .and_then(|it| it.group)
+            .filter(|it| it.owner != self.api.own_key);
+
+        if let Some(mut group) = group {
+            self.leave(&channel, &mut group).await;
+        }
+
+        self.resubscribe().await;
+    }
+
+    /// Posts that we left encrypted `channel`, so its creator rekeys without
+    /// us and we cannot read what is posted afterwards.
+    async fn leave(&mut self, channel: &str, group: &mut Group) {
+        // not a member of the latest epoch, there is nothing to leave
+        let Some((content, mut metadata)) = group.seal(&mut self.api, "").await else {
+            return;
+        };
+
+        metadata.insert(LEFT_KEY.to_string(), json!(true));
+
+        let post = Post {
+            channel: channel.to_string(),
+            content,
+            reply_to: None,
+            metadata: Some(metadata),
+        };
+
+        if let Err(error) = self.api.post("text", post).await {
+            let text =
+                format!("Failed to leave #{channel}, ask its creator to remove you: {error}");
+            self.notice(text).await;
+        }
+    }
+
+    async fn resubscribe(&mut self) {
+        if let Some(socket) = self.socket.as_mut() {
+            if !resubscribe(socket, self.channels.keys().cloned().collect()).await {
+                self.socket = None;
+            }
+        }
+    }
+
+    async fn load_history(&mut self, channel: String) {
+        let Some(cursor) = self
+            .channels
+            .get(&channel)
+            .and_then(|it| it.oldest.as_ref())
+        else {
+            return;
+        };
+
+        let request = PostRequest {
+            channel: channel.clone(),
+            before: Some(cursor.to_string()),
+            ..Default::default()
+        };
+
+        let mut messages: Vec<PostEntry> = match self.api.get("text", request).await {
+            Ok(messages) => messages,
+            Err(error) => {
+                let text = format!("Failed to load history: {error}");
+                return self.notice(text).await;
+            }
+        };
+
+        let Some(cursors) = self.channels.get_mut(&channel) else {
+            return;
+        };
+
+        let Some(first) = messages.first() else {
+            return;
+        };
+
+        // page on past forged posts instead of asking for them again
+        cursors.oldest = Some(Cursor::of(&first.post));
+
+        messages.retain(PostEntry::is_authentic);
+
+        for entry in &mut messages {
+            entry.retain_valid_edits();
+        }
+
+        if let Some(group) = cursors.group.as_mut() {
+            group.open(&mut self.api, &mut messages).await;
+        }
+
+        self.show(FrontendCommand::PrependMessages {
+            channel,
+            messages: messages.iter().map(Message::from).collect(),
+        })
+        .await;
+    }
+
+    async fn send_message(&mut self, channel: String, content: String, reply_to: Option<String>) {
+        let Some((content, metadata)) = self.seal(&channel, content).await else {
+            return;
+        };
+
+        let post = Post {
+            channel: channel.clone(),
+            content,
+            reply_to,
+            metadata,
+        };
+
+        let text = match self.api.post("text", post).await {
+            Ok(()) => return,
+            Err(error) if error.is(ErrorCode::ChannelNotFound) => {
+                format!("#{channel} does not exist on this server")
+            }
+            Err(error) => format!("Failed to send: {error}"),
+        };
+
+        self.notice(text).await;
+    }
+
+    async fn edit(&mut self, channel: String, target: String, content: String) {
+        let Some((content, metadata)) = self.seal(&channel, content).await else {
+            return;
+        };
+
+        let edit = Edit {
+            target,
+            content,
+            metadata,
+        };
+
+        // the edited post comes back through the stream
+        if let Err(error) = self.api.post("edit", edit).await {
+            self.notice(format!("Failed to edit: {error}")).await;
+        }
+    }
+
+    async fn retract(&mut self, target: String) {
+        let retraction = Retraction {
+            target,
+            metadata: None,
+        };
+
+        // the tombstone comes back through the stream
+        if let Err(error) = self.api.post("retraction", retraction).await {
+            self.notice(format!("Failed to retract: {error}")).await;
+        }
+    }
+
+    async fn react(&mut self, target: String, emoji: String, remove: bool) {
+        let reaction = Reaction {
+            target,
+            emoji,
+            remove,
+            metadata: None,
+        };
+
+        // the new counts come back through the stream
+        if let Err(error) = self.api.post("reaction", reaction).await {
+            self.notice(format!("Failed to react: {error}")).await;
+        }
+    }
+
+    async fn attach(&mut self, channel: String, path: PathBuf) {
+        let name = path
+            .file_name()
+            .map(|it| it.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let result = match self.api.upload_resource(&path).await {
+            Ok(id) => {
+                let post = Post {
+                    channel,
+                    content: name.clone(),
+                    reply_to: None,
+                    metadata: Some(Map::from_iter([(RESOURCES_KEY.to_string(), json!([id]))])),
+                };
+
+                self.api
+                    .post("text", post)
+                    .await
+                    .map_err(|it| it.to_string())
+            }
+            Err(error) => Err(error),
+        };
+
+        let text = match result {
+            Ok(()) => format!("Attached {name}"),
+            Err(error) => format!("Failed to attach {}: {error}", path.display()),
+        };
+
+        self.notice(text).await;
+    }
+
+    async fn send_profile(&mut self, name: String) {
+        let profile = Profile {
+            name,
+            metadata: None,
+        };
+
+        if let Err(error) = self.api.post("profile", profile).await {
+            self.notice(format!("Failed to set name: {error}")).await;
+        }
+    }
+
+    async fn request_profile(&mut self, target: String) {
+        let (current, revoked) = self.api.follow_rotations(&target).await;
+
+        let mut profile = self
+            .api
+            .fetch_profile(&current)
+            .await
+            .ok()
+            .filter(|it| it.key == current);
+
+        // the new key might not have a profile yet
+        if profile.is_none() && current != target {
+            profile = self
+                .api
+                .fetch_profile(&target)
+                .await
+                .ok()
+                .filter(|it| it.key == target);
+        }
+
+        let (name, verified) = match profile {
+            Some(profile) => {
+                let verified = profile.verify();
+                (profile.data.name, verified)
+            }
+            None => ("Guest".to_string(), false),
+        };
+
+        self.show(FrontendCommand::RespondProfile {
+            profile: ProfileDisplay {
+                successor: (current != target).then_some(current),
+                key: target,
+                name,
+                verified,
+                revoked,
+            },
+        })
+        .await;
+    }
+
+    async fn open_direct(&mut self, peer: String) {
+        let identity = match self.api.fetch_bundle(&peer, false).await {
+            Ok(entry) => Some(entry.bundle.data.identity_key),
+            Err(error) if !error.is(ErrorCode::BundleNotFound) => {
+                let text = format!("Failed to open a conversation: {error}");
+                return self.notice(text).await;
+            }
+            Err(_) => None,
+        };
+
+        if identity.is_none() {
+            let name = self
+                .api
+                .fetch_profile(&peer)
+                .await
+                .ok()
+                .filter(|it| it.key == peer && it.verify())
+                .map_or(peer.clone(), |it| it.data.name);
+
+            self.notice(format!("{name} has not published an encryption key"))
+                .await;
+        }
+
+        let pre_key = self.key_store.anchor(&peer).map(|it| PreKeyRef {
+            owner: peer.clone(),
+            id: it.id,
+        });
+
+        self.conversations.insert(
+            peer,
+            Conversation {
+                identity,
+                pre_key,
+                newest: None,
+            },
+        );
+
+        // load the newest page right away
+        self.last_direct_poll = None;
+    }
+
+    async fn send_direct(&mut self, peer: String, content: String) {
+        let Some(conversation) = self.conversations.get(&peer) else {
+            return;
+        };
+
+        if conversation.identity.is_none() {
+            let text = "Cannot encrypt to someone without an encryption key".to_string();
+            return self.notice(text).await;
+        }
+
+        let pre_key = match conversation.pre_key.clone() {
+            Some(pre_key) => Some(pre_key),
+            None => self.claim_pre_key(&peer).await,
+        };
+
+        let sealed = self
+            .direct_key(&peer, pre_key.as_ref())
+            .and_then(|it| it.seal(content.as_bytes()));
+
+        let Some(content) = sealed else {
+            return self
+                .notice("Failed to encrypt the message".to_string())
+                .await;
+        };
+
+        let metadata = pre_key.map(|it| Map::from_iter([(PRE_KEY_KEY.to_string(), json!(it))]));
+
+        let message = DirectMessage {
+            recipient: peer,
+            content,
+            metadata,
+        };
+
+        // the message comes back with the next poll
+        if let Err(error) = self.api.post("direct", message).await {
+            self.notice(format!("Failed to send: {error}")).await;
+        }
+    }
+
+    /// Claims a pre-key of `peer` for our conversation and remembers it.
+    /// `None` if they have none left, messages are then sealed to the
+    /// identity keys alone.
+    async fn claim_pre_key(&mut self, peer: &str) -> Option<PreKeyRef> {
+        let entry = self.api.fetch_bundle(peer, true).await.ok()?;
+        let claimed = entry.claimed()?;
+
+        let pre_key = PreKeyRef {
+            owner: peer.to_string(),
+            id: claimed.id,
+        };
+        let anchor = Anchor {
+            id: claimed.id,
+            key: claimed.key.clone(),
+        };
+        self.key_store.insert_anchor(peer.to_string(), anchor);
+
+        // without the pre-key we could not read what we sent after a restart
+        if let Err(error) = self.key_store.save() {
+            self.notice(format!("Failed to store the pre-key: {error}"))
+                .await;
+        }
+
+        // the pre-key belongs to the identity key of this bundle
+        let conversation = self.conversations.get_mut(peer)?;
+        conversation.identity = Some(entry.bundle.data.identity_key.clone());
+        conversation.pre_key = Some(pre_key.clone());
+
+        Some(pre_key)
+    }
+
+    /// Key of the messages with `peer` sealed to `pre_key`, or to the
+    /// identity keys alone.
+    fn direct_key(&self, peer: &str, pre_key: Option<&PreKeyRef>) -> Option<SharedKey> {
+        let identity = self.conversations.get(peer)?.identity.as_deref()?;
+
+        match pre_key {
+            None => self.encryption_key.shared_key(identity),
+            Some(it) if it.owner == self.api.own_key => {
+                let secret = self.key_store.pre_key(it.id)?;
+
+                self.encryption_key
+                    .shared_key_from_pre_key(identity, &secret)
+            }
+            Some(it) if it.owner == peer => {
+                let anchor = self
+                    .key_store
+                    .anchor(peer)
+                    .filter(|anchor| anchor.id == it.id)?;
+
+                self.encryption_key
+                    .shared_key_to_pre_key(identity, &anchor.key)
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// Readable form of a stored message with `peer`, which might not
+    /// decrypt.
+    fn direct_message(&self, peer: &str, entry: &DirectEntry) -> Message {
+        let message = &entry.message;
+
+        let content = self
+            .direct_key(peer, message.data.pre_key().as_ref())
+            .and_then(|it| it.open(&message.data.content))
+            .and_then(|it| String::from_utf8(it).ok())
+            .unwrap_or_else(|| UNREADABLE.to_string());
+
+        Message {
+            id: entry.id.clone(),
+            sender: message.key.clone(),
+            content,
+            history: Vec::new(),
+            attachments: Vec::new(),
+            reply_to: None,
+            reactions: BTreeMap::new(),
+            retracted: false,
+        }
+    }
+
+    /// Invites or removes `member` and rekeys `channel`, returns what
+    /// happened.
+    async fn change_members(&mut self, channel: &str, member: String, remove: bool) -> String {
+        let owner = self
+            .channels
+            .get(channel)
+            .and_then(|it| it.group.as_ref())
+            .map(|it| it.owner.clone());
+
+        let own_key = self.api.own_key.clone();
+
+        match owner {
+            None => return format!("#{channel} is not encrypted"),
+            Some(owner) if owner != own_key => {
+                return format!("Only the creator of #{channel} can change its members");
+            }
+            Some(_) if remove && member == own_key => {
+                return format!("The creator of #{channel} cannot be removed");
+            }
+            Some(_) => {}
+        }
+
+        let latest = match self.api.fetch_group_key(channel, None).await {
+            Ok(latest) => Some(latest).filter(|it| it.key == own_key && it.verify()),
+            Err(error) if error.is(ErrorCode::GroupKeyNotFound) => None,
+            Err(error) => return format!("Failed to change members: {error}"),
+        };
+
+        let (mut members, epoch) = match latest {
+            Some(it) => (it.data.keys.into_keys().collect(), it.data.epoch + 1),
+            None => (BTreeSet::from([own_key]), 0),
+        };
+
+        let changed = if remove {
+            members.remove(&member)
+        } else {
+            members.insert(member.clone())
+        };
+
+        let short = &member[..8];
+
+        if !changed {
+            return format!(
+                "{short} is already {}",
+                if remove { "not a member" } else { "a member" }
+            );
+        }
+
+        match self.publish_group_key(channel, epoch, members).await {
+            Ok(()) if remove => format!("Removed {short}, #{channel} rekeyed"),
+            Ok(()) => format!("Invited {short} to #{channel}"),
+            Err(error) => format!("Failed to change members: {error}"),
+        }
+    }
+
+    /// Creates a new identity at `path`, publishes the rotation to it and
+    /// carries the profile over. Returns the new key.
+    async fn rotate_identity(&mut self, path: &Path) -> Result<String, String> {
+        let new_key_pair = identity::create(path).map_err(|it| it.to_string())?;
+        let new_key = new_key_pair
+            .public_key()
+            .ok_or("failed to read the new key")?
+            .to_base64();
+
+        let timestamp = self.api.next_timestamp();
+        let rotation = Signed::<Rotation>::countersigned(
+            &self.api.key_pair,
+            &new_key_pair,
+            self.api.server.clone(),
+            timestamp,
+        )
+        .ok_or("failed to sign the rotation")?;
+
+        self.api
+            .send::<Value>(Method::POST, "rotation", &rotation)
+            .await
+            .map_err(|it| it.to_string())?;
+
+        let own_key = self.api.own_key.clone();
+
+        if let Ok(profile) = self.api.fetch_profile(&own_key).await {
+            let timestamp = self.api.next_timestamp();
+            let profile = Signed::new(
+                &new_key_pair,
+                self.api.server.clone(),
+                timestamp,
+                profile.data,
+            )
+            .ok_or("failed to sign the profile")?;
+
+            // the rotation already happened, the name can be set again later
+            if let Err(error) = self
+                .api
+                .send::<Value>(Method::POST, "profile", &profile)
+                .await
+            {
+                let text = format!("Failed to carry over the profile: {error}");
+                self.notice(text).await;
+            }
+        }
+
+        Ok(new_key)
+    }
+
+    async fn rotate(&mut self, path: PathBuf) {
+        let text = match self.rotate_identity(&path).await {
+            Ok(key) => format!(
+                "Rotated to {}, restart with --identity {}",
+                &key[..8],
+                path.display()
+            ),
+            Err(error) => format!("Failed to rotate: {error}"),
+        };
+
+        self.notice(text).await;
+    }
+
+    async fn revoke(&mut self, reason: Option<String>) {
+        let revocation = Revocation {
+            reason,
+            metadata: None,
+        };
+
+        let text = match self.api.post("revocation", revocation).await {
+            Ok(()) => "Revoked this key, it can no longer be trusted".to_string(),
+            Err(error) => format!("Failed to revoke: {error}"),
+        };
+
+        self.notice(text).await;
+    }
+
+    /// Polls open direct conversations, they have no stream.
+    async fn poll_direct(&mut self) {
+        if self
+            .last_direct_poll
+            .is_some_and(|it| it.elapsed() < DIRECT_POLL_INTERVAL)
+        {
+            return;
+        }
+
+        self.last_direct_poll = Some(Instant::now());
+
+        let peers: Vec<String> = self.conversations.keys().cloned().collect();
+
+        for peer in peers {
+            let Some(conversation) = self.conversations.get(&peer) else {
+                continue;
+            };
+
+            let request = DirectRequest {
+                peer: peer.clone(),
+                after: conversation.newest.as_ref().map(Cursor::to_string),
+                ..Default::default()
+            };
+
+            // a failed poll is retried with the next one
+            let Ok(entries) = self.api.get::<_, Vec<DirectEntry>>("direct", request).await else {
+                continue;
+            };
+
+            let Some(last) = entries.last() else {
+                continue;
+            };
+
+            let own_key = self.api.own_key.clone();
+
+            if let Some(conversation) = self.conversations.get_mut(&peer) {
+                conversation.newest = Some(Cursor::of(&last.message));
+
+                // answer on the pre-key of ours the peer started with
+                if conversation.pre_key.is_none() {
+                    conversation.pre_key = entries
+                        .iter()
+                        .filter(|it| it.message.key == peer)
+                        .filter_map(|it| it.message.data.pre_key())
+                        .find(|it| it.owner == own_key);
+                }
+            }
+
+            let messages = entries
+                .iter()
+                .map(|it| self.direct_message(&peer, it))
+                .collect();
+
+            self.show(FrontendCommand::AppendDirect { peer, messages })
+                .await;
+        }
+    }
+
+    /// (Re)connects the live stream, posts are polled while it is down.
+    async fn connect(&mut self) {
+        if self.socket.is_some()
+            || self
+                .last_connect
+                .is_some_and(|it| it.elapsed() < RECONNECT_INTERVAL)
+        {
+            return;
+        }
+
+        self.last_connect = Some(Instant::now());
+        self.socket = self
+            .api
+            .connect_stream(self.channels.keys().cloned().collect())
+            .await;
+
+        // poll once more to fill the gap before the subscription
+        self.catch_up = self.socket.is_some();
+    }
+
+    /// Waits for a pushed post, or polls every channel while the stream is
+    /// down or catching up.
+    async fn receive(&mut self) -> Vec<PostEntry> {
+        let mut messages = Vec::new();
+
+        match self.socket.as_mut() {
+            Some(socket) if !self.catch_up => {
+                match tokio::time::timeout(POLL_INTERVAL, socket.next()).await {
+                    Ok(Some(Ok(WsMessage::Text(text)))) => {
+                        if let Ok(post) = serde_json::from_str(&text) {
+                            messages.push(post);
+                        }
+                    }
+                    // the socket dropped, poll until it is back
+                    Ok(Some(Err(_))) | Ok(None) => self.socket = None,
+                    // nothing pushed in time, or a control frame
+                    _ => {}
+                }
+            }
+            _ => {
+                // poll messages, the first poll of a channel loads its newest page
+                for (channel, cursors) in self.channels.iter_mut() {
+                    let request = PostRequest {
+                        channel: channel.clone(),
+                        after: cursors.newest.as_ref().map(Cursor::to_string),
+                        retracted_since: cursors
+                            .polled
+                            .map(|it| it.saturating_sub(RETRACTION_OVERLAP)),
+                        ..Default::default()
+                    };
+
+                    let Ok(req) = self.api.sign(request) else {
+                        continue;
+                    };
+
+                    // a failed poll is retried with the next one
+                    if let Ok(page) = self
+                        .api
+                        .send::<Vec<PostEntry>>(Method::GET, "text", &req)
+                        .await
+                    {
+                        messages.extend(page);
+                        cursors.polled = Some(req.timestamp);
+                    }
+                }
+
+                if !self.catch_up {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+
+                self.catch_up = false;
+            }
+        }
+
+        messages
+    }
+
+    /// Opens and passes on `messages` to the channels they belong to. Members
+    /// leaving a channel we created are removed from it.
+    async fn append(&mut self, mut messages: Vec<PostEntry>) {
+        for entry in &mut messages {
+            entry.retain_valid_edits();
+        }
+
+        // a post may arrive through both the stream and a poll, edited,
+        // retracted and reacted to posts arrive again
+        messages.retain(|entry| {
+            self.seen.insert((
+                entry.id.clone(),
+                entry.edits.len(),
+                entry.is_retracted(),
+                entry.reactions.clone(),
+            ))
+        });
+
+        // (channel, member, whether to rekey) of members who left
+        let mut left = Vec::new();
+
+        for (channel, cursors) in self.channels.iter_mut() {
+            let mut posts: Vec<PostEntry> = messages
+                .iter()
+                .filter(|entry| &entry.post.data.channel == channel)
+                .cloned()
+                .collect();
+
+            let Some(first) = posts.first() else {
+                continue;
+            };
+
+            if cursors.oldest.is_none() {
+                cursors.oldest = Some(Cursor::of(&first.post));
+            }
+            cursors.newest = posts
+                .iter()
+                .map(|entry| Cursor::of(&entry.post))
+                .chain(cursors.newest.take())
+                .max();
+
+            // the server may have forged them, the author is trusted below
+            posts.retain(PostEntry::is_authentic);
+
+            if let Some(group) = cursors.group.as_mut() {
+                group.open(&mut self.api, &mut posts).await;
+            }
+
+            let (leaves, posts): (Vec<PostEntry>, Vec<PostEntry>) = posts
+                .into_iter()
+                .partition(|entry| is_leave(&entry.post.data.metadata));
+
+            let owner = cursors.group.as_ref().map(|it| &it.owner);
+
+            // only the creator can rekey, against the epoch the leave is for
+            let latest = match owner {
+                Some(owner) if *owner == self.api.own_key && !leaves.is_empty() => self
+                    .api
+                    .fetch_group_key(channel, None)
+                    .await
+                    .ok()
+                    .filter(|it| it.key == *owner && it.verify()),
+                _ => None,
+            };
+
+            for entry in leaves {
+                let rekey = latest
+                    .as_ref()
+                    .is_some_and(|it| is_leaving(&entry.post, &it.data));
+
+                left.push((channel.clone(), entry.post.key.clone(), rekey));
+            }
+
+            // a closed frontend ends the loop in `run`
+            let _ = self
+                .frontend
+                .send(FrontendCommand::AppendMessages {
+                    channel: channel.clone(),
+                    messages: posts.iter().map(Message::from).collect(),
+                })
+                .await;
+        }
+
+        for (channel, member, rekey) in left {
+            let text = if rekey {
+                self.change_members(&channel, member, true).await
+            } else {
+                format!("{} left #{channel}", &member[..8])
+            };
+
+            self.notice(text).await;
+        }
+    }
+}
+
+/// Talks to `server` for the frontend until it sends
+/// [`BackendCommand::Exit`].
+pub async fn backend(
+    chan: (Sender<FrontendCommand>, Receiver<BackendCommand>),
+    key_pair: KeyPair,
+    key_store: KeyStore,
+    server: String,
+) {
+    let (frontend, commands) = chan;
+
+    let encryption_key = EncryptionKeyPair::derive(&key_pair).unwrap();
+    let own_key = key_pair.public_key().unwrap().to_base64();
+
+    let mut backend = Backend {
+        api: Api {
+            client: Client::new(),
+            server,
+            key_pair,
+            own_key,
+            last_timestamp: 0,
+        },
+        frontend,
+        encryption_key,
+        key_store,
+        channels: HashMap::new(),
+        conversations: HashMap::new(),
+        seen: HashSet::new(),
+        socket: None,
+        last_connect: None,
+        last_direct_poll: None,
+        catch_up: false,
+    };
+
+    backend.run(commands).await;
+}
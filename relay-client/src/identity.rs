@@ -0,0 +1,101 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use lay::crypto::KeyPair;
+
+/// Default identity file, `$XDG_DATA_HOME/relay/identity.pk8`.
+pub fn default_path() -> Option<PathBuf> {
+    let data_home = match std::env::var_os("XDG_DATA_HOME") {
+        Some(it) if !it.is_empty() => PathBuf::from(it),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/share"),
+    };
+
+    Some(data_home.join("relay").join("identity.pk8"))
+}
+
+/// Passphrase used to seal new identities and unseal encrypted ones.
+const PASSPHRASE_VAR: &str = "RELAY_PASSPHRASE";
+
+fn passphrase() -> Option<String> {
+    std::env::var(PASSPHRASE_VAR)
+        .ok()
+        .filter(|it| !it.is_empty())
+}
+
+/// Loads the identity at `path`, creating a new one on first use.
+///
+/// The file is either a plain PKCS#8 document or a sealed key file, which
+/// is unsealed with `$RELAY_PASSPHRASE`.
+pub fn load_or_create(path: &Path) -> io::Result<KeyPair> {
+    match fs::read(path) {
+        Ok(document) if KeyPair::is_sealed(&document) => {
+            let passphrase = passphrase().ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} is encrypted, set {PASSPHRASE_VAR}", path.display()),
+                )
+            })?;
+
+            KeyPair::unseal(&document, &passphrase).map_err(|err| {
+                io::Error::new(ErrorKind::InvalidData, format!("{}: {err}", path.display()))
+            })
+        }
+        Ok(document) => KeyPair::from_pkcs8(&document).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not a valid identity", path.display()),
+            )
+        }),
+        Err(err) if err.kind() == ErrorKind::NotFound => create(path),
+        Err(err) => Err(err),
+    }
+}
+
+/// Creates a new identity at `path`, which must not exist yet.
+pub fn create(path: &Path) -> io::Result<KeyPair> {
+    let document =
+        KeyPair::generate_pkcs8().ok_or_else(|| io::Error::other("failed to generate a key"))?;
+
+    if let Some(dir) = path.parent() {
+        create_private_dir(dir)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let key_pair = KeyPair::from_pkcs8(&document)
+        .ok_or_else(|| io::Error::other("generated an invalid key"))?;
+
+    let contents = match passphrase() {
+        Some(passphrase) => key_pair
+            .seal(&passphrase)
+            .ok_or_else(|| io::Error::other("failed to encrypt the key"))?,
+        None => document,
+    };
+
+    options.open(path)?.write_all(&contents)?;
+
+    Ok(key_pair)
+}
+
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+
+    builder.create(dir)
+}
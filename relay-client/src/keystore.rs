@@ -0,0 +1,131 @@
+//! What an identity keeps between sessions besides its key: the one-time
+//! pre-keys it published, the pre-keys of others its conversations are
+//! sealed to and the creators of the channels it joined.
+//!
+//! Kept next to the identity file and sealed with a key derived from it, so
+//! they are exactly as safe as the identity itself.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use lay::crypto::{EncryptionKeyPair, KeyPair, SharedKey};
+use serde::{Deserialize, Serialize};
+
+/// A pre-key of a peer we sealed a conversation to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Anchor {
+    pub id: u32,
+    /// The public key, the peer may publish a new bundle without it.
+    pub key: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Contents {
+    /// Secrets of our pre-keys by id.
+    #[serde(rename = "preKeys")]
+    pre_keys: BTreeMap<u32, String>,
+    /// Pre-key each conversation we started is sealed to, by peer.
+    anchors: BTreeMap<String, Anchor>,
+    /// Key of the creator of each channel, as first seen.
+    owners: BTreeMap<String, String>,
+}
+
+pub struct KeyStore {
+    path: PathBuf,
+    key: SharedKey,
+    contents: Contents,
+}
+
+impl KeyStore {
+    /// Opens the store of the identity at `identity`, which is empty until
+    /// something is saved.
+    pub fn load(identity: &Path, key_pair: &KeyPair) -> io::Result<Self> {
+        let path = identity.with_extension("keys");
+        let key = SharedKey::derive_local(key_pair)
+            .ok_or_else(|| io::Error::other("failed to derive the key store key"))?;
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(sealed) => key
+                .open(sealed.trim())
+                .and_then(|it| serde_json::from_slice(&it).ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{} does not belong to this identity", path.display()),
+                    )
+                })?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Contents::default(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(Self {
+            path,
+            key,
+            contents,
+        })
+    }
+
+    /// Writes the store, replacing the previous file only once the new one
+    /// is complete.
+    pub fn save(&self) -> io::Result<()> {
+        let contents = serde_json::to_vec(&self.contents).map_err(io::Error::other)?;
+        let sealed = self
+            .key
+            .seal(&contents)
+            .ok_or_else(|| io::Error::other("failed to encrypt the key store"))?;
+
+        let partial = self.path.with_extension("keys.partial");
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(&partial)?;
+        file.write_all(sealed.as_bytes())?;
+        file.sync_all()?;
+
+        fs::rename(&partial, &self.path)
+    }
+
+    /// The first id no pre-key of ours has had yet.
+    pub fn next_pre_key_id(&self) -> u32 {
+        self.contents
+            .pre_keys
+            .last_key_value()
+            .map_or(0, |(id, _)| id + 1)
+    }
+
+    pub fn insert_pre_key(&mut self, id: u32, pre_key: &EncryptionKeyPair) {
+        self.contents.pre_keys.insert(id, pre_key.to_base64());
+    }
+
+    pub fn pre_key(&self, id: u32) -> Option<EncryptionKeyPair> {
+        EncryptionKeyPair::from_base64(self.contents.pre_keys.get(&id)?)
+    }
+
+    pub fn anchor(&self, peer: &str) -> Option<&Anchor> {
+        self.contents.anchors.get(peer)
+    }
+
+    pub fn insert_anchor(&mut self, peer: String, anchor: Anchor) {
+        self.contents.anchors.insert(peer, anchor);
+    }
+
+    pub fn channel_owner(&self, channel: &str) -> Option<&str> {
+        self.contents.owners.get(channel).map(String::as_str)
+    }
+
+    pub fn insert_channel_owner(&mut self, channel: String, owner: String) {
+        self.contents.owners.insert(channel, owner);
+    }
+}
@@ -1,45 +1,116 @@
+mod backend;
+mod identity;
+mod keystore;
+
 use std::{
-    collections::HashMap,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    collections::{BTreeMap, HashMap, HashSet},
+    path::PathBuf,
+    time::Duration,
 };
 
+use backend::{backend, BackendCommand};
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use lay::{
-    crypto::KeyPair,
-    profile::{Profile, ProfileRequest},
-    text::{Post, PostRequest},
-    Signed,
-};
+use keystore::KeyStore;
+use lay::{crypto::PublicKey, text::PostEntry};
 use ratatui::{
     prelude::{Backend, Constraint, CrosstermBackend, Layout},
-    style::{Color, Style, Stylize},
+    style::{Color, Modifier, Style, Stylize},
     text::{Line, Span, Text},
     widgets::{Block, Borders, Paragraph, Scrollbar, ScrollbarOrientation, ScrollbarState},
     Frame, Terminal,
 };
-use reqwest::Client;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 
+/// Channel joined on startup, created if the server does not know it yet.
+const DEFAULT_CHANNEL: &str = "general";
+/// Width of the channel list on the left.
+const SIDEBAR_WIDTH: u16 = 20;
+/// Characters of the parent shown above a reply.
+const QUOTE_LENGTH: usize = 40;
+
 #[derive(Clone)]
 struct Message {
+    id: String,
     sender: String,
+    /// Content of the latest revision.
     content: String,
+    /// Earlier revisions, oldest first.
+    history: Vec<String>,
+    attachments: Vec<String>,
+    reply_to: Option<String>,
+    /// Number of keys per emoji.
+    reactions: BTreeMap<String, u64>,
+    retracted: bool,
+}
+
+impl From<&PostEntry> for Message {
+    fn from(entry: &PostEntry) -> Self {
+        let post = &entry.post;
+
+        let history = std::iter::once(&post.data.content)
+            .chain(entry.edits.iter().map(|it| &it.data.content))
+            .take(entry.edits.len())
+            .cloned()
+            .collect();
+
+        Self {
+            id: entry.id.clone(),
+            sender: post.key.clone(),
+            content: entry.content().to_string(),
+            history,
+            attachments: post
+                .data
+                .resources()
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            reply_to: post.data.reply_to.clone(),
+            reactions: entry.reactions.clone(),
+            retracted: entry.is_retracted(),
+        }
+    }
 }
 
 #[derive(Clone)]
 struct ProfileDisplay {
     key: String,
+    /// Name of the profile of `successor`, if it has one.
     name: String,
     verified: bool,
+    /// Whether `key` has been revoked, nothing it signed can be trusted.
+    revoked: bool,
+    /// The key `key` has been rotated to, at the end of the chain.
+    successor: Option<String>,
 }
 
 enum FrontendCommand {
-    DisplayMessages { messages: Vec<Message> },
-    RespondProfile { profile: ProfileDisplay },
+    AppendMessages {
+        channel: String,
+        messages: Vec<Message>,
+    },
+    PrependMessages {
+        channel: String,
+        messages: Vec<Message>,
+    },
+    AppendDirect {
+        peer: String,
+        messages: Vec<Message>,
+    },
+    RespondProfile {
+        profile: ProfileDisplay,
+    },
+    /// Shown in the status line until the next key press.
+    Notice {
+        text: String,
+    },
+    /// `channel` turned out to be end-to-end encrypted.
+    Encrypted {
+        channel: String,
+    },
 }
 
 enum Mode {
@@ -48,41 +119,199 @@ enum Mode {
     Command,
 }
 
-struct State {
-    mode: Mode,
-    input: String,
+/// Messages and scroll position of a joined channel or a direct
+/// conversation.
+struct Buffer {
+    name: String,
+    /// Key of the other side of a direct conversation, whose `name` is that
+    /// key too.
+    peer: Option<String>,
+    /// Whether the channel is end-to-end encrypted.
+    encrypted: bool,
     messages: Vec<Message>,
-    cursor_position: usize,
+    /// Ids of `messages`, history pages may overlap what is already shown.
+    ids: HashSet<String>,
+    /// Id of the message selected in normal mode.
+    selected: Option<String>,
+    unread: usize,
     autoscroll: bool,
     vertical_scroll_state: ScrollbarState,
     horizontal_scroll_state: ScrollbarState,
     vertical_scroll: usize,
     horizontal_scroll: usize,
-    command_buffer: String,
-    users: HashMap<String, ProfileDisplay>,
-    unknown_users: Vec<String>,
 }
 
-impl Default for State {
-    fn default() -> Self {
+impl Buffer {
+    fn new(name: String) -> Self {
         Self {
-            mode: Mode::Normal,
-            input: String::new(),
+            name,
+            peer: None,
+            encrypted: false,
             messages: Vec::new(),
-            cursor_position: 0,
+            ids: HashSet::new(),
+            selected: None,
+            unread: 0,
             autoscroll: true,
             vertical_scroll_state: ScrollbarState::default(),
             horizontal_scroll_state: ScrollbarState::default(),
             vertical_scroll: 0,
             horizontal_scroll: 0,
+        }
+    }
+
+    /// Replaces messages that are already in the buffer, e.g. after an edit,
+    /// drops retracted ones and returns the new ones.
+    fn merge(&mut self, messages: Vec<Message>) -> Vec<Message> {
+        let mut new = Vec::new();
+
+        for message in messages {
+            if message.retracted {
+                // the id stays known, so an older copy does not bring it back
+                self.ids.insert(message.id.clone());
+                self.messages.retain(|it| it.id != message.id);
+            } else if self.ids.insert(message.id.clone()) {
+                new.push(message);
+            } else if let Some(it) = self.messages.iter_mut().find(|it| it.id == message.id) {
+                *it = message;
+            }
+        }
+
+        new
+    }
+
+    fn direct(peer: String) -> Self {
+        Self {
+            peer: Some(peer.clone()),
+            ..Self::new(peer)
+        }
+    }
+
+    /// `#channel` with an indicator if it is encrypted, or `@name` of a direct
+    /// conversation.
+    fn title(&self, users: &HashMap<String, ProfileDisplay>) -> String {
+        match &self.peer {
+            Some(peer) => match users.get(peer) {
+                Some(user) => format!("@{}", user.name),
+                None => format!("@{}", &peer[..peer.len().min(8)]),
+            },
+            None if self.encrypted => format!("#{} [e2e]", self.name),
+            None => format!("#{}", self.name),
+        }
+    }
+
+    fn selected_index(&self) -> Option<usize> {
+        let id = self.selected.as_ref()?;
+        self.messages.iter().position(|it| it.id == *id)
+    }
+
+    /// Moves the selection `count` messages up, starting below the newest.
+    fn select_up(&mut self, count: usize) {
+        let index = self
+            .selected_index()
+            .unwrap_or(self.messages.len())
+            .saturating_sub(count);
+
+        self.selected = self.messages.get(index).map(|it| it.id.clone());
+    }
+
+    /// Moves the selection `count` messages down, past the newest clears it.
+    fn select_down(&mut self, count: usize) {
+        let Some(index) = self.selected_index() else {
+            return;
+        };
+
+        self.selected = self.messages.get(index + count).map(|it| it.id.clone());
+    }
+
+    fn scroll_vertical(&mut self, position: usize) {
+        self.autoscroll = false;
+        self.vertical_scroll = position;
+        self.vertical_scroll_state = self
+            .vertical_scroll_state
+            .position(self.vertical_scroll as u16);
+    }
+
+    fn scroll_horizontal(&mut self, position: usize) {
+        self.autoscroll = false;
+        self.horizontal_scroll = position;
+        self.horizontal_scroll_state = self
+            .horizontal_scroll_state
+            .position(self.horizontal_scroll as u16);
+    }
+}
+
+struct State {
+    /// Our own public key, to find the messages we may edit.
+    key: String,
+    mode: Mode,
+    input: String,
+    buffers: Vec<Buffer>,
+    current: usize,
+    cursor_position: usize,
+    command_buffer: String,
+    users: HashMap<String, ProfileDisplay>,
+    unknown_users: Vec<String>,
+    /// Show earlier revisions below edited messages.
+    show_history: bool,
+    /// Message the input is sent as a reply to.
+    reply_to: Option<String>,
+}
+
+impl State {
+    fn new(key: String) -> Self {
+        Self {
+            key,
+            mode: Mode::Normal,
+            input: String::new(),
+            buffers: vec![Buffer::new(DEFAULT_CHANNEL.to_string())],
+            current: 0,
+            cursor_position: 0,
             command_buffer: String::new(),
             users: HashMap::new(),
             unknown_users: Vec::new(),
+            show_history: false,
+            reply_to: None,
         }
     }
-}
 
-impl State {
+    fn buffer(&mut self) -> &mut Buffer {
+        &mut self.buffers[self.current]
+    }
+
+    fn find_buffer(&self, channel: &str) -> Option<usize> {
+        self.buffers
+            .iter()
+            .position(|it| it.peer.is_none() && it.name == channel)
+    }
+
+    /// Key of the verified user called `target`, or `target` itself if it is
+    /// a key.
+    fn resolve_user(&self, target: &str) -> Option<String> {
+        self.users
+            .values()
+            .find(|it| it.verified && !it.revoked && it.successor.is_none() && it.name == target)
+            .map(|it| it.key.clone())
+            .or_else(|| PublicKey::from_base64(target).map(|_| target.to_string()))
+    }
+
+    fn find_direct(&self, peer: &str) -> Option<usize> {
+        self.buffers
+            .iter()
+            .position(|it| it.peer.as_deref() == Some(peer))
+    }
+
+    fn switch_to(&mut self, index: usize) {
+        self.current = index;
+        self.buffer().unread = 0;
+    }
+
+    /// Takes the count typed before a motion, defaulting to one.
+    fn take_count(&mut self) -> usize {
+        let count = self.command_buffer.parse::<usize>().unwrap_or(1);
+        self.command_buffer.clear();
+        count
+    }
+
     fn clamp_cursor(&self, position: usize) -> usize {
         position.clamp(0, self.input.len())
     }
@@ -114,6 +343,28 @@ impl State {
     }
 }
 
+/// Name shown for `key`, unknown keys are queued to have their profile
+/// requested.
+fn display_name(
+    users: &HashMap<String, ProfileDisplay>,
+    unknown_users: &mut Vec<String>,
+    key: &str,
+) -> String {
+    match users.get(key) {
+        Some(user) if user.revoked => format!("{} (revoked)", user.name),
+        Some(user) if !user.verified => format!("{} (unverified)", user.name),
+        Some(user) if user.successor.is_some() => format!("{} (rotated)", user.name),
+        Some(user) => user.name.clone(),
+        None => {
+            if !unknown_users.iter().any(|it| it == key) {
+                unknown_users.push(key.to_string());
+            }
+
+            "Guest".to_string()
+        }
+    }
+}
+
 fn draw_ui<B: Backend>(state: &mut State, frame: &mut Frame<B>, server: &str) {
     let chunks = Layout::default()
         .direction(ratatui::prelude::Direction::Vertical)
@@ -174,13 +425,25 @@ fn draw_ui<B: Backend>(state: &mut State, frame: &mut Frame<B>, server: &str) {
     let help_message = Paragraph::new(text);
     frame.render_widget(help_message, chunks[3]);
 
+    let mut input_block = Block::default().borders(Borders::ALL);
+
+    if let Some(parent) = state.reply_to.as_ref().and_then(|id| {
+        state.buffers[state.current]
+            .messages
+            .iter()
+            .find(|it| it.id == *id)
+    }) {
+        let sender = display_name(&state.users, &mut state.unknown_users, &parent.sender);
+        input_block = input_block.title(format!("Reply to {sender}"));
+    }
+
     let input = Paragraph::new(state.input.as_str())
         .style(match state.mode {
             Mode::Normal => Style::default(),
             Mode::Input => Style::default().fg(Color::Gray),
             Mode::Command => Style::default(),
         })
-        .block(Block::default().borders(Borders::ALL));
+        .block(input_block);
     frame.render_widget(input, chunks[2]);
 
     match state.mode {
@@ -192,51 +455,161 @@ fn draw_ui<B: Backend>(state: &mut State, frame: &mut Frame<B>, server: &str) {
         Mode::Command => {}
     }
 
-    let messages: Vec<Line> = state
+    let body = Layout::default()
+        .direction(ratatui::prelude::Direction::Horizontal)
+        .constraints([Constraint::Length(SIDEBAR_WIDTH), Constraint::Min(1)].as_ref())
+        .split(chunks[1]);
+
+    let channels: Vec<Line> = state
+        .buffers
+        .iter()
+        .enumerate()
+        .map(|(i, buffer)| {
+            let label = if buffer.unread > 0 {
+                format!("{} ({})", buffer.title(&state.users), buffer.unread)
+            } else {
+                buffer.title(&state.users)
+            };
+
+            let style = if i == state.current {
+                Style::default().bg(Color::DarkGray).fg(Color::White)
+            } else if buffer.unread > 0 {
+                Style::default().add_modifier(Modifier::BOLD)
+            } else {
+                Style::default()
+            };
+
+            Line::from(Span::styled(label, style))
+        })
+        .collect();
+
+    let sidebar =
+        Paragraph::new(channels).block(Block::default().borders(Borders::ALL).title("Channels"));
+    frame.render_widget(sidebar, body[0]);
+
+    let buffer = &mut state.buffers[state.current];
+
+    let messages: Vec<Line> = buffer
         .messages
         .iter()
-        .map(|m| {
-            let sender = if state.users.contains_key(&m.sender) {
-                state.users[&m.sender].name.as_str()
+        .flat_map(|m| {
+            let sender = display_name(&state.users, &mut state.unknown_users, &m.sender);
+
+            let mut lines = Vec::new();
+
+            // replies are indented below a quote of their parent
+            let indent = if let Some(parent) = &m.reply_to {
+                let quote = match buffer.messages.iter().find(|it| it.id == *parent) {
+                    Some(parent) => {
+                        let sender =
+                            display_name(&state.users, &mut state.unknown_users, &parent.sender);
+                        let mut snippet: String =
+                            parent.content.chars().take(QUOTE_LENGTH).collect();
+
+                        if parent.content.chars().count() > QUOTE_LENGTH {
+                            snippet.push('…');
+                        }
+
+                        format!("  ┌ {sender}: {snippet}")
+                    }
+                    // not loaded yet, or retracted
+                    None => "  ┌ (message not shown)".to_string(),
+                };
+
+                lines.push(Line::from(Span::styled(
+                    quote,
+                    Style::default().fg(Color::DarkGray),
+                )));
+
+                "  "
             } else {
-                if !state.unknown_users.contains(&m.sender) {
-                    state.unknown_users.push(m.sender.clone());
-                }
+                ""
+            };
+
+            let mut line = format!("{indent}{}: {}", sender, m.content);
+
+            for id in &m.attachments {
+                line.push_str(&format!(" [file {}]", &id[..id.len().min(12)]));
+            }
 
-                "Guest"
+            let style = if buffer.selected.as_ref() == Some(&m.id) {
+                Style::default().bg(Color::DarkGray).fg(Color::White)
+            } else {
+                Style::default()
             };
 
-            Line::from(Span::raw(format!("{}: {}", sender, m.content)))
+            let mut main = Line::from(Span::styled(line, style));
+
+            if !m.history.is_empty() {
+                main.spans.push(Span::styled(
+                    " (edited)",
+                    Style::default().fg(Color::DarkGray),
+                ));
+            }
+
+            lines.push(main);
+
+            if !m.reactions.is_empty() {
+                let tally: Vec<String> = m
+                    .reactions
+                    .iter()
+                    .map(|(emoji, count)| format!("{emoji} {count}"))
+                    .collect();
+
+                lines.push(Line::from(Span::styled(
+                    format!("{indent}  {}", tally.join("  ")),
+                    Style::default().fg(Color::DarkGray),
+                )));
+            }
+
+            if state.show_history {
+                lines.extend(m.history.iter().map(|it| {
+                    Line::from(Span::styled(
+                        format!("{indent}  was: {it}"),
+                        Style::default().fg(Color::DarkGray),
+                    ))
+                }));
+            }
+
+            lines
         })
         .collect();
+    let line_count = messages.len();
 
-    state.vertical_scroll_state = state
+    buffer.vertical_scroll_state = buffer
         .vertical_scroll_state
         .content_length(messages.len() as u16);
-    state.horizontal_scroll_state = state.horizontal_scroll_state.content_length(50);
+    buffer.horizontal_scroll_state = buffer.horizontal_scroll_state.content_length(50);
 
     let messages = Paragraph::new(messages)
-        .block(Block::default().borders(Borders::ALL).title("Messages"))
-        .scroll((state.vertical_scroll as u16, state.horizontal_scroll as u16));
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(buffer.title(&state.users)),
+        )
+        .scroll((
+            buffer.vertical_scroll as u16,
+            buffer.horizontal_scroll as u16,
+        ));
 
     // handle autoscroll
-    if state.autoscroll && state.messages.len() > chunks[1].height as usize {
-        state.vertical_scroll = state.messages.len() - chunks[1].height as usize + 2;
-        state.vertical_scroll_state = state
+    if buffer.autoscroll && line_count > body[1].height as usize {
+        buffer.vertical_scroll = line_count - body[1].height as usize + 2;
+        buffer.vertical_scroll_state = buffer
             .vertical_scroll_state
-            .position(state.vertical_scroll as u16);
+            .position(buffer.vertical_scroll as u16);
     }
 
-    frame.render_widget(messages, chunks[1]);
+    frame.render_widget(messages, body[1]);
     frame.render_stateful_widget(
         Scrollbar::default().orientation(ScrollbarOrientation::VerticalRight),
-        chunks[1],
-        &mut state.vertical_scroll_state,
+        body[1],
+        &mut buffer.vertical_scroll_state,
     );
     frame.render_stateful_widget(
         Scrollbar::default().orientation(ScrollbarOrientation::HorizontalBottom),
-        chunks[1],
-        &mut state.horizontal_scroll_state,
+        body[1],
+        &mut buffer.horizontal_scroll_state,
     );
 }
 
@@ -244,8 +617,9 @@ async fn frontend<B: Backend>(
     mut chan: (Sender<BackendCommand>, Receiver<FrontendCommand>),
     terminal: &mut Terminal<B>,
     server: String,
+    key: String,
 ) {
-    let mut state = State::default();
+    let mut state = State::new(key);
 
     'l: loop {
         let mut redraw = false;
@@ -255,13 +629,59 @@ async fn frontend<B: Backend>(
             redraw = true;
 
             match cmd {
-                FrontendCommand::DisplayMessages { messages } => {
-                    // TODO: This should append new messages.
-                    state.messages = messages;
+                FrontendCommand::AppendMessages { channel, messages } => {
+                    // posts for a channel we just parted
+                    let Some(index) = state.find_buffer(&channel) else {
+                        continue;
+                    };
+
+                    let buffer = &mut state.buffers[index];
+                    let messages = buffer.merge(messages);
+
+                    if index != state.current {
+                        buffer.unread += messages.len();
+                    }
+
+                    buffer.messages.extend(messages);
+                }
+                FrontendCommand::AppendDirect { peer, messages } => {
+                    // a conversation we just closed
+                    let Some(index) = state.find_direct(&peer) else {
+                        continue;
+                    };
+
+                    let buffer = &mut state.buffers[index];
+                    let messages = buffer.merge(messages);
+
+                    if index != state.current {
+                        buffer.unread += messages.len();
+                    }
+
+                    buffer.messages.extend(messages);
+                }
+                FrontendCommand::PrependMessages { channel, messages } => {
+                    let Some(index) = state.find_buffer(&channel) else {
+                        continue;
+                    };
+
+                    // keep the same lines in view
+                    let buffer = &mut state.buffers[index];
+                    let messages = buffer.merge(messages);
+                    buffer.vertical_scroll += messages.len();
+                    buffer.vertical_scroll_state = buffer
+                        .vertical_scroll_state
+                        .position(buffer.vertical_scroll as u16);
+                    buffer.messages.splice(0..0, messages);
                 }
                 FrontendCommand::RespondProfile { profile } => {
                     state.users.insert(profile.key.clone(), profile);
                 }
+                FrontendCommand::Notice { text } => state.command_buffer = text,
+                FrontendCommand::Encrypted { channel } => {
+                    if let Some(index) = state.find_buffer(&channel) {
+                        state.buffers[index].encrypted = true;
+                    }
+                }
             }
         }
 
@@ -293,63 +713,84 @@ async fn frontend<B: Backend>(
                             break 'l;
                         }
                         KeyCode::Char('h') | KeyCode::Left => {
-                            let offset = state.command_buffer.parse::<usize>().unwrap_or(1);
-                            state.command_buffer.clear();
-
-                            state.autoscroll = false;
-
-                            state.horizontal_scroll =
-                                state.horizontal_scroll.saturating_sub(offset);
-                            state.horizontal_scroll_state = state
-                                .horizontal_scroll_state
-                                .position(state.horizontal_scroll as u16);
+                            let offset = state.take_count();
+                            let buffer = state.buffer();
+                            buffer
+                                .scroll_horizontal(buffer.horizontal_scroll.saturating_sub(offset));
                         }
                         KeyCode::Char('j') | KeyCode::Down => {
-                            let offset = state.command_buffer.parse::<usize>().unwrap_or(1);
-                            state.command_buffer.clear();
-
-                            state.autoscroll = false;
-
-                            state.vertical_scroll = state.vertical_scroll.saturating_add(offset);
-                            state.vertical_scroll_state = state
-                                .vertical_scroll_state
-                                .position(state.vertical_scroll as u16);
+                            let offset = state.take_count();
+                            let buffer = state.buffer();
+                            buffer.scroll_vertical(buffer.vertical_scroll.saturating_add(offset));
                         }
                         KeyCode::Char('k') | KeyCode::Up => {
-                            let offset = state.command_buffer.parse::<usize>().unwrap_or(1);
-                            state.command_buffer.clear();
-
-                            state.autoscroll = false;
-
-                            state.vertical_scroll = state.vertical_scroll.saturating_sub(offset);
-                            state.vertical_scroll_state = state
-                                .vertical_scroll_state
-                                .position(state.vertical_scroll as u16);
+                            let offset = state.take_count();
+                            let buffer = state.buffer();
+                            buffer.scroll_vertical(buffer.vertical_scroll.saturating_sub(offset));
+
+                            // reached the top, fetch the previous page
+                            if buffer.vertical_scroll == 0 && buffer.peer.is_none() {
+                                let channel = buffer.name.clone();
+
+                                chan.0
+                                    .send(BackendCommand::LoadHistory { channel })
+                                    .await
+                                    .unwrap();
+                            }
                         }
                         KeyCode::Char('l') | KeyCode::Right => {
-                            let offset = state.command_buffer.parse::<usize>().unwrap_or(1);
-                            state.command_buffer.clear();
-
-                            state.autoscroll = false;
-
-                            state.horizontal_scroll =
-                                state.horizontal_scroll.saturating_add(offset);
-                            state.horizontal_scroll_state = state
-                                .horizontal_scroll_state
-                                .position(state.horizontal_scroll as u16);
+                            let offset = state.take_count();
+                            let buffer = state.buffer();
+                            buffer
+                                .scroll_horizontal(buffer.horizontal_scroll.saturating_add(offset));
+                        }
+                        KeyCode::Char('K') => {
+                            let count = state.take_count();
+                            state.buffer().select_up(count);
+                        }
+                        KeyCode::Char('J') => {
+                            let count = state.take_count();
+                            state.buffer().select_down(count);
+                        }
+                        KeyCode::Char('r') => {
+                            if let Some(id) = state.buffer().selected.take() {
+                                state.reply_to = Some(id);
+                                state.mode = Mode::Input;
+                                state.command_buffer.clear();
+                            }
+                        }
+                        KeyCode::Char('+') if state.buffer().selected.is_some() => {
+                            state.mode = Mode::Command;
+                            state.command_buffer = ":react ".to_string();
+                        }
+                        KeyCode::Esc => state.buffer().selected = None,
+                        KeyCode::Char('s') => state.buffer().autoscroll = true,
+                        // previous and next channel
+                        KeyCode::Char('[') => {
+                            let count = state.buffers.len();
+                            state.switch_to((state.current + count - 1) % count);
                         }
-                        KeyCode::Char('s') => state.autoscroll = true,
-                        KeyCode::Char(c) if c.is_digit(10) => state.command_buffer.push(c),
+                        KeyCode::Char(']') => {
+                            state.switch_to((state.current + 1) % state.buffers.len());
+                        }
+                        KeyCode::Char(c) if c.is_ascii_digit() => state.command_buffer.push(c),
                         _ => {}
                     },
                     Mode::Input if key.kind == KeyEventKind::Press => match key.code {
-                        KeyCode::Enter if state.input.len() > 0 => {
-                            chan.0
-                                .send(BackendCommand::SendMessage {
+                        KeyCode::Enter if !state.input.is_empty() => {
+                            let command = match state.buffer().peer.clone() {
+                                Some(peer) => BackendCommand::SendDirect {
+                                    peer,
+                                    content: state.input.clone(),
+                                },
+                                None => BackendCommand::SendMessage {
+                                    channel: state.buffer().name.clone(),
                                     content: state.input.clone(),
-                                })
-                                .await
-                                .unwrap();
+                                    reply_to: state.reply_to.take(),
+                                },
+                            };
+
+                            chan.0.send(command).await.unwrap();
                             state.input.clear();
                             state.reset_cursor();
                         }
@@ -359,13 +800,17 @@ async fn frontend<B: Backend>(
                         KeyCode::Right => state.move_cursor_right(),
                         KeyCode::Up => state.reset_cursor(),
                         KeyCode::Down => state.cursor_position = state.input.len(),
-                        KeyCode::Esc => state.mode = Mode::Normal,
+                        KeyCode::Esc => {
+                            state.reply_to = None;
+                            state.mode = Mode::Normal;
+                        }
                         _ => {}
                     },
                     Mode::Command => match key.code {
                         KeyCode::Enter => {
                             // TODO: Proper command API.
                             let args: Vec<&str> = state.command_buffer.split_whitespace().collect();
+                            let mut notice = String::new();
 
                             match args[0] {
                                 ":profile" if args.len() == 2 => {
@@ -377,10 +822,191 @@ async fn frontend<B: Backend>(
                                         .unwrap();
                                 }
                                 ":refresh-profiles" if args.len() == 1 => state.users.clear(),
+                                ":join" | ":private" if args.len() == 2 => {
+                                    let channel = args[1].trim_start_matches('#').to_string();
+                                    let encrypted = args[0] == ":private";
+
+                                    match state.find_buffer(&channel) {
+                                        Some(index) => state.switch_to(index),
+                                        None => {
+                                            state.buffers.push(Buffer::new(channel.clone()));
+                                            state.switch_to(state.buffers.len() - 1);
+
+                                            chan.0
+                                                .send(BackendCommand::Join { channel, encrypted })
+                                                .await
+                                                .unwrap();
+                                        }
+                                    }
+                                }
+                                // the last channel can not be parted
+                                ":part" if args.len() == 1 && state.buffers.len() > 1 => {
+                                    let buffer = state.buffers.remove(state.current);
+                                    let current = state.current.min(state.buffers.len() - 1);
+                                    state.switch_to(current);
+
+                                    let command = match buffer.peer {
+                                        Some(peer) => BackendCommand::CloseDirect { peer },
+                                        None => BackendCommand::Part {
+                                            channel: buffer.name,
+                                        },
+                                    };
+
+                                    chan.0.send(command).await.unwrap();
+                                }
+                                ":dm" if args.len() == 2 => {
+                                    let target = args[1].trim_start_matches('@');
+
+                                    match state.resolve_user(target) {
+                                        Some(peer) => match state.find_direct(&peer) {
+                                            Some(index) => state.switch_to(index),
+                                            None => {
+                                                state.buffers.push(Buffer::direct(peer.clone()));
+                                                state.switch_to(state.buffers.len() - 1);
+
+                                                chan.0
+                                                    .send(BackendCommand::OpenDirect { peer })
+                                                    .await
+                                                    .unwrap();
+                                            }
+                                        },
+                                        None => notice = format!("No one known as {target}"),
+                                    }
+                                }
+                                ":invite" | ":remove" if args.len() == 2 => {
+                                    let buffer = &state.buffers[state.current];
+                                    let target = args[1].trim_start_matches('@');
+
+                                    match state.resolve_user(target) {
+                                        Some(member) if buffer.encrypted => {
+                                            chan.0
+                                                .send(BackendCommand::ChangeMembers {
+                                                    channel: buffer.name.clone(),
+                                                    member,
+                                                    remove: args[0] == ":remove",
+                                                })
+                                                .await
+                                                .unwrap();
+                                        }
+                                        Some(_) => {
+                                            notice = format!("#{} is not encrypted", buffer.name)
+                                        }
+                                        None => notice = format!("No one known as {target}"),
+                                    }
+                                }
+                                ":attach" if args.len() == 2 => {
+                                    let buffer = &state.buffers[state.current];
+
+                                    if buffer.encrypted || buffer.peer.is_some() {
+                                        notice = "Attachments are not encrypted".to_string();
+                                    } else {
+                                        chan.0
+                                            .send(BackendCommand::Attach {
+                                                channel: buffer.name.clone(),
+                                                path: PathBuf::from(args[1]),
+                                            })
+                                            .await
+                                            .unwrap();
+                                    }
+                                }
+                                ":edit" if args.len() > 1 => {
+                                    let buffer = &state.buffers[state.current];
+                                    let target = buffer
+                                        .messages
+                                        .iter()
+                                        .rev()
+                                        .find(|it| it.sender == state.key)
+                                        .map(|it| it.id.clone());
+
+                                    match target {
+                                        Some(target) => {
+                                            // everything after the command, spacing intact
+                                            let content = state.command_buffer[":edit".len()..]
+                                                .trim()
+                                                .to_string();
+
+                                            chan.0
+                                                .send(BackendCommand::Edit {
+                                                    channel: buffer.name.clone(),
+                                                    target,
+                                                    content,
+                                                })
+                                                .await
+                                                .unwrap();
+                                        }
+                                        None => notice = "No message of yours to edit".to_string(),
+                                    }
+                                }
+                                ":retract" if args.len() == 1 => {
+                                    let buffer = &state.buffers[state.current];
+                                    let target = buffer
+                                        .messages
+                                        .iter()
+                                        .rev()
+                                        .find(|it| it.sender == state.key)
+                                        .map(|it| it.id.clone());
+
+                                    match target {
+                                        Some(target) => {
+                                            chan.0
+                                                .send(BackendCommand::Retract { target })
+                                                .await
+                                                .unwrap();
+                                        }
+                                        None => {
+                                            notice = "No message of yours to retract".to_string()
+                                        }
+                                    }
+                                }
+                                ":react" | ":unreact" if args.len() == 2 => {
+                                    match state.buffers[state.current].selected.clone() {
+                                        Some(target) => {
+                                            chan.0
+                                                .send(BackendCommand::React {
+                                                    target,
+                                                    emoji: args[1].to_string(),
+                                                    remove: args[0] == ":unreact",
+                                                })
+                                                .await
+                                                .unwrap();
+                                        }
+                                        None => notice = "No message selected".to_string(),
+                                    }
+                                }
+                                ":rotate" if args.len() == 2 => {
+                                    chan.0
+                                        .send(BackendCommand::Rotate {
+                                            path: PathBuf::from(args[1]),
+                                        })
+                                        .await
+                                        .unwrap();
+                                }
+                                ":revoke" => {
+                                    // everything after the command, spacing intact
+                                    let reason = state.command_buffer[":revoke".len()..].trim();
+
+                                    chan.0
+                                        .send(BackendCommand::Revoke {
+                                            reason: (!reason.is_empty())
+                                                .then(|| reason.to_string()),
+                                        })
+                                        .await
+                                        .unwrap();
+                                }
+                                ":history" if args.len() == 1 => {
+                                    state.show_history = !state.show_history;
+                                }
+                                ":switch" if args.len() == 2 => {
+                                    let channel = args[1].trim_start_matches('#');
+
+                                    if let Some(index) = state.find_buffer(channel) {
+                                        state.switch_to(index);
+                                    }
+                                }
                                 _ => {}
                             }
 
-                            state.command_buffer.clear();
+                            state.command_buffer = notice;
                             state.mode = Mode::Normal;
                         }
                         KeyCode::Backspace => {
@@ -401,176 +1027,17 @@ async fn frontend<B: Backend>(
     }
 }
 
-enum BackendCommand {
-    Exit,
-    SendMessage { content: String },
-    SendProfile { name: String },
-    RequestProfile { target: String },
-}
-
-async fn backend(
-    mut chan: (Sender<FrontendCommand>, Receiver<BackendCommand>),
-    key_pair: KeyPair,
-    server: String,
-) {
-    let client = Client::new();
-    let text_url = format!("{server}/text");
-    let profile_url = format!("{server}/profile");
-
-    'l: loop {
-        // process commands
-        while let Ok(cmd) = chan.1.try_recv() {
-            match cmd {
-                BackendCommand::Exit => break 'l,
-                BackendCommand::SendMessage { content } => {
-                    let post = Signed::new(
-                        &key_pair,
-                        server.clone(),
-                        SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_millis() as u64,
-                        Post {
-                            channel: "general".to_string(),
-                            content,
-                            metadata: None,
-                        },
-                    )
-                    .unwrap();
-
-                    client
-                        .post(&text_url)
-                        .header("Content-Type", "application/json")
-                        .body(serde_json::to_string(&post).unwrap())
-                        .send()
-                        .await
-                        .unwrap();
-                }
-                BackendCommand::SendProfile { name } => {
-                    let profile = Signed::new(
-                        &key_pair,
-                        server.clone(),
-                        SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_millis() as u64,
-                        Profile {
-                            name,
-                            metadata: None,
-                        },
-                    )
-                    .unwrap();
-
-                    client
-                        .post(&profile_url)
-                        .header("Content-Type", "application/json")
-                        .body(serde_json::to_string(&profile).unwrap())
-                        .send()
-                        .await
-                        .unwrap();
-                }
-                BackendCommand::RequestProfile { target } => {
-                    let req = Signed::new(
-                        &key_pair,
-                        server.clone(),
-                        SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_millis() as u64,
-                        ProfileRequest {
-                            target_key: target.clone(),
-                        },
-                    )
-                    .unwrap();
-
-                    let res = client
-                        .get(&profile_url)
-                        .header("Content-Type", "application/json")
-                        .body(serde_json::to_string(&req).unwrap())
-                        .send()
-                        .await
-                        .unwrap()
-                        .text()
-                        .await
-                        .unwrap();
-
-                    if let Ok(profile) = serde_json::from_str::<Signed<Profile>>(&res) {
-                        let verified = profile.verify();
-
-                        chan.0
-                            .send(FrontendCommand::RespondProfile {
-                                profile: ProfileDisplay {
-                                    key: profile.key,
-                                    name: profile.data.name,
-                                    verified,
-                                },
-                            })
-                            .await
-                            .unwrap();
-                    } else {
-                        // TODO: This is stupid.
-                        chan.0
-                            .send(FrontendCommand::RespondProfile {
-                                profile: ProfileDisplay {
-                                    key: target.clone(),
-                                    name: "Guest".to_string(),
-                                    verified: false,
-                                },
-                            })
-                            .await
-                            .unwrap();
-                    }
-                }
-            }
-        }
+/// Value of the `--identity <file>` argument.
+fn identity_arg() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
 
-        // poll messages
-        let req = Signed::new(
-            &key_pair,
-            server.clone(),
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64,
-            PostRequest {
-                channel: "general".to_string(),
-                metadata: None,
-            },
-        )
-        .unwrap();
-
-        let resp = client
-            .get(&text_url)
-            .header("Content-Type", "application/json")
-            .body(serde_json::to_string(&req).unwrap())
-            .send()
-            .await
-            .unwrap()
-            .text()
-            .await
-            .unwrap();
-
-        let messages: Vec<Signed<Post>> = serde_json::from_str(&resp).unwrap();
-
-        if chan
-            .0
-            .send(FrontendCommand::DisplayMessages {
-                messages: messages
-                    .iter()
-                    .map(|m| Message {
-                        sender: m.key.clone(),
-                        content: m.data.content.clone(),
-                    })
-                    .collect(),
-            })
-            .await
-            .is_err()
-        {
-            break;
+    while let Some(arg) = args.next() {
+        if arg == "--identity" {
+            return args.next().map(PathBuf::from);
         }
-
-        tokio::time::sleep(Duration::from_millis(50)).await;
     }
+
+    None
 }
 
 #[tokio::main]
@@ -578,8 +1045,13 @@ async fn main() {
     // load config
     let server = std::env::var("IP").unwrap_or("http://0.0.0.0:3000".to_string());
 
-    let pkcs8 = KeyPair::generate_pkcs8().unwrap();
-    let key_pair = KeyPair::from_pkcs8(&pkcs8).unwrap();
+    let identity = identity_arg()
+        .or_else(identity::default_path)
+        .expect("No identity file given, pass '--identity <file>' or set HOME");
+    let key_pair = identity::load_or_create(&identity)
+        .unwrap_or_else(|err| panic!("Failed to load identity '{}': {err}", identity.display()));
+    let key_store = KeyStore::load(&identity, &key_pair)
+        .unwrap_or_else(|err| panic!("Failed to load the key store: {err}"));
 
     // begin terminal
     enable_raw_mode().unwrap();
@@ -595,10 +1067,12 @@ async fn main() {
     let (bs, br) = mpsc::channel(32);
 
     let backend_server = server.clone();
+    let key = key_pair.public_key().unwrap().to_base64();
 
-    let handle = tokio::spawn(async move { backend((fs, br), key_pair, backend_server).await });
+    let handle =
+        tokio::spawn(async move { backend((fs, br), key_pair, key_store, backend_server).await });
 
-    frontend((bs, fr), &mut terminal, server).await;
+    frontend((bs, fr), &mut terminal, server, key).await;
 
     handle.await.unwrap();
 
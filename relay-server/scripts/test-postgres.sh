@@ -0,0 +1,16 @@
+#!/bin/sh
+# Runs the PostgreSQL store tests against a throwaway server, which needs
+# `initdb` and `pg_ctl` on the PATH. Arguments are passed on to `cargo test`.
+set -eu
+
+dir=$(mktemp -d)
+port=${RELAY_TEST_PORT:-54329}
+
+trap 'pg_ctl -D "$dir/data" -m immediate stop >/dev/null 2>&1; rm -rf "$dir"' EXIT
+
+initdb -D "$dir/data" -U relay --auth=trust >/dev/null
+pg_ctl -D "$dir/data" -l "$dir/log" -w \
+    -o "-p $port -k $dir -c listen_addresses=127.0.0.1" start >/dev/null
+
+RELAY_TEST_POSTGRES="postgres://relay@127.0.0.1:$port/postgres" \
+    cargo test -p relay-server "$@" store::postgres -- --include-ignored
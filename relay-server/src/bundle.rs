@@ -0,0 +1,98 @@
+use std::collections::HashSet;
+
+use axum::{extract::State, Json};
+use lay::{
+    bundle::{Bundle, BundleEntry, BundleRequest},
+    crypto::is_x25519_public_key,
+    ErrorCode, Signed,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    error::{storage, ApiError},
+    replay::Replay,
+    store::SharedStore,
+};
+
+/// Most pre-keys a single bundle may carry.
+const MAX_PRE_KEYS: usize = 100;
+
+/// The bundle of `targetKey`, claiming one of its pre-keys if asked to.
+///
+/// The server cannot vouch for the keys, clients have to
+/// [`BundleEntry::verify`] the bundle against the key they expect.
+pub async fn get_bundle(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    Json(req): Json<Signed<BundleRequest>>,
+) -> Result<Json<BundleEntry>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let target = &req.data.target_key;
+
+    let Some(bundle) = store
+        .get_bundle(target)
+        .await
+        .map_err(storage("failed to load bundle"))?
+    else {
+        return Err(ApiError::new(
+            ErrorCode::BundleNotFound,
+            "Requested bundle does not exist!",
+        ));
+    };
+
+    let pre_key = if req.data.claim_pre_key {
+        store
+            .claim_pre_key(target)
+            .await
+            .map_err(storage("failed to claim pre-key"))?
+    } else {
+        None
+    };
+
+    let remaining = store
+        .count_pre_keys(target)
+        .await
+        .map_err(storage("failed to count pre-keys"))?;
+
+    Ok(Json(BundleEntry {
+        bundle,
+        pre_key,
+        remaining,
+    }))
+}
+
+/// Replaces the bundle of the signing key.
+pub async fn post_bundle(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    Json(req): Json<Signed<Bundle>>,
+) -> Result<Json<Value>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let bundle = &req.data;
+    let ids: HashSet<u32> = bundle.pre_keys.iter().map(|it| it.id).collect();
+
+    if !is_x25519_public_key(&bundle.identity_key)
+        || bundle.pre_keys.len() > MAX_PRE_KEYS
+        || ids.len() != bundle.pre_keys.len()
+        || !bundle
+            .pre_keys
+            .iter()
+            .all(|it| is_x25519_public_key(&it.key))
+    {
+        return Err(ApiError::new(
+            ErrorCode::InvalidBundle,
+            format!(
+                "Bundles hold base64 X25519 keys and at most {MAX_PRE_KEYS} pre-keys with distinct ids!"
+            ),
+        ));
+    }
+
+    store
+        .put_bundle(&req)
+        .await
+        .map_err(storage("failed to store bundle"))?;
+
+    Ok(Json(json!({})))
+}
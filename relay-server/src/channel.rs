@@ -0,0 +1,87 @@
+use axum::{extract::State, Json};
+use lay::{
+    channel::{Channel, ChannelRequest},
+    Error, ErrorCode, Signed,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    error::{storage, ApiError},
+    replay::Replay,
+    store::SharedStore,
+};
+
+/// Longest accepted channel name, in bytes.
+const MAX_NAME_LEN: usize = 64;
+
+/// Error for requests that reference a channel which does not exist.
+pub fn channel_not_found() -> Error {
+    Error::new(
+        ErrorCode::ChannelNotFound,
+        "Requested channel does not exist!",
+    )
+}
+
+pub async fn get_channel(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    Json(req): Json<Signed<ChannelRequest>>,
+) -> Result<Json<Signed<Channel>>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let channel = match &req.data.name {
+        Some(name) => store
+            .get_channel(name)
+            .await
+            .map_err(storage("failed to load channel"))?,
+        None => None,
+    };
+
+    Ok(Json(channel.ok_or_else(channel_not_found)?))
+}
+
+pub async fn get_channels(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    Json(req): Json<Signed<ChannelRequest>>,
+) -> Result<Json<Vec<Signed<Channel>>>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let channels = store
+        .list_channels()
+        .await
+        .map_err(storage("failed to list channels"))?;
+
+    Ok(Json(channels))
+}
+
+pub async fn post_channel(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    Json(req): Json<Signed<Channel>>,
+) -> Result<Json<Value>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let name = &req.data.name;
+
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.chars().any(char::is_whitespace) {
+        return Err(ApiError::new(
+            ErrorCode::InvalidChannelName,
+            format!("Channel names must be 1 to {MAX_NAME_LEN} bytes without whitespace!"),
+        ));
+    }
+
+    let inserted = store
+        .insert_channel(&req)
+        .await
+        .map_err(storage("failed to insert channel"))?;
+
+    if !inserted {
+        return Err(ApiError::new(
+            ErrorCode::ChannelExists,
+            "A channel with this name already exists!",
+        ));
+    }
+
+    Ok(Json(json!({})))
+}
@@ -0,0 +1,71 @@
+use axum::{extract::State, Json};
+use lay::{
+    crypto::PublicKey,
+    direct::{DirectEntry, DirectMessage, DirectRequest},
+    ErrorCode, Signed,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    error::{storage, ApiError},
+    replay::Replay,
+    store::{DirectQuery, SharedStore},
+    text::{cursor, DEFAULT_LIMIT, MAX_LIMIT},
+};
+
+/// Stores an encrypted message for `recipient`.
+///
+/// The content is opaque here, only the signature, timestamp and recipient
+/// are checked.
+pub async fn post_direct(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    Json(req): Json<Signed<DirectMessage>>,
+) -> Result<Json<Value>, ApiError> {
+    let Some(entry) = DirectEntry::new(req) else {
+        return Err(ApiError::new(
+            ErrorCode::FailedVerifySignature,
+            "Signature verification failed!",
+        ));
+    };
+
+    replay.authorize(&entry.message).await?;
+
+    if PublicKey::from_base64(&entry.message.data.recipient).is_none() {
+        return Err(ApiError::new(
+            ErrorCode::InvalidRecipient,
+            "Recipient must be a base64 Ed25519 public key!",
+        ));
+    }
+
+    store
+        .insert_direct(&entry)
+        .await
+        .map_err(storage("failed to insert direct message"))?;
+
+    Ok(Json(json!({ "id": entry.id })))
+}
+
+/// Direct messages between the signing key and `peer`.
+pub async fn get_direct(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    Json(req): Json<Signed<DirectRequest>>,
+) -> Result<Json<Vec<DirectEntry>>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let query = DirectQuery {
+        key: req.key.clone(),
+        peer: req.data.peer.clone(),
+        before: cursor(&req.data.before)?,
+        after: cursor(&req.data.after)?,
+        limit: req.data.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT),
+    };
+
+    let messages = store
+        .query_direct(&query)
+        .await
+        .map_err(storage("failed to query direct messages"))?;
+
+    Ok(Json(messages))
+}
@@ -0,0 +1,49 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use lay::{Error, ErrorCode};
+
+use crate::store::StoreError;
+
+/// A protocol [`Error`] returned from a handler, answered with the status
+/// code of its [`ErrorCode`] and the error as JSON body.
+#[derive(Debug)]
+pub struct ApiError(pub Error);
+
+impl ApiError {
+    pub fn new(status: ErrorCode, message: impl Into<String>) -> Self {
+        Self(Error::new(status, message))
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(error: Error) -> Self {
+        Self(error)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.0.status.http_status())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+        (status, Json(self.0)).into_response()
+    }
+}
+
+/// Turns a storage failure into [`ErrorCode::StorageUnavailable`].
+///
+/// The cause is logged together with `context` but not sent to the client,
+/// it may contain details of the database.
+pub fn storage(context: &'static str) -> impl FnOnce(StoreError) -> Error {
+    move |error| {
+        tracing::error!(%error, "{context}");
+
+        Error::new(
+            ErrorCode::StorageUnavailable,
+            "Storage is temporarily unavailable!",
+        )
+    }
+}
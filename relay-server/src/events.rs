@@ -0,0 +1,374 @@
+use std::{collections::VecDeque, convert::Infallible};
+
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::stream;
+use lay::{
+    text::{Cursor, PostEntry, PostRequest},
+    Error, ErrorCode, Signed,
+};
+use serde::Deserialize;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+
+use crate::{
+    error::ApiError,
+    replay::Replay,
+    store::{PostQuery, SharedStore},
+    stream::{Feed, Update},
+    text::{fetch_posts, post_query, MAX_LIMIT},
+};
+
+/// Header carrying the signed request, as an alternative to `?request=`.
+const REQUEST_HEADER: &str = "x-relay-request";
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// JSON of a `Signed<PostRequest>`.
+    request: Option<String>,
+}
+
+/// Server-Sent Events feed of a channel.
+///
+/// Takes a `Signed<PostRequest>` in the `request` query parameter or the
+/// `X-Relay-Request` header. Its signature and timestamp are checked like
+/// those of a `/text` request, but the same request may open the feed again
+/// while it is inside the timestamp window. Every post is sent as a `post`
+/// event with its cursor as `id`. Changed posts are sent again in full without
+/// `id`: as an `edit` event after an edit, a `retract` event with the
+/// tombstone of a retracted post and a `react` event when reactions change.
+/// Posts after `Last-Event-ID` (or the request's `after` cursor) are replayed
+/// before live ones, and live posts are filtered like the replayed ones.
+///
+/// A reconnecting `EventSource` sends the same request again together with
+/// `Last-Event-ID`, and resumes after the last post it received. Once the
+/// request has left the window, clients reopen the feed with a freshly signed
+/// one.
+pub async fn get_events(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    State(feed): State<Feed>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let raw = query.request.or_else(|| {
+        headers
+            .get(REQUEST_HEADER)
+            .and_then(|it| it.to_str().ok())
+            .map(str::to_string)
+    });
+
+    let Some(req) = raw.and_then(|it| serde_json::from_str::<Signed<PostRequest>>(&it).ok()) else {
+        return Err(ApiError::new(
+            ErrorCode::MissingRequest,
+            "Expected a signed post request!",
+        ));
+    };
+
+    // reading changes nothing, so a resent request is not a replay
+    replay.authorize_repeatable(&req)?;
+
+    let mut filter = req.data;
+
+    if let Some(last_event_id) = headers.get("last-event-id").and_then(|it| it.to_str().ok()) {
+        filter.after = Some(last_event_id.to_string());
+    }
+
+    let query = post_query(&filter)?;
+
+    let page = query.after.is_some().then(|| PostQuery {
+        limit: MAX_LIMIT,
+        ..query.clone()
+    });
+
+    // live updates are filtered like `/text` filters stored posts, except
+    // that changes to posts before `after` are still of interest
+    let query = PostQuery {
+        after: None,
+        ..query
+    };
+
+    let mut events = Events {
+        // subscribe before reading the backlog so nothing falls in between
+        live: BroadcastStream::new(feed.subscribe()),
+        store,
+        replayed: page.as_ref().and_then(|it| it.after.clone()),
+        page,
+        buffered: VecDeque::new(),
+        query,
+    };
+
+    // a failing store is still answered with an error
+    events.fetch_page().await?;
+
+    let events = stream::unfold(events, |mut events| async move {
+        let update = events.next().await?;
+        Some((update, events))
+    })
+    .filter_map(|update| event(&update).map(Ok));
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// The updates of one connection: the backlog after `Last-Event-ID`, read a
+/// page at a time as the client keeps up, then the live feed.
+struct Events {
+    store: SharedStore,
+    /// The next page of the backlog, `None` once it is exhausted.
+    page: Option<PostQuery>,
+    buffered: VecDeque<PostEntry>,
+    /// Cursor of the last replayed post, live posts up to it have been sent.
+    replayed: Option<Cursor>,
+    query: PostQuery,
+    live: BroadcastStream<Update>,
+}
+
+impl Events {
+    async fn fetch_page(&mut self) -> Result<(), Error> {
+        let Some(page) = self.page.take() else {
+            return Ok(());
+        };
+
+        let entries = fetch_posts(&self.store, &page).await?;
+
+        if entries.len() as u64 >= page.limit {
+            self.page = Some(PostQuery {
+                after: entries.last().map(|it| Cursor::of(&it.post)),
+                ..page
+            });
+        }
+
+        self.buffered.extend(entries);
+
+        Ok(())
+    }
+
+    /// The next update to send, `None` ends the connection.
+    async fn next(&mut self) -> Option<Update> {
+        loop {
+            if let Some(entry) = self.buffered.pop_front() {
+                self.replayed = Some(Cursor::of(&entry.post));
+                return Some(Update::Post(entry));
+            }
+
+            if self.page.is_some() {
+                // already logged, the client resumes with `Last-Event-ID`
+                self.fetch_page().await.ok()?;
+                continue;
+            }
+
+            // a lagging client is disconnected and resumes the same way
+            let update = self.live.next().await?.ok()?;
+            let entry = update.entry();
+
+            let replayed = matches!(update, Update::Post(_))
+                && self
+                    .replayed
+                    .as_ref()
+                    .is_some_and(|it| Cursor::of(&entry.post) <= *it);
+
+            if self.query.matches(entry) && !replayed {
+                return Some(update);
+            }
+        }
+    }
+}
+
+/// The event of `update`, `None` if it cannot be serialized.
+fn event(update: &Update) -> Option<Event> {
+    let entry = update.entry();
+
+    let data = serde_json::to_string(entry)
+        .map_err(|error| tracing::error!(%error, id = entry.id, "failed to serialize post"))
+        .ok()?;
+
+    let event = match update {
+        Update::Post(_) => Event::default()
+            .event("post")
+            .id(Cursor::of(&entry.post).to_string()),
+        // not a position in the channel, so it must not move `Last-Event-ID`
+        Update::Edit(_) => Event::default().event("edit"),
+        Update::Retract(_) => Event::default().event("retract"),
+        Update::React(_) => Event::default().event("react"),
+    };
+
+    Some(event.data(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use axum::{
+        body::HttpBody,
+        extract::{Query, State},
+        http::{HeaderMap, HeaderValue},
+        response::{IntoResponse, Response},
+    };
+    use lay::{
+        crypto::KeyPair,
+        text::{Cursor, PostEntry, PostRequest},
+        ErrorCode,
+    };
+
+    use super::{get_events, EventsQuery};
+    use crate::{
+        replay::{Replay, DEFAULT_WINDOW},
+        store::{
+            tests::{key_pair, post, sign},
+            MemoryStore, SharedStore,
+        },
+        stream::Feed,
+        text::MAX_LIMIT,
+    };
+
+    struct Server {
+        store: SharedStore,
+        replay: Replay,
+        feed: Feed,
+    }
+
+    impl Server {
+        fn new() -> Self {
+            let store: SharedStore = Arc::new(MemoryStore::default());
+
+            Self {
+                replay: Replay::new(store.clone(), DEFAULT_WINDOW),
+                feed: Feed::default(),
+                store,
+            }
+        }
+
+        /// Posts `count` posts to `#a`, oldest first.
+        async fn posts(&self, count: u64) -> Vec<PostEntry> {
+            let alice = key_pair();
+            let mut entries = Vec::new();
+
+            for timestamp in 1..=count {
+                let entry = post(&alice, timestamp, "a", &timestamp.to_string());
+                self.store.insert_post(&entry).await.unwrap();
+                entries.push(entry);
+            }
+
+            entries
+        }
+
+        async fn open(&self, request: &str, last_event_id: Option<&str>) -> Response {
+            let mut headers = HeaderMap::new();
+
+            if let Some(id) = last_event_id {
+                headers.insert("last-event-id", HeaderValue::from_str(id).unwrap());
+            }
+
+            get_events(
+                State(self.store.clone()),
+                State(self.replay.clone()),
+                State(self.feed.clone()),
+                Query(EventsQuery {
+                    request: Some(request.to_string()),
+                }),
+                headers,
+            )
+            .await
+            .into_response()
+        }
+    }
+
+    fn request(key_pair: &KeyPair, after: Option<&PostEntry>) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let req = sign(
+            key_pair,
+            now,
+            PostRequest {
+                channel: "a".to_string(),
+                after: after.map(id),
+                ..Default::default()
+            },
+        );
+
+        serde_json::to_string(&req).unwrap()
+    }
+
+    fn id(entry: &PostEntry) -> String {
+        Cursor::of(&entry.post).to_string()
+    }
+
+    /// Reads events until `count` of them had an id, returns those ids.
+    async fn ids(response: Response, count: usize) -> Vec<String> {
+        let mut body = response.into_body();
+        let mut text = String::new();
+        let mut ids = Vec::new();
+
+        while ids.len() < count {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+                .await
+                .expect("fewer events than expected")
+                .unwrap()
+                .unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+
+            ids = text
+                .lines()
+                .filter_map(|it| it.strip_prefix("id:"))
+                .map(|it| it.trim().to_string())
+                .collect();
+        }
+
+        ids
+    }
+
+    #[tokio::test]
+    async fn backlog_is_replayed_page_by_page() {
+        let server = Server::new();
+        let entries = server.posts(MAX_LIMIT + 20).await;
+
+        let response = server
+            .open(&request(&key_pair(), Some(&entries[0])), None)
+            .await;
+
+        assert!(response.status().is_success());
+        assert_eq!(
+            ids(response, entries.len() - 1).await,
+            entries[1..].iter().map(id).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnect_resumes_after_last_event_id() {
+        let server = Server::new();
+        let entries = server.posts(5).await;
+        let request = request(&key_pair(), Some(&entries[0]));
+
+        let response = server.open(&request, None).await;
+        let received = ids(response, 2).await;
+        assert_eq!(received, [id(&entries[1]), id(&entries[2])]);
+
+        // the connection drops, `EventSource` resends the same request
+        let response = server
+            .open(&request, received.last().map(String::as_str))
+            .await;
+
+        assert!(response.status().is_success());
+        assert_eq!(ids(response, 2).await, [id(&entries[3]), id(&entries[4])]);
+    }
+
+    #[tokio::test]
+    async fn missing_request_is_refused() {
+        let server = Server::new();
+        let response = server.open("{}", None).await;
+
+        assert_eq!(
+            response.status().as_u16(),
+            ErrorCode::MissingRequest.http_status()
+        );
+    }
+}
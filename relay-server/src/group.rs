@@ -0,0 +1,148 @@
+use axum::{extract::State, Json};
+use lay::{
+    crypto::PublicKey,
+    group::{epoch_of, GroupKey, GroupKeyRequest},
+    Error, ErrorCode, Signed,
+};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    channel::channel_not_found,
+    error::{storage, ApiError},
+    replay::Replay,
+    store::SharedStore,
+};
+
+/// Most members an encrypted channel can have.
+const MAX_MEMBERS: usize = 256;
+
+/// Checks that `key` is a member of the latest epoch of the encrypted
+/// `channel` and that content with `metadata` is sealed for that epoch.
+///
+/// Older epochs are refused so removed members cannot keep posting with a
+/// secret they still know.
+pub async fn check_member(
+    store: &SharedStore,
+    channel: &str,
+    key: &str,
+    metadata: &Option<Map<String, Value>>,
+) -> Result<(), Error> {
+    let latest = store
+        .get_group_key(channel, None)
+        .await
+        .map_err(storage("failed to look up group key"))?;
+
+    let Some(latest) = latest.filter(|it| it.data.is_member(key)) else {
+        return Err(Error::new(
+            ErrorCode::NotMember,
+            "Only members can post to an encrypted channel!",
+        ));
+    };
+
+    if epoch_of(metadata) != Some(latest.data.epoch) {
+        return Err(Error::new(
+            ErrorCode::InvalidGroupKey,
+            format!(
+                "Content must be sealed for the latest epoch ({})!",
+                latest.data.epoch
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+pub async fn get_group_key(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    Json(req): Json<Signed<GroupKeyRequest>>,
+) -> Result<Json<Signed<GroupKey>>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let group_key = store
+        .get_group_key(&req.data.channel, req.data.epoch)
+        .await
+        .map_err(storage("failed to load group key"))?;
+
+    let Some(group_key) = group_key else {
+        return Err(ApiError::new(
+            ErrorCode::GroupKeyNotFound,
+            "Requested group key does not exist!",
+        ));
+    };
+
+    Ok(Json(group_key))
+}
+
+/// Starts the next epoch of an encrypted channel, signed by its creator.
+pub async fn post_group_key(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    Json(req): Json<Signed<GroupKey>>,
+) -> Result<Json<Value>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let group_key = &req.data;
+
+    let channel = store
+        .get_channel(&group_key.channel)
+        .await
+        .map_err(storage("failed to look up channel"))?
+        .ok_or_else(channel_not_found)?;
+
+    if !channel.data.is_encrypted() {
+        return Err(ApiError::new(
+            ErrorCode::InvalidGroupKey,
+            "Only encrypted channels have group keys!",
+        ));
+    }
+
+    if channel.key != req.key {
+        return Err(ApiError::new(
+            ErrorCode::NotAuthor,
+            "Only the creator of a channel can change its members!",
+        ));
+    }
+
+    if group_key.keys.is_empty()
+        || group_key.keys.len() > MAX_MEMBERS
+        || group_key
+            .keys
+            .keys()
+            .any(|it| PublicKey::from_base64(it).is_none())
+    {
+        return Err(ApiError::new(
+            ErrorCode::InvalidGroupKey,
+            format!("Group keys are sealed to 1 to {MAX_MEMBERS} Ed25519 public keys!"),
+        ));
+    }
+
+    let expected = store
+        .get_group_key(&group_key.channel, None)
+        .await
+        .map_err(storage("failed to look up group key"))?
+        .map_or(0, |it| it.data.epoch + 1);
+
+    let stale = || {
+        ApiError::new(
+            ErrorCode::InvalidGroupKey,
+            format!("The next epoch of this channel is {expected}!"),
+        )
+    };
+
+    if group_key.epoch != expected {
+        return Err(stale());
+    }
+
+    let inserted = store
+        .insert_group_key(&req)
+        .await
+        .map_err(storage("failed to insert group key"))?;
+
+    // a concurrent change of members won the race
+    if !inserted {
+        return Err(stale());
+    }
+
+    Ok(Json(json!({})))
+}
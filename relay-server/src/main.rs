@@ -1,12 +1,77 @@
+mod bundle;
+mod channel;
+mod direct;
+mod error;
+mod events;
+mod group;
 mod profile;
+mod replay;
+mod resource;
+mod rotation;
+mod store;
+mod stream;
+#[cfg(test)]
+mod tests;
 mod text;
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf};
 
-use axum::{routing::get, Router};
+use axum::{
+    extract::{DefaultBodyLimit, FromRef},
+    routing::{get, post},
+    Router,
+};
+use bundle::{get_bundle, post_bundle};
+use channel::{get_channel, get_channels, post_channel};
+use direct::{get_direct, post_direct};
+use events::get_events;
+use group::{get_group_key, post_group_key};
 use profile::{get_profile, post_profile};
-use rbatis::RBatis;
-use text::{get_text, post_text};
+use replay::Replay;
+use resource::{get_resource, post_resource, Blobs};
+use rotation::{get_revocation, get_rotation, post_revocation, post_rotation};
+use store::SharedStore;
+use stream::{stream, Feed};
+use text::{get_text, post_edit, post_reaction, post_retraction, post_text, Moderators};
+
+#[derive(Clone)]
+pub struct AppState {
+    store: SharedStore,
+    replay: Replay,
+    feed: Feed,
+    blobs: Blobs,
+    moderators: Moderators,
+}
+
+impl FromRef<AppState> for SharedStore {
+    fn from_ref(state: &AppState) -> Self {
+        state.store.clone()
+    }
+}
+
+impl FromRef<AppState> for Replay {
+    fn from_ref(state: &AppState) -> Self {
+        state.replay.clone()
+    }
+}
+
+impl FromRef<AppState> for Feed {
+    fn from_ref(state: &AppState) -> Self {
+        state.feed.clone()
+    }
+}
+
+impl FromRef<AppState> for Blobs {
+    fn from_ref(state: &AppState) -> Self {
+        state.blobs.clone()
+    }
+}
+
+impl FromRef<AppState> for Moderators {
+    fn from_ref(state: &AppState) -> Self {
+        state.moderators.clone()
+    }
+}
 
 #[tokio::main]
 async fn main() {
@@ -21,21 +86,57 @@ async fn main() {
         .expect("Relay db url must be set via environment variable 'RELAY_DB'")
         .into_string()
         .unwrap();
+    let window = std::env::var("RELAY_TIMESTAMP_WINDOW")
+        .ok()
+        .and_then(|it| it.parse().ok())
+        .unwrap_or(replay::DEFAULT_WINDOW);
+    let resource_dir = std::env::var_os("RELAY_RESOURCE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(resource::DEFAULT_DIR));
+    let max_resource_size = std::env::var("RELAY_MAX_RESOURCE_SIZE")
+        .ok()
+        .and_then(|it| it.parse().ok())
+        .unwrap_or(resource::DEFAULT_MAX_SIZE);
 
-    let db = RBatis::new();
-    db.init(rbdc_sqlite::driver::SqliteDriver {}, &db_url)
-        .unwrap();
-    db.get_pool().unwrap().resize(5);
+    let moderators = std::env::var("RELAY_MODERATORS")
+        .map(|it| Moderators::parse(&it))
+        .unwrap_or_default();
 
-    // setup db
-    db.exec("create table if not exists posts (key varchar(48) not null, server varchar(48) not null, timestamp bigint not null, channel text not null, content text, signature varchar(96) primary key);", vec![]).await.unwrap();
-    db.exec("create table if not exists users (key varchar(48) primary key, lastrequest bigint not null)", vec![]).await.unwrap();
-    db.exec("create table if not exists profiles (key varchar(48) primary key, server varchar(48) not null, timestamp bigint not null, name varchar(255) not null, signature varchar(96) not null)", vec![]).await.unwrap();
+    let pool_size = std::env::var("RELAY_DB_POOL_SIZE")
+        .ok()
+        .and_then(|it| it.parse().ok());
 
-    let app = Router::new()
-        .route("/text", get(get_text).post(post_text))
-        .route("/profile", get(get_profile).post(post_profile))
-        .with_state(db);
+    let store = store::open(&db_url, pool_size)
+        .await
+        .unwrap_or_else(|err| panic!("Failed to open '{db_url}': {err}"));
+
+    let migrated = store
+        .migrate()
+        .await
+        .unwrap_or_else(|err| panic!("Failed to migrate '{db_url}': {err}"));
+
+    if migrated.from != migrated.to {
+        println!(
+            "Migrated database schema from version {} to {}",
+            migrated.from, migrated.to
+        );
+    }
+
+    if std::env::args().any(|it| it == "--migrate-only") {
+        println!("Database schema is at version {}", migrated.to);
+        return;
+    }
+
+    let app = router(
+        AppState {
+            replay: Replay::new(store.clone(), window),
+            feed: Feed::default(),
+            blobs: Blobs::new(resource_dir, max_resource_size),
+            moderators,
+            store,
+        },
+        max_resource_size,
+    );
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
 
@@ -44,3 +145,30 @@ async fn main() {
         .await
         .unwrap();
 }
+
+/// Every endpoint, uploads are limited to `max_resource_size` bytes.
+fn router(state: AppState, max_resource_size: usize) -> Router {
+    Router::new()
+        .route("/text", get(get_text).post(post_text))
+        .route("/edit", post(post_edit))
+        .route("/retraction", post(post_retraction))
+        .route("/reaction", post(post_reaction))
+        .route("/direct", get(get_direct).post(post_direct))
+        .route("/channel", get(get_channel).post(post_channel))
+        .route("/channels", get(get_channels))
+        .route("/profile", get(get_profile).post(post_profile))
+        .route("/bundle", get(get_bundle).post(post_bundle))
+        .route("/group", get(get_group_key).post(post_group_key))
+        .route("/rotation", get(get_rotation).post(post_rotation))
+        .route("/revocation", get(get_revocation).post(post_revocation))
+        .route(
+            "/resource",
+            // room for the signed request and multipart framing
+            get(get_resource)
+                .post(post_resource)
+                .layer(DefaultBodyLimit::max(max_resource_size + 64 * 1024)),
+        )
+        .route("/stream", get(stream))
+        .route("/events", get(get_events))
+        .with_state(state)
+}
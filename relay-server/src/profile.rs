@@ -1,86 +1,49 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{extract::State, Json};
 use lay::{
     profile::{Profile, ProfileRequest},
-    Error, Signed,
+    ErrorCode, Signed,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    error::{storage, ApiError},
+    replay::Replay,
+    store::SharedStore,
 };
-use rbatis::RBatis;
-use rbs::to_value;
-use serde_json::json;
 
 pub async fn get_profile(
-    State(db): State<RBatis>,
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
     Json(req): Json<Signed<ProfileRequest>>,
-) -> impl IntoResponse {
-    if !req.verify() {
-        let error = serde_json::to_value(Error {
-            status: "FAILED_VERIFY_SIGNATURE".to_string(),
-            message: "Signature verification failed!".to_string(),
-            details: None,
-        })
-        .unwrap();
-
-        return (StatusCode::BAD_REQUEST, Json(error));
-    }
+) -> Result<Json<Signed<Profile>>, ApiError> {
+    replay.authorize(&req).await?;
 
-    let Ok(profile) = db.query_decode::<Signed<Profile>>("select * from profiles where key=?;", vec![to_value!(req.data.target_key)]).await else {
-        let error = serde_json::to_value(Error {
-            status: "PROFILE_NOT_FOUND".to_string(),
-            message: "Requested profile does not exist!".to_string(),
-            details: None,
-        })
-        .unwrap();
+    let profile = store
+        .get_profile(&req.data.target_key)
+        .await
+        .map_err(storage("failed to load profile"))?;
 
-        return (StatusCode::BAD_REQUEST, Json(error));
+    let Some(profile) = profile else {
+        return Err(ApiError::new(
+            ErrorCode::ProfileNotFound,
+            "Requested profile does not exist!",
+        ));
     };
 
-    (
-        StatusCode::OK,
-        Json(serde_json::to_value(&profile).unwrap()),
-    )
+    Ok(Json(profile))
 }
 
 pub async fn post_profile(
-    State(db): State<RBatis>,
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
     Json(req): Json<Signed<Profile>>,
-) -> impl IntoResponse {
-    if !req.verify() {
-        let error = serde_json::to_value(Error {
-            status: "FAILED_VERIFY_SIGNATURE".to_string(),
-            message: "Signature verification failed!".to_string(),
-            details: None,
-        })
-        .unwrap();
-
-        return (StatusCode::BAD_REQUEST, Json(error));
-    }
+) -> Result<Json<Value>, ApiError> {
+    replay.authorize(&req).await?;
 
-    if let Ok(_) = db
-        .query_decode::<String>(
-            "select name from profiles where key=?1;",
-            vec![to_value!(req.key.clone())],
-        )
-        .await
-    {
-        db.exec(
-            "update profiles set name=?1 where key=?2;",
-            vec![to_value!(req.data.name), to_value!(req.key)],
-        )
+    store
+        .put_profile(&req)
         .await
-        .unwrap();
-    } else {
-        db.exec(
-        "insert into profiles (key, server, timestamp, name, signature) values (?1, ?2, ?3, ?4, ?5);",
-        vec![
-            to_value!(req.key),
-            to_value!(req.server),
-            to_value!(req.timestamp),
-            to_value!(req.data.name),
-            to_value!(req.signature),
-        ],
-    )
-    .await
-    .unwrap();
-    }
+        .map_err(storage("failed to store profile"))?;
 
-    (StatusCode::OK, Json(json!({})))
+    Ok(Json(json!({})))
 }
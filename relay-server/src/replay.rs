@@ -0,0 +1,239 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lay::{Error, ErrorCode, Signed};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{error::storage, store::SharedStore};
+
+/// Default accepted clock skew between client and server, in milliseconds.
+pub const DEFAULT_WINDOW: u64 = 60_000;
+
+/// Rejects signed requests that are stale or have been seen before.
+///
+/// Every key has a high-water mark (`users.lastrequest`), a request is only
+/// accepted if its timestamp is within `window` of the server clock and
+/// strictly greater than the last accepted one. Requests that change nothing
+/// and may be sent again verbatim only need to be inside the window, see
+/// [`Replay::authorize_repeatable`]. Strings with a NUL character are refused
+/// up front, postgres can store them in neither `text` nor `jsonb`.
+#[derive(Clone)]
+pub struct Replay {
+    store: SharedStore,
+    window: u64,
+}
+
+impl Replay {
+    pub fn new(store: SharedStore, window: u64) -> Self {
+        Self { store, window }
+    }
+
+    /// Verifies the signature of `req`, then [`Replay::check`]s it.
+    pub async fn authorize<T: Clone + Serialize>(&self, req: &Signed<T>) -> Result<(), Error> {
+        verify(req)?;
+
+        self.check(req).await
+    }
+
+    /// Verifies the signature of `req` and that it is inside the window, but
+    /// accepts it any number of times and leaves the high-water mark alone.
+    ///
+    /// Only for requests that read, like an `/events` subscription, which an
+    /// `EventSource` resends unchanged whenever it reconnects.
+    pub fn authorize_repeatable<T: Clone + Serialize>(&self, req: &Signed<T>) -> Result<(), Error> {
+        verify(req)?;
+
+        self.check_window(req, now())
+    }
+
+    pub async fn check<T: Clone + Serialize>(&self, req: &Signed<T>) -> Result<(), Error> {
+        self.check_at(req, now()).await
+    }
+
+    /// [`Replay::check`] with the server clock at `now`.
+    async fn check_at<T: Clone + Serialize>(&self, req: &Signed<T>, now: u64) -> Result<(), Error> {
+        self.check_window(req, now)?;
+
+        let advanced = self
+            .store
+            .advance_last_request(&req.key, req.timestamp)
+            .await
+            .map_err(storage("failed to advance last request"))?;
+
+        if !advanced {
+            return Err(Error::new(
+                ErrorCode::ImpossibleTimestamp,
+                "Non-unique timestamp for request!",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn check_window<T: Clone + Serialize>(&self, req: &Signed<T>, now: u64) -> Result<(), Error> {
+        if req.timestamp.abs_diff(now) > self.window {
+            return Err(Error::new(
+                ErrorCode::ImpossibleTimestamp,
+                "Timestamp outside of the accepted window!",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn verify<T: Clone + Serialize>(req: &Signed<T>) -> Result<(), Error> {
+    if !req.verify() {
+        return Err(Error::new(
+            ErrorCode::FailedVerifySignature,
+            "Signature verification failed!",
+        ));
+    }
+
+    if serde_json::to_value(req).is_ok_and(|it| contains_nul(&it)) {
+        return Err(Error::new(
+            ErrorCode::InvalidString,
+            "Strings must not contain NUL characters!",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Whether any string in `value`, object keys included, contains a NUL.
+fn contains_nul(value: &Value) -> bool {
+    match value {
+        Value::String(it) => it.contains('\0'),
+        Value::Array(items) => items.iter().any(contains_nul),
+        Value::Object(map) => map
+            .iter()
+            .any(|(key, it)| key.contains('\0') || contains_nul(it)),
+        _ => false,
+    }
+}
+
+/// Server clock in milliseconds.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use lay::{crypto::KeyPair, profile::Profile, ErrorCode, Signed};
+
+    use super::Replay;
+    use crate::store::{
+        tests::{key_pair, sign},
+        MemoryStore,
+    };
+
+    const NOW: u64 = 1_700_000_000_000;
+    const WINDOW: u64 = 60_000;
+
+    fn replay() -> Replay {
+        Replay::new(Arc::new(MemoryStore::default()), WINDOW)
+    }
+
+    fn request(key_pair: &KeyPair, timestamp: u64) -> Signed<Profile> {
+        let profile = Profile {
+            name: "alice".to_string(),
+            metadata: None,
+        };
+
+        sign(key_pair, timestamp, profile)
+    }
+
+    async fn check(replay: &Replay, req: &Signed<Profile>) -> Result<(), ErrorCode> {
+        replay.check_at(req, NOW).await.map_err(|it| it.status)
+    }
+
+    #[tokio::test]
+    async fn window_is_enforced() {
+        let replay = replay();
+        let alice = key_pair();
+
+        for timestamp in [NOW - WINDOW - 1, NOW + WINDOW + 1, 0, u64::MAX] {
+            assert_eq!(
+                check(&replay, &request(&alice, timestamp)).await,
+                Err(ErrorCode::ImpossibleTimestamp)
+            );
+        }
+
+        // rejected requests leave the high-water mark alone
+        assert_eq!(check(&replay, &request(&alice, NOW - WINDOW)).await, Ok(()));
+        assert_eq!(check(&replay, &request(&alice, NOW + WINDOW)).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn timestamps_only_move_forward() {
+        let replay = replay();
+        let alice = key_pair();
+        let first = request(&alice, NOW);
+
+        assert_eq!(check(&replay, &first).await, Ok(()));
+
+        for req in [first, request(&alice, NOW), request(&alice, NOW - 1)] {
+            assert_eq!(
+                check(&replay, &req).await,
+                Err(ErrorCode::ImpossibleTimestamp)
+            );
+        }
+
+        assert_eq!(check(&replay, &request(&alice, NOW + 1)).await, Ok(()));
+        assert_eq!(
+            check(&replay, &request(&alice, NOW)).await,
+            Err(ErrorCode::ImpossibleTimestamp)
+        );
+    }
+
+    #[tokio::test]
+    async fn keys_are_independent() {
+        let replay = replay();
+
+        assert_eq!(check(&replay, &request(&key_pair(), NOW)).await, Ok(()));
+        assert_eq!(check(&replay, &request(&key_pair(), NOW)).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn nul_is_refused() {
+        let replay = replay();
+        let alice = key_pair();
+
+        let mut profile = request(&alice, NOW).data;
+        profile.name = "al\0ice".to_string();
+        let req = sign(&alice, NOW, profile.clone());
+        assert_eq!(
+            replay.authorize_repeatable(&req).map_err(|it| it.status),
+            Err(ErrorCode::InvalidString)
+        );
+
+        profile.name = "alice".to_string();
+        profile.metadata = Some(
+            [("a\0".to_string(), serde_json::Value::Null)]
+                .into_iter()
+                .collect(),
+        );
+        let req = sign(&alice, NOW, profile);
+        assert_eq!(
+            replay.authorize_repeatable(&req).map_err(|it| it.status),
+            Err(ErrorCode::InvalidString)
+        );
+    }
+
+    #[tokio::test]
+    async fn signature_is_verified() {
+        let replay = replay();
+        let mut req = request(&key_pair(), NOW);
+        req.data.name = "mallory".to_string();
+
+        assert_eq!(
+            replay.authorize(&req).await.map_err(|it| it.status),
+            Err(ErrorCode::FailedVerifySignature)
+        );
+    }
+}
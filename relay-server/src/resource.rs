@@ -0,0 +1,384 @@
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+use axum::{
+    extract::{multipart::MultipartError, Multipart, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use lay::{
+    resource::{Resource, ResourceRequest},
+    Error, ErrorCode, Signed,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tempfile::NamedTempFile;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::{
+    error::{storage, ApiError},
+    replay::Replay,
+    store::SharedStore,
+};
+
+/// Default directory blobs are stored in.
+pub const DEFAULT_DIR: &str = "resources";
+/// Default upper bound for a single upload, in bytes.
+pub const DEFAULT_MAX_SIZE: usize = 8 * 1024 * 1024;
+
+/// Header carrying the signed request, as an alternative to `?request=`.
+const REQUEST_HEADER: &str = "x-relay-request";
+
+/// Content-addressed blob storage on disk.
+///
+/// A blob with id `abcd...` lives at `<dir>/ab/abcd...`, so identical uploads
+/// are only stored once.
+#[derive(Clone)]
+pub struct Blobs {
+    dir: PathBuf,
+    max_size: usize,
+}
+
+impl Blobs {
+    pub fn new(dir: PathBuf, max_size: usize) -> Self {
+        Self { dir, max_size }
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(&id[..2]).join(id)
+    }
+
+    async fn write(&self, id: &str, data: &[u8]) -> std::io::Result<()> {
+        let path = self.path(id);
+
+        if fs::try_exists(&path).await? {
+            return Ok(());
+        }
+
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir).await?;
+
+        // write aside and rename, so a blob is never visible half-written,
+        // each upload to its own file as the same id may be uploaded twice
+        let (file, partial) = NamedTempFile::new_in(dir)?.into_parts();
+        let mut file = fs::File::from_std(file);
+
+        file.write_all(data).await?;
+        file.sync_all().await?;
+
+        partial.persist(&path).map_err(|it| it.error)
+    }
+}
+
+pub async fn post_resource(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    State(blobs): State<Blobs>,
+    mut multipart: Multipart,
+) -> Result<Json<Value>, ApiError> {
+    let mut req: Option<Signed<Resource>> = None;
+    let mut data = None;
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(it)) => it,
+            Ok(None) => break,
+            Err(error) => return Err(multipart_error(error)),
+        };
+
+        match field.name() {
+            Some("resource") => {
+                req = field
+                    .text()
+                    .await
+                    .ok()
+                    .and_then(|it| serde_json::from_str(&it).ok())
+            }
+            Some("file") => data = Some(field.bytes().await.map_err(multipart_error)?),
+            _ => {}
+        }
+    }
+
+    let (Some(req), Some(data)) = (req, data) else {
+        return Err(ApiError::new(
+            ErrorCode::MissingRequest,
+            "Expected a signed resource and a file part!",
+        ));
+    };
+
+    replay.authorize(&req).await?;
+
+    if data.len() > blobs.max_size {
+        return Err(too_large());
+    }
+
+    if Resource::id_of(&data) != req.data.id {
+        return Err(ApiError::new(
+            ErrorCode::ResourceHashMismatch,
+            "Resource id does not match the uploaded content!",
+        ));
+    }
+
+    if let Err(error) = blobs.write(&req.data.id, &data).await {
+        tracing::error!(%error, id = req.data.id, "failed to write blob");
+        return Err(blob_unavailable());
+    }
+
+    store
+        .insert_resource(&req, data.len() as u64)
+        .await
+        .map_err(storage("failed to insert resource"))?;
+
+    Ok(Json(json!({})))
+}
+
+fn too_large() -> ApiError {
+    ApiError::new(
+        ErrorCode::ResourceTooLarge,
+        "Upload exceeds the size limit!",
+    )
+}
+
+/// Error for an upload that cannot be read, only hitting the body limit
+/// (slightly above `max_size`) means it is too large.
+fn multipart_error(error: MultipartError) -> ApiError {
+    if error.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return too_large();
+    }
+
+    ApiError::new(ErrorCode::MissingRequest, "Malformed multipart upload!")
+}
+
+/// Error for blobs that cannot be read or written, the cause is only logged.
+fn blob_unavailable() -> ApiError {
+    ApiError::new(
+        ErrorCode::StorageUnavailable,
+        "Storage is temporarily unavailable!",
+    )
+}
+
+/// Error for posts that attach a resource which was never uploaded.
+pub fn resource_not_found() -> Error {
+    Error::new(
+        ErrorCode::ResourceNotFound,
+        "Requested resource does not exist!",
+    )
+}
+
+#[derive(Deserialize)]
+pub struct ResourceQuery {
+    /// JSON of a `Signed<ResourceRequest>`.
+    request: Option<String>,
+}
+
+/// Downloads a blob.
+///
+/// Takes a `Signed<ResourceRequest>` in the `request` query parameter or the
+/// `X-Relay-Request` header, like `/events`. A single `Range: bytes=` range
+/// is answered with `206 Partial Content`, or `416` if it starts past the end
+/// of the blob. Multiple ranges and malformed headers are ignored, as RFC 9110
+/// allows, and answered with the whole blob and `200 OK`.
+pub async fn get_resource(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    State(blobs): State<Blobs>,
+    Query(query): Query<ResourceQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let raw = query.request.or_else(|| {
+        headers
+            .get(REQUEST_HEADER)
+            .and_then(|it| it.to_str().ok())
+            .map(str::to_string)
+    });
+
+    let Some(req) = raw.and_then(|it| serde_json::from_str::<Signed<ResourceRequest>>(&it).ok())
+    else {
+        return Err(ApiError::new(
+            ErrorCode::MissingRequest,
+            "Expected a signed resource request!",
+        ));
+    };
+
+    replay.authorize(&req).await?;
+
+    let id = &req.data.id;
+
+    let stored = if Resource::is_valid_id(id) {
+        store
+            .get_resource(id)
+            .await
+            .map_err(storage("failed to load resource"))?
+    } else {
+        None
+    };
+
+    let stored = stored.ok_or_else(resource_not_found)?;
+
+    let range = match headers.get(header::RANGE).and_then(|it| it.to_str().ok()) {
+        Some(range) => match byte_range(range, stored.size) {
+            Ok(it) => it,
+            Err(()) => {
+                return Ok((
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, format!("bytes */{}", stored.size))],
+                )
+                    .into_response());
+            }
+        },
+        None => None,
+    };
+
+    let (start, end) = range.unwrap_or((0, stored.size.saturating_sub(1)));
+    let length = if stored.size == 0 { 0 } else { end - start + 1 };
+
+    let data = match read_range(&blobs.path(id), start, length).await {
+        Ok(it) => it,
+        Err(error) => {
+            tracing::error!(%error, id, "failed to read blob");
+            return Err(blob_unavailable());
+        }
+    };
+
+    let content_type = stored
+        .resource
+        .data
+        .metadata
+        .as_ref()
+        .and_then(|it| it.get("type")?.as_str())
+        .and_then(|it| HeaderValue::from_str(it).ok())
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, content_type);
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    if range.is_some() {
+        // only digits, always a valid header value
+        if let Ok(value) = HeaderValue::from_str(&format!("bytes {start}-{end}/{}", stored.size)) {
+            headers.insert(header::CONTENT_RANGE, value);
+        }
+
+        return Ok((StatusCode::PARTIAL_CONTENT, headers, data).into_response());
+    }
+
+    Ok((StatusCode::OK, headers, data).into_response())
+}
+
+/// Parses a `Range` header against a blob of `size` bytes into an inclusive
+/// range. `Ok(None)` means the header is ignored and the whole blob is sent,
+/// `Err` that the range does not overlap the blob.
+fn byte_range(range: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = range.strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+
+    // serving several ranges needs a multipart body, the whole blob will do
+    if spec.contains(',') {
+        return Ok(None);
+    }
+
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // `a-b`
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        // `a-`
+        (Ok(start), Err(_)) if end.is_empty() => (start, size.saturating_sub(1)),
+        // `-n`, the last n bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return Err(());
+            }
+
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+
+    if start >= size {
+        return Err(());
+    }
+
+    Ok(Some((start, end)))
+}
+
+async fn read_range(path: &Path, start: u64, length: u64) -> std::io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+
+    let mut data = Vec::with_capacity(length as usize);
+    file.take(length).read_to_end(&mut data).await?;
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use lay::resource::Resource;
+    use tokio::fs;
+
+    use super::{byte_range, Blobs, DEFAULT_MAX_SIZE};
+
+    #[test]
+    fn ranges() {
+        for (range, expected) in [
+            ("bytes=0-9", Ok(Some((0, 9)))),
+            ("bytes=90-200", Ok(Some((90, 99)))),
+            ("bytes=10-", Ok(Some((10, 99)))),
+            ("bytes=-10", Ok(Some((90, 99)))),
+            ("bytes=-200", Ok(Some((0, 99)))),
+            // past the end
+            ("bytes=100-", Err(())),
+            ("bytes=100-150", Err(())),
+            ("bytes=-0", Err(())),
+            // malformed, ignored
+            ("bytes=abc", Ok(None)),
+            ("bytes=9-0", Ok(None)),
+            ("bytes=1-x", Ok(None)),
+            ("bytes=-", Ok(None)),
+            ("items=0-9", Ok(None)),
+            // several ranges, ignored
+            ("bytes=0-1,5-6", Ok(None)),
+        ] {
+            assert_eq!(byte_range(range, 100), expected, "{range}");
+        }
+
+        assert_eq!(byte_range("bytes=0-", 0), Err(()));
+    }
+
+    #[tokio::test]
+    async fn concurrent_uploads_of_one_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let blobs = Blobs::new(dir.path().to_path_buf(), DEFAULT_MAX_SIZE);
+
+        let data = vec![7; 1 << 20];
+        let id = Resource::id_of(&data);
+
+        let (first, second) = tokio::join!(blobs.write(&id, &data), blobs.write(&id, &data));
+        first.unwrap();
+        second.unwrap();
+
+        assert_eq!(fs::read(blobs.path(&id)).await.unwrap(), data);
+
+        // nothing but the blob is left behind
+        let mut entries = fs::read_dir(blobs.path(&id).parent().unwrap())
+            .await
+            .unwrap();
+        let mut count = 0;
+
+        while entries.next_entry().await.unwrap().is_some() {
+            count += 1;
+        }
+
+        assert_eq!(count, 1);
+    }
+}
@@ -0,0 +1,137 @@
+use axum::{extract::State, Json};
+use lay::{
+    crypto::PublicKey,
+    rotation::{KeyStatusRequest, Revocation, Rotation},
+    ErrorCode, Signed,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    error::{storage, ApiError},
+    replay::Replay,
+    store::SharedStore,
+};
+
+pub async fn get_rotation(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    Json(req): Json<Signed<KeyStatusRequest>>,
+) -> Result<Json<Signed<Rotation>>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let rotation = store
+        .get_rotation(&req.data.target_key)
+        .await
+        .map_err(storage("failed to load rotation"))?;
+
+    let Some(rotation) = rotation else {
+        return Err(ApiError::new(
+            ErrorCode::RotationNotFound,
+            "Requested key has not been rotated!",
+        ));
+    };
+
+    Ok(Json(rotation))
+}
+
+/// Replaces the signing key with `newKey`.
+///
+/// Neither key may have been rotated or revoked before, so following the
+/// rotations of a key can never run in circles. The store checks the
+/// rotations together with the insert, see [`crate::store::Store::insert_rotation`].
+pub async fn post_rotation(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    Json(req): Json<Signed<Rotation>>,
+) -> Result<Json<Value>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let new_key = &req.data.new_key;
+
+    if PublicKey::from_base64(new_key).is_none() || *new_key == req.key {
+        return Err(ApiError::new(
+            ErrorCode::InvalidRotation,
+            "Keys are rotated to a different Ed25519 public key!",
+        ));
+    }
+
+    if !req.verify_countersignature() {
+        return Err(ApiError::new(
+            ErrorCode::InvalidRotation,
+            "The countersignature does not match the new key!",
+        ));
+    }
+
+    for key in [&req.key, new_key] {
+        let revoked = store
+            .get_revocation(key)
+            .await
+            .map_err(storage("failed to look up revocation"))?;
+
+        if revoked.is_some() {
+            return Err(ApiError::new(
+                ErrorCode::KeyRevoked,
+                "Revoked keys cannot take part in a rotation!",
+            ));
+        }
+    }
+
+    let inserted = store
+        .insert_rotation(&req)
+        .await
+        .map_err(storage("failed to insert rotation"))?;
+
+    if !inserted {
+        return Err(ApiError::new(
+            ErrorCode::KeyRotated,
+            "Keys can only be rotated once, and never to a retired key!",
+        ));
+    }
+
+    Ok(Json(json!({})))
+}
+
+pub async fn get_revocation(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    Json(req): Json<Signed<KeyStatusRequest>>,
+) -> Result<Json<Signed<Revocation>>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let revocation = store
+        .get_revocation(&req.data.target_key)
+        .await
+        .map_err(storage("failed to load revocation"))?;
+
+    let Some(revocation) = revocation else {
+        return Err(ApiError::new(
+            ErrorCode::RevocationNotFound,
+            "Requested key has not been revoked!",
+        ));
+    };
+
+    Ok(Json(revocation))
+}
+
+/// Revokes the signing key, for good.
+pub async fn post_revocation(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    Json(req): Json<Signed<Revocation>>,
+) -> Result<Json<Value>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let inserted = store
+        .insert_revocation(&req)
+        .await
+        .map_err(storage("failed to insert revocation"))?;
+
+    if !inserted {
+        return Err(ApiError::new(
+            ErrorCode::KeyRevoked,
+            "This key has been revoked already!",
+        ));
+    }
+
+    Ok(Json(json!({})))
+}
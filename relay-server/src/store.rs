@@ -0,0 +1,247 @@
+mod memory;
+mod migrations;
+mod postgres;
+mod sqlite;
+#[cfg(test)]
+pub mod tests;
+
+use std::{fmt, sync::Arc};
+
+use async_trait::async_trait;
+use lay::{
+    bundle::Bundle,
+    channel::Channel,
+    direct::{DirectEntry, DirectMessage},
+    group::GroupKey,
+    profile::Profile,
+    resource::Resource,
+    rotation::{Revocation, Rotation},
+    text::{Cursor, Edit, PostEntry, Reaction, Retraction},
+    Signed,
+};
+
+pub use memory::MemoryStore;
+pub use migrations::Migrated;
+pub use postgres::PostgresStore;
+pub use sqlite::SqliteStore;
+
+/// Storage shared by all handlers.
+pub type SharedStore = Arc<dyn Store>;
+
+/// Opens the backend selected by the scheme of `url`:
+///
+/// - `postgres://` or `postgresql://` connects to PostgreSQL,
+/// - `memory:` keeps everything in process memory,
+/// - anything else is treated as a SQLite url.
+///
+/// `pool_size` overrides the backend's default number of connections.
+pub async fn open(url: &str, pool_size: Option<usize>) -> Result<SharedStore, StoreError> {
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        let pool_size = pool_size.unwrap_or(postgres::DEFAULT_POOL_SIZE);
+
+        return Ok(Arc::new(PostgresStore::connect(url, pool_size).await?));
+    }
+
+    if url.starts_with("memory:") {
+        return Ok(Arc::new(MemoryStore::default()));
+    }
+
+    let pool_size = pool_size.unwrap_or(sqlite::DEFAULT_POOL_SIZE);
+
+    Ok(Arc::new(SqliteStore::connect(url, pool_size).await?))
+}
+
+/// A failure of the underlying storage.
+#[derive(Debug)]
+pub struct StoreError(pub String);
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "storage error: {}", self.0)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/// A validated [`lay::text::PostRequest`].
+#[derive(Debug, Clone)]
+pub struct PostQuery {
+    pub channel: String,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub author: Option<String>,
+    pub before: Option<Cursor>,
+    pub after: Option<Cursor>,
+    pub thread: Option<String>,
+    pub limit: u64,
+}
+
+impl PostQuery {
+    pub fn matches(&self, entry: &PostEntry) -> bool {
+        let post = &entry.post;
+        let cursor = Cursor::of(post);
+
+        post.data.channel == self.channel
+            && self.since.is_none_or(|it| post.timestamp >= it)
+            && self.until.is_none_or(|it| post.timestamp <= it)
+            && self.author.as_ref().is_none_or(|it| *it == post.key)
+            && self.before.as_ref().is_none_or(|it| cursor < *it)
+            && self.after.as_ref().is_none_or(|it| cursor > *it)
+            && self.thread.as_ref().is_none_or(|it| entry.is_in_thread(it))
+    }
+}
+
+/// A validated [`lay::direct::DirectRequest`] signed by `key`.
+#[derive(Debug, Clone)]
+pub struct DirectQuery {
+    pub key: String,
+    pub peer: String,
+    pub before: Option<Cursor>,
+    pub after: Option<Cursor>,
+    pub limit: u64,
+}
+
+impl DirectQuery {
+    pub fn matches(&self, message: &Signed<DirectMessage>) -> bool {
+        let cursor = Cursor::of(message);
+        let (from, to) = (&message.key, &message.data.recipient);
+
+        ((*from == self.key && *to == self.peer) || (*from == self.peer && *to == self.key))
+            && self.before.as_ref().is_none_or(|it| cursor < *it)
+            && self.after.as_ref().is_none_or(|it| cursor > *it)
+    }
+}
+
+/// Number of keys that reacted to the post `target` with `emoji`.
+#[derive(Debug, Clone)]
+pub struct ReactionCount {
+    pub target: String,
+    pub emoji: String,
+    pub count: u64,
+}
+
+/// An uploaded resource together with the size of its blob.
+#[derive(Debug, Clone)]
+pub struct StoredResource {
+    pub resource: Signed<Resource>,
+    pub size: u64,
+}
+
+/// Everything the handlers persist, so they do not depend on a backend.
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// Brings the schema up to date, fails without changes if the database
+    /// is newer than this binary.
+    async fn migrate(&self) -> Result<Migrated, StoreError>;
+
+    /// Stores a post together with its `thread`, `false` if one with the
+    /// same id exists.
+    async fn insert_post(&self, entry: &PostEntry) -> Result<bool, StoreError>;
+
+    async fn get_post(&self, id: &str) -> Result<Option<PostEntry>, StoreError>;
+
+    /// Posts matching `query`, ordered oldest to newest. Without `after` the
+    /// newest `limit` posts are returned, otherwise the oldest ones after it.
+    async fn query_posts(&self, query: &PostQuery) -> Result<Vec<PostEntry>, StoreError>;
+
+    /// Stores an edit with id `id`, `false` if it exists already.
+    async fn insert_edit(&self, id: &str, edit: &Signed<Edit>) -> Result<bool, StoreError>;
+
+    /// Edits of the posts `targets`, ordered oldest to newest.
+    async fn query_edits(&self, targets: &[String]) -> Result<Vec<Signed<Edit>>, StoreError>;
+
+    /// Turns the post `retraction.data.target` into a tombstone: its content,
+    /// metadata, edits and reactions are dropped, the id is kept. `false` if
+    /// it has been retracted before.
+    async fn retract_post(&self, retraction: &Signed<Retraction>) -> Result<bool, StoreError>;
+
+    /// Retractions of the posts `targets`.
+    async fn query_retractions(
+        &self,
+        targets: &[String],
+    ) -> Result<Vec<Signed<Retraction>>, StoreError>;
+
+    /// Tombstones of the posts in `channel` retracted at or after `since`,
+    /// ordered like [`Store::query_posts`].
+    async fn query_retracted(
+        &self,
+        channel: &str,
+        since: u64,
+    ) -> Result<Vec<PostEntry>, StoreError>;
+
+    /// Adds the reaction of `reaction.key`, or takes it back if `remove` is
+    /// set. `false` if that did not change anything.
+    async fn put_reaction(&self, reaction: &Signed<Reaction>) -> Result<bool, StoreError>;
+
+    /// Reactions on the posts `targets`, counted per emoji.
+    async fn count_reactions(&self, targets: &[String]) -> Result<Vec<ReactionCount>, StoreError>;
+
+    /// Stores a direct message, `false` if one with the same id exists.
+    async fn insert_direct(&self, entry: &DirectEntry) -> Result<bool, StoreError>;
+
+    /// Direct messages matching `query`, ordered and limited like
+    /// [`Store::query_posts`].
+    async fn query_direct(&self, query: &DirectQuery) -> Result<Vec<DirectEntry>, StoreError>;
+
+    async fn get_profile(&self, key: &str) -> Result<Option<Signed<Profile>>, StoreError>;
+
+    /// Replaces the profile of `profile.key`.
+    async fn put_profile(&self, profile: &Signed<Profile>) -> Result<(), StoreError>;
+
+    async fn get_bundle(&self, key: &str) -> Result<Option<Signed<Bundle>>, StoreError>;
+
+    /// Replaces the bundle of `bundle.key`, only its pre-keys can be claimed
+    /// afterwards.
+    async fn put_bundle(&self, bundle: &Signed<Bundle>) -> Result<(), StoreError>;
+
+    /// Takes the unclaimed pre-key of `key` with the lowest id, `None` once
+    /// all of them have been claimed.
+    async fn claim_pre_key(&self, key: &str) -> Result<Option<u32>, StoreError>;
+
+    /// Number of unclaimed pre-keys of `key`.
+    async fn count_pre_keys(&self, key: &str) -> Result<u64, StoreError>;
+
+    /// Stores the rotation of `rotation.key`, `false` if that key or the new
+    /// one has been rotated before. Both are checked in the same step as the
+    /// insert, so concurrent rotations cannot form a cycle.
+    async fn insert_rotation(&self, rotation: &Signed<Rotation>) -> Result<bool, StoreError>;
+
+    async fn get_rotation(&self, key: &str) -> Result<Option<Signed<Rotation>>, StoreError>;
+
+    /// Stores the revocation of `revocation.key`, `false` if that key has
+    /// been revoked before.
+    async fn insert_revocation(&self, revocation: &Signed<Revocation>) -> Result<bool, StoreError>;
+
+    async fn get_revocation(&self, key: &str) -> Result<Option<Signed<Revocation>>, StoreError>;
+
+    /// Moves the last request timestamp of `key` forward, `false` if
+    /// `timestamp` is not newer than the stored one.
+    async fn advance_last_request(&self, key: &str, timestamp: u64) -> Result<bool, StoreError>;
+
+    /// Stores the group key of a new epoch, `false` if that epoch exists.
+    async fn insert_group_key(&self, group_key: &Signed<GroupKey>) -> Result<bool, StoreError>;
+
+    /// The group key of `epoch` in `channel`, the latest one without `epoch`.
+    async fn get_group_key(
+        &self,
+        channel: &str,
+        epoch: Option<u64>,
+    ) -> Result<Option<Signed<GroupKey>>, StoreError>;
+
+    /// Creates a channel, `false` if the name is taken.
+    async fn insert_channel(&self, channel: &Signed<Channel>) -> Result<bool, StoreError>;
+
+    async fn get_channel(&self, name: &str) -> Result<Option<Signed<Channel>>, StoreError>;
+
+    /// All channels, ordered by name.
+    async fn list_channels(&self) -> Result<Vec<Signed<Channel>>, StoreError>;
+
+    /// Records an upload, the first upload of an id is kept.
+    async fn insert_resource(
+        &self,
+        resource: &Signed<Resource>,
+        size: u64,
+    ) -> Result<(), StoreError>;
+
+    async fn get_resource(&self, id: &str) -> Result<Option<StoredResource>, StoreError>;
+}
@@ -0,0 +1,453 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use async_trait::async_trait;
+use lay::{
+    bundle::Bundle,
+    channel::Channel,
+    direct::DirectEntry,
+    group::GroupKey,
+    profile::Profile,
+    resource::Resource,
+    rotation::{Revocation, Rotation},
+    text::{Edit, PostEntry, Reaction, Retraction},
+    Signed,
+};
+
+use super::{DirectQuery, Migrated, PostQuery, ReactionCount, Store, StoreError, StoredResource};
+
+/// Keeps everything in process memory, lost on restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    posts: Vec<PostEntry>,
+    edits: BTreeMap<String, Signed<Edit>>,
+    retractions: HashMap<String, Signed<Retraction>>,
+    /// Keyed by target, key and emoji.
+    reactions: BTreeMap<(String, String, String), Signed<Reaction>>,
+    directs: Vec<DirectEntry>,
+    profiles: HashMap<String, Signed<Profile>>,
+    bundles: HashMap<String, Signed<Bundle>>,
+    /// Unclaimed pre-key ids per key.
+    pre_keys: HashMap<String, BTreeSet<u32>>,
+    rotations: HashMap<String, Signed<Rotation>>,
+    revocations: HashMap<String, Signed<Revocation>>,
+    last_requests: HashMap<String, u64>,
+    channels: BTreeMap<String, Signed<Channel>>,
+    /// Keyed by channel and epoch.
+    group_keys: BTreeMap<(String, u64), Signed<GroupKey>>,
+    resources: HashMap<String, StoredResource>,
+}
+
+impl MemoryStore {
+    // a panic while holding the lock may have left `Inner` half updated
+    fn read(&self) -> Result<RwLockReadGuard<'_, Inner>, StoreError> {
+        self.inner.read().map_err(|_| poisoned())
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, Inner>, StoreError> {
+        self.inner.write().map_err(|_| poisoned())
+    }
+}
+
+fn poisoned() -> StoreError {
+    StoreError("memory store poisoned by an earlier panic".to_string())
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn migrate(&self) -> Result<Migrated, StoreError> {
+        // there is no schema to keep up to date
+        Ok(Migrated { from: 0, to: 0 })
+    }
+
+    async fn insert_post(&self, entry: &PostEntry) -> Result<bool, StoreError> {
+        let mut inner = self.write()?;
+
+        if inner.posts.iter().any(|it| it.id == entry.id) {
+            return Ok(false);
+        }
+
+        inner.posts.push(entry.clone());
+        Ok(true)
+    }
+
+    async fn get_post(&self, id: &str) -> Result<Option<PostEntry>, StoreError> {
+        Ok(self.read()?.posts.iter().find(|it| it.id == id).cloned())
+    }
+
+    async fn query_posts(&self, query: &PostQuery) -> Result<Vec<PostEntry>, StoreError> {
+        let inner = self.read()?;
+
+        let mut posts: Vec<PostEntry> = inner
+            .posts
+            .iter()
+            .filter(|it| query.matches(it))
+            .cloned()
+            .collect();
+        posts.sort_by(|a, b| {
+            (a.post.timestamp, &a.post.signature).cmp(&(b.post.timestamp, &b.post.signature))
+        });
+
+        let limit = query.limit as usize;
+
+        if query.after.is_none() && posts.len() > limit {
+            posts.drain(..posts.len() - limit);
+        }
+        posts.truncate(limit);
+
+        Ok(posts)
+    }
+
+    async fn insert_edit(&self, id: &str, edit: &Signed<Edit>) -> Result<bool, StoreError> {
+        let mut inner = self.write()?;
+
+        if inner.edits.contains_key(id) {
+            return Ok(false);
+        }
+
+        inner.edits.insert(id.to_string(), edit.clone());
+        Ok(true)
+    }
+
+    async fn query_edits(&self, targets: &[String]) -> Result<Vec<Signed<Edit>>, StoreError> {
+        let inner = self.read()?;
+
+        let mut edits: Vec<Signed<Edit>> = inner
+            .edits
+            .values()
+            .filter(|it| targets.contains(&it.data.target))
+            .cloned()
+            .collect();
+        edits.sort_by(|a, b| (a.timestamp, &a.signature).cmp(&(b.timestamp, &b.signature)));
+
+        Ok(edits)
+    }
+
+    async fn retract_post(&self, retraction: &Signed<Retraction>) -> Result<bool, StoreError> {
+        let mut inner = self.write()?;
+        let target = &retraction.data.target;
+
+        if inner.retractions.contains_key(target) {
+            return Ok(false);
+        }
+
+        if let Some(entry) = inner.posts.iter_mut().find(|it| it.id == *target) {
+            entry.post.data.content.clear();
+            entry.post.data.metadata = None;
+        }
+
+        inner.edits.retain(|_, it| it.data.target != *target);
+        inner.reactions.retain(|(it, _, _), _| it != target);
+        inner.retractions.insert(target.clone(), retraction.clone());
+        Ok(true)
+    }
+
+    async fn query_retractions(
+        &self,
+        targets: &[String],
+    ) -> Result<Vec<Signed<Retraction>>, StoreError> {
+        let inner = self.read()?;
+
+        Ok(targets
+            .iter()
+            .filter_map(|it| inner.retractions.get(it))
+            .cloned()
+            .collect())
+    }
+
+    async fn query_retracted(
+        &self,
+        channel: &str,
+        since: u64,
+    ) -> Result<Vec<PostEntry>, StoreError> {
+        let inner = self.read()?;
+
+        let mut posts: Vec<PostEntry> = inner
+            .posts
+            .iter()
+            .filter(|it| {
+                it.post.data.channel == channel
+                    && inner
+                        .retractions
+                        .get(&it.id)
+                        .is_some_and(|it| it.timestamp >= since)
+            })
+            .cloned()
+            .collect();
+        posts.sort_by(|a, b| {
+            (a.post.timestamp, &a.post.signature).cmp(&(b.post.timestamp, &b.post.signature))
+        });
+
+        Ok(posts)
+    }
+
+    async fn put_reaction(&self, reaction: &Signed<Reaction>) -> Result<bool, StoreError> {
+        let mut inner = self.write()?;
+        let id = (
+            reaction.data.target.clone(),
+            reaction.key.clone(),
+            reaction.data.emoji.clone(),
+        );
+
+        if reaction.data.remove {
+            return Ok(inner.reactions.remove(&id).is_some());
+        }
+
+        if inner.reactions.contains_key(&id) {
+            return Ok(false);
+        }
+
+        inner.reactions.insert(id, reaction.clone());
+        Ok(true)
+    }
+
+    async fn count_reactions(&self, targets: &[String]) -> Result<Vec<ReactionCount>, StoreError> {
+        let inner = self.read()?;
+
+        let mut counts: BTreeMap<(&String, &String), u64> = BTreeMap::new();
+
+        for (target, _, emoji) in inner.reactions.keys() {
+            if targets.contains(target) {
+                *counts.entry((target, emoji)).or_default() += 1;
+            }
+        }
+
+        Ok(counts
+            .into_iter()
+            .map(|((target, emoji), count)| ReactionCount {
+                target: target.clone(),
+                emoji: emoji.clone(),
+                count,
+            })
+            .collect())
+    }
+
+    async fn insert_direct(&self, entry: &DirectEntry) -> Result<bool, StoreError> {
+        let mut inner = self.write()?;
+
+        if inner.directs.iter().any(|it| it.id == entry.id) {
+            return Ok(false);
+        }
+
+        inner.directs.push(entry.clone());
+        Ok(true)
+    }
+
+    async fn query_direct(&self, query: &DirectQuery) -> Result<Vec<DirectEntry>, StoreError> {
+        let inner = self.read()?;
+
+        let mut messages: Vec<DirectEntry> = inner
+            .directs
+            .iter()
+            .filter(|it| query.matches(&it.message))
+            .cloned()
+            .collect();
+        messages.sort_by(|a, b| {
+            (a.message.timestamp, &a.message.signature)
+                .cmp(&(b.message.timestamp, &b.message.signature))
+        });
+
+        let limit = query.limit as usize;
+
+        if query.after.is_none() && messages.len() > limit {
+            messages.drain(..messages.len() - limit);
+        }
+        messages.truncate(limit);
+
+        Ok(messages)
+    }
+
+    async fn get_profile(&self, key: &str) -> Result<Option<Signed<Profile>>, StoreError> {
+        Ok(self.read()?.profiles.get(key).cloned())
+    }
+
+    async fn put_profile(&self, profile: &Signed<Profile>) -> Result<(), StoreError> {
+        self.write()?
+            .profiles
+            .insert(profile.key.clone(), profile.clone());
+        Ok(())
+    }
+
+    async fn get_bundle(&self, key: &str) -> Result<Option<Signed<Bundle>>, StoreError> {
+        Ok(self.read()?.bundles.get(key).cloned())
+    }
+
+    async fn put_bundle(&self, bundle: &Signed<Bundle>) -> Result<(), StoreError> {
+        let mut inner = self.write()?;
+
+        inner.pre_keys.insert(
+            bundle.key.clone(),
+            bundle.data.pre_keys.iter().map(|it| it.id).collect(),
+        );
+        inner.bundles.insert(bundle.key.clone(), bundle.clone());
+        Ok(())
+    }
+
+    async fn claim_pre_key(&self, key: &str) -> Result<Option<u32>, StoreError> {
+        Ok(self
+            .write()?
+            .pre_keys
+            .get_mut(key)
+            .and_then(BTreeSet::pop_first))
+    }
+
+    async fn count_pre_keys(&self, key: &str) -> Result<u64, StoreError> {
+        Ok(self
+            .read()?
+            .pre_keys
+            .get(key)
+            .map_or(0, |it| it.len() as u64))
+    }
+
+    async fn insert_rotation(&self, rotation: &Signed<Rotation>) -> Result<bool, StoreError> {
+        let mut inner = self.write()?;
+
+        if inner.rotations.contains_key(&rotation.key)
+            || inner.rotations.contains_key(&rotation.data.new_key)
+        {
+            return Ok(false);
+        }
+
+        inner
+            .rotations
+            .insert(rotation.key.clone(), rotation.clone());
+        Ok(true)
+    }
+
+    async fn get_rotation(&self, key: &str) -> Result<Option<Signed<Rotation>>, StoreError> {
+        Ok(self.read()?.rotations.get(key).cloned())
+    }
+
+    async fn insert_revocation(&self, revocation: &Signed<Revocation>) -> Result<bool, StoreError> {
+        let mut inner = self.write()?;
+
+        if inner.revocations.contains_key(&revocation.key) {
+            return Ok(false);
+        }
+
+        inner
+            .revocations
+            .insert(revocation.key.clone(), revocation.clone());
+        Ok(true)
+    }
+
+    async fn get_revocation(&self, key: &str) -> Result<Option<Signed<Revocation>>, StoreError> {
+        Ok(self.read()?.revocations.get(key).cloned())
+    }
+
+    async fn advance_last_request(&self, key: &str, timestamp: u64) -> Result<bool, StoreError> {
+        let mut inner = self.write()?;
+        let last = inner.last_requests.entry(key.to_string()).or_insert(0);
+
+        if timestamp <= *last {
+            return Ok(false);
+        }
+
+        *last = timestamp;
+        Ok(true)
+    }
+
+    async fn insert_channel(&self, channel: &Signed<Channel>) -> Result<bool, StoreError> {
+        let mut inner = self.write()?;
+
+        if inner.channels.contains_key(&channel.data.name) {
+            return Ok(false);
+        }
+
+        inner
+            .channels
+            .insert(channel.data.name.clone(), channel.clone());
+        Ok(true)
+    }
+
+    async fn insert_group_key(&self, group_key: &Signed<GroupKey>) -> Result<bool, StoreError> {
+        let mut inner = self.write()?;
+        let id = (group_key.data.channel.clone(), group_key.data.epoch);
+
+        if inner.group_keys.contains_key(&id) {
+            return Ok(false);
+        }
+
+        inner.group_keys.insert(id, group_key.clone());
+        Ok(true)
+    }
+
+    async fn get_group_key(
+        &self,
+        channel: &str,
+        epoch: Option<u64>,
+    ) -> Result<Option<Signed<GroupKey>>, StoreError> {
+        let inner = self.read()?;
+        let channel = channel.to_string();
+
+        let group_key = match epoch {
+            Some(epoch) => inner.group_keys.get(&(channel, epoch)),
+            None => inner
+                .group_keys
+                .range((channel.clone(), 0)..=(channel, u64::MAX))
+                .next_back()
+                .map(|(_, it)| it),
+        };
+
+        Ok(group_key.cloned())
+    }
+
+    async fn get_channel(&self, name: &str) -> Result<Option<Signed<Channel>>, StoreError> {
+        Ok(self.read()?.channels.get(name).cloned())
+    }
+
+    async fn list_channels(&self) -> Result<Vec<Signed<Channel>>, StoreError> {
+        Ok(self.read()?.channels.values().cloned().collect())
+    }
+
+    async fn insert_resource(
+        &self,
+        resource: &Signed<Resource>,
+        size: u64,
+    ) -> Result<(), StoreError> {
+        self.write()?
+            .resources
+            .entry(resource.data.id.clone())
+            .or_insert_with(|| StoredResource {
+                resource: resource.clone(),
+                size,
+            });
+        Ok(())
+    }
+
+    async fn get_resource(&self, id: &str) -> Result<Option<StoredResource>, StoreError> {
+        Ok(self.read()?.resources.get(id).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn conformance() {
+        crate::store::tests::run(&MemoryStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn poisoned_lock_is_an_error() {
+        let store = MemoryStore::default();
+
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _inner = store.inner.write().unwrap();
+            panic!("poisons the lock");
+        }));
+
+        assert!(store.get_post("id").await.is_err());
+        assert!(store.advance_last_request("key", 1).await.is_err());
+    }
+}
@@ -0,0 +1,278 @@
+//! Numbered schema migrations.
+//!
+//! Every backend keeps its own list because the dialects differ, but the
+//! versions have to line up: migration `n` does the same thing everywhere.
+//! Applied versions are recorded in `schema_version`, a database is migrated
+//! forward on startup and never downgraded.
+//!
+//! Migrations are append-only, never edit one that has been released.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::StoreError;
+
+/// A single forward migration.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    /// Statements run in order inside one transaction.
+    pub statements: &'static [&'static str],
+    /// Data step run after the statements, in the same transaction.
+    pub backfill: Option<Backfill>,
+}
+
+/// Data steps that need code rather than SQL, each backend implements them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backfill {
+    /// Fills in `posts.id` of older rows, computed from the canonical
+    /// encoding of each post.
+    PostIds,
+}
+
+/// Outcome of a migration run.
+#[derive(Debug, Clone, Copy)]
+pub struct Migrated {
+    pub from: u32,
+    pub to: u32,
+}
+
+/// Bookkeeping table, created before anything else.
+pub const SCHEMA_VERSION_TABLE: &str = "create table if not exists schema_version (version integer primary key, description text not null, applied bigint not null)";
+
+/// Latest version known to this binary.
+pub fn latest(migrations: &[Migration]) -> u32 {
+    migrations.last().map_or(0, |it| it.version)
+}
+
+/// Refuses databases written by a newer binary, their schema is unknown.
+pub fn check_supported(current: u32, migrations: &[Migration]) -> Result<(), StoreError> {
+    let latest = latest(migrations);
+
+    if current > latest {
+        return Err(StoreError(format!(
+            "database schema version {current} is newer than the latest one this build knows ({latest})"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Timestamp recorded in `schema_version.applied`, in milliseconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Migrations that still have to run on a database at `current`.
+pub fn pending(migrations: &[Migration], current: u32) -> &[Migration] {
+    let start = migrations.partition_point(|it| it.version <= current);
+
+    &migrations[start..]
+}
+
+// Version 1 uses `if not exists` so databases created before migrations
+// existed are adopted as they are. The oldest of those have no `metadata` on
+// posts and profiles, so the columns are added again, adding a column that
+// exists already is skipped. Version 2 adds `posts.id` and fills it in for
+// older rows.
+
+pub const SQLITE: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        statements: &[
+            "create table if not exists posts (key varchar(48) not null, server varchar(48) not null, timestamp bigint not null, channel text not null, content text, metadata text, signature varchar(96) primary key)",
+            "alter table posts add column metadata text",
+            "create index if not exists posts_channel_timestamp on posts (channel, timestamp, signature)",
+            "create table if not exists users (key varchar(48) primary key, lastrequest bigint not null)",
+            "create table if not exists channels (name varchar(64) primary key, key varchar(48) not null, server varchar(48) not null, timestamp bigint not null, topic text, metadata text, signature varchar(96) not null)",
+            "create table if not exists resources (id varchar(64) primary key, key varchar(48) not null, server varchar(48) not null, timestamp bigint not null, size bigint not null, metadata text, signature varchar(96) not null)",
+            "create table if not exists profiles (key varchar(48) primary key, server varchar(48) not null, timestamp bigint not null, name varchar(255) not null, metadata text, signature varchar(96) not null)",
+            "alter table profiles add column metadata text",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 2,
+        description: "content-addressed post ids",
+        statements: &[
+            "alter table posts add column id varchar(64)",
+            "create unique index if not exists posts_id on posts (id)",
+        ],
+        backfill: Some(Backfill::PostIds),
+    },
+    Migration {
+        version: 3,
+        description: "post edits",
+        statements: &[
+            "create table if not exists edits (id varchar(64) primary key, target varchar(64) not null, key varchar(48) not null, server varchar(48) not null, timestamp bigint not null, content text not null, metadata text, signature varchar(96) not null)",
+            "create index if not exists edits_target on edits (target, timestamp, signature)",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 4,
+        description: "post retractions",
+        statements: &[
+            "create table if not exists retractions (target varchar(64) primary key, key varchar(48) not null, server varchar(48) not null, timestamp bigint not null, metadata text, signature varchar(96) not null)",
+            "create index if not exists retractions_timestamp on retractions (timestamp)",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 5,
+        description: "threaded replies",
+        statements: &[
+            "alter table posts add column reply_to varchar(64)",
+            "alter table posts add column thread varchar(64)",
+            "create index if not exists posts_thread on posts (thread, timestamp, signature)",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 6,
+        description: "post reactions",
+        statements: &[
+            "create table if not exists reactions (target varchar(64) not null, key varchar(48) not null, emoji varchar(32) not null, server varchar(48) not null, timestamp bigint not null, signature varchar(96) not null, primary key (target, key, emoji))",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 7,
+        description: "direct messages",
+        statements: &[
+            "create table if not exists direct_messages (id varchar(64) primary key, key varchar(48) not null, recipient varchar(48) not null, server varchar(48) not null, timestamp bigint not null, content text not null, metadata text, signature varchar(96) not null)",
+            "create index if not exists direct_messages_pair on direct_messages (key, recipient, timestamp, signature)",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 8,
+        description: "identity bundles",
+        statements: &[
+            "create table if not exists bundles (key varchar(48) primary key, server varchar(48) not null, timestamp bigint not null, identity_key varchar(48) not null, pre_keys text not null, metadata text, signature varchar(96) not null)",
+            "create table if not exists pre_keys (key varchar(48) not null, id bigint not null, primary key (key, id))",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 9,
+        description: "group keys",
+        statements: &[
+            "create table if not exists group_keys (channel text not null, epoch bigint not null, key varchar(48) not null, server varchar(48) not null, timestamp bigint not null, keys text not null, metadata text, signature varchar(96) not null, primary key (channel, epoch))",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 10,
+        description: "key rotations and revocations",
+        statements: &[
+            "create table if not exists rotations (key varchar(48) primary key, server varchar(48) not null, timestamp bigint not null, new_key varchar(48) not null, countersignature varchar(96) not null, metadata text, signature varchar(96) not null)",
+            "create table if not exists revocations (key varchar(48) primary key, server varchar(48) not null, timestamp bigint not null, reason text, metadata text, signature varchar(96) not null)",
+        ],
+        backfill: None,
+    },
+];
+
+// Signatures and names compare bytewise (`collate "C"`) so cursors order the
+// same way as in the other backends.
+
+pub const POSTGRES: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        statements: &[
+            r#"create table if not exists posts (key text not null, server text not null, timestamp bigint not null, channel text collate "C" not null, content text not null, metadata jsonb, signature text collate "C" primary key)"#,
+            "alter table posts add column if not exists metadata jsonb",
+            "create index if not exists posts_channel_timestamp on posts (channel, timestamp, signature)",
+            "create table if not exists users (key text primary key, lastrequest bigint not null)",
+            r#"create table if not exists channels (name text collate "C" primary key, key text not null, server text not null, timestamp bigint not null, topic text, metadata jsonb, signature text not null)"#,
+            "create table if not exists resources (id text primary key, key text not null, server text not null, timestamp bigint not null, size bigint not null, metadata jsonb, signature text not null)",
+            "create table if not exists profiles (key text primary key, server text not null, timestamp bigint not null, name text not null, metadata jsonb, signature text not null)",
+            "alter table profiles add column if not exists metadata jsonb",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 2,
+        description: "content-addressed post ids",
+        statements: &[
+            "alter table posts add column if not exists id text",
+            "create unique index if not exists posts_id on posts (id)",
+        ],
+        backfill: Some(Backfill::PostIds),
+    },
+    Migration {
+        version: 3,
+        description: "post edits",
+        statements: &[
+            r#"create table if not exists edits (id text primary key, target text not null, key text not null, server text not null, timestamp bigint not null, content text not null, metadata jsonb, signature text collate "C" not null)"#,
+            "create index if not exists edits_target on edits (target, timestamp, signature)",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 4,
+        description: "post retractions",
+        statements: &[
+            "create table if not exists retractions (target text primary key, key text not null, server text not null, timestamp bigint not null, metadata jsonb, signature text not null)",
+            "create index if not exists retractions_timestamp on retractions (timestamp)",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 5,
+        description: "threaded replies",
+        statements: &[
+            "alter table posts add column if not exists reply_to text",
+            "alter table posts add column if not exists thread text",
+            "create index if not exists posts_thread on posts (thread, timestamp, signature)",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 6,
+        description: "post reactions",
+        statements: &[
+            "create table if not exists reactions (target text not null, key text not null, emoji text not null, server text not null, timestamp bigint not null, signature text not null, primary key (target, key, emoji))",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 7,
+        description: "direct messages",
+        statements: &[
+            r#"create table if not exists direct_messages (id text primary key, key text not null, recipient text not null, server text not null, timestamp bigint not null, content text not null, metadata jsonb, signature text collate "C" not null)"#,
+            "create index if not exists direct_messages_pair on direct_messages (key, recipient, timestamp, signature)",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 8,
+        description: "identity bundles",
+        statements: &[
+            "create table if not exists bundles (key text primary key, server text not null, timestamp bigint not null, identity_key text not null, pre_keys jsonb not null, metadata jsonb, signature text not null)",
+            "create table if not exists pre_keys (key text not null, id bigint not null, primary key (key, id))",
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 9,
+        description: "group keys",
+        statements: &[
+            r#"create table if not exists group_keys (channel text collate "C" not null, epoch bigint not null, key text not null, server text not null, timestamp bigint not null, keys jsonb not null, metadata jsonb, signature text not null, primary key (channel, epoch))"#,
+        ],
+        backfill: None,
+    },
+    Migration {
+        version: 10,
+        description: "key rotations and revocations",
+        statements: &[
+            "create table if not exists rotations (key text primary key, server text not null, timestamp bigint not null, new_key text not null, countersignature text not null, metadata jsonb, signature text not null)",
+            "create table if not exists revocations (key text primary key, server text not null, timestamp bigint not null, reason text, metadata jsonb, signature text not null)",
+        ],
+        backfill: None,
+    },
+];
@@ -0,0 +1,1212 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use deadpool_postgres::{Config, Pool, PoolConfig, Runtime, Transaction};
+use lay::{
+    bundle::Bundle,
+    channel::Channel,
+    direct::{DirectEntry, DirectMessage},
+    group::GroupKey,
+    profile::Profile,
+    resource::Resource,
+    rotation::{Revocation, Rotation},
+    text::{Edit, Post, PostEntry, Reaction, Retraction},
+    Signed,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use tokio_postgres::{types::ToSql, NoTls, Row};
+
+use super::{
+    migrations::{self, Backfill, Migrated, POSTGRES, SCHEMA_VERSION_TABLE},
+    DirectQuery, PostQuery, ReactionCount, Store, StoreError, StoredResource,
+};
+
+/// Default number of pooled connections.
+pub const DEFAULT_POOL_SIZE: usize = 16;
+
+impl From<tokio_postgres::Error> for StoreError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        Self(error.to_string())
+    }
+}
+
+impl From<deadpool_postgres::PoolError> for StoreError {
+    fn from(error: deadpool_postgres::PoolError) -> Self {
+        Self(error.to_string())
+    }
+}
+
+/// A value for a `bigint` column. Anything above `i64::MAX - 1` is refused
+/// rather than wrapped, which leaves `i64::MAX` free for [`bound`].
+fn bigint(value: u64) -> Result<i64, StoreError> {
+    i64::try_from(value)
+        .ok()
+        .filter(|it| *it < i64::MAX)
+        .ok_or_else(|| StoreError(format!("{value} is too large to store")))
+}
+
+/// A value compared against a `bigint` column. Stored values stay below
+/// `i64::MAX`, so saturating compares exactly like the `u64` would.
+fn bound(value: u64) -> i64 {
+    i64::try_from(value).unwrap_or(i64::MAX)
+}
+
+fn to_jsonb(metadata: &Option<Map<String, Value>>) -> Option<Value> {
+    metadata.clone().map(Value::Object)
+}
+
+/// Reads a metadata column, anything but an object or null is corrupt.
+fn from_jsonb(row: &Row, column: &str) -> Result<Option<Map<String, Value>>, StoreError> {
+    match row.try_get::<_, Option<Value>>(column)? {
+        Some(Value::Object(it)) => Ok(Some(it)),
+        None | Some(Value::Null) => Ok(None),
+        Some(_) => Err(StoreError(format!(
+            "malformed JSON in the database: {column} is not an object"
+        ))),
+    }
+}
+
+/// Reads a `jsonb` column into `T`, a corrupt value is an error rather than
+/// a default.
+fn from_json<T: DeserializeOwned>(row: &Row, column: &str) -> Result<T, StoreError> {
+    serde_json::from_value(row.try_get(column)?)
+        .map_err(|it| StoreError(format!("malformed JSON in the database: {it}")))
+}
+
+fn post_from_row(row: &Row) -> Result<PostEntry, StoreError> {
+    let post = Signed {
+        key: row.try_get("key")?,
+        server: row.try_get("server")?,
+        timestamp: row.try_get::<_, i64>("timestamp")? as u64,
+        data: Post {
+            channel: row.try_get("channel")?,
+            content: row.try_get("content")?,
+            reply_to: row.try_get("reply_to")?,
+            metadata: from_jsonb(row, "metadata")?,
+        },
+        signature: row.try_get("signature")?,
+    };
+
+    // only rows that have not been backfilled yet lack an id
+    let id = row
+        .try_get::<_, Option<String>>("id")?
+        .or_else(|| post.id())
+        .ok_or_else(|| StoreError("post id cannot be computed".to_string()))?;
+
+    Ok(PostEntry {
+        id,
+        post,
+        thread: row.try_get("thread")?,
+        edits: Vec::new(),
+        retraction: None,
+        reactions: BTreeMap::new(),
+    })
+}
+
+fn direct_from_row(row: &Row) -> Result<DirectEntry, StoreError> {
+    Ok(DirectEntry {
+        id: row.try_get("id")?,
+        message: Signed {
+            key: row.try_get("key")?,
+            server: row.try_get("server")?,
+            timestamp: row.try_get::<_, i64>("timestamp")? as u64,
+            data: DirectMessage {
+                recipient: row.try_get("recipient")?,
+                content: row.try_get("content")?,
+                metadata: from_jsonb(row, "metadata")?,
+            },
+            signature: row.try_get("signature")?,
+        },
+    })
+}
+
+fn edit_from_row(row: &Row) -> Result<Signed<Edit>, StoreError> {
+    Ok(Signed {
+        key: row.try_get("key")?,
+        server: row.try_get("server")?,
+        timestamp: row.try_get::<_, i64>("timestamp")? as u64,
+        data: Edit {
+            target: row.try_get("target")?,
+            content: row.try_get("content")?,
+            metadata: from_jsonb(row, "metadata")?,
+        },
+        signature: row.try_get("signature")?,
+    })
+}
+
+fn retraction_from_row(row: &Row) -> Result<Signed<Retraction>, StoreError> {
+    Ok(Signed {
+        key: row.try_get("key")?,
+        server: row.try_get("server")?,
+        timestamp: row.try_get::<_, i64>("timestamp")? as u64,
+        data: Retraction {
+            target: row.try_get("target")?,
+            metadata: from_jsonb(row, "metadata")?,
+        },
+        signature: row.try_get("signature")?,
+    })
+}
+
+fn bundle_from_row(row: &Row) -> Result<Signed<Bundle>, StoreError> {
+    Ok(Signed {
+        key: row.try_get("key")?,
+        server: row.try_get("server")?,
+        timestamp: row.try_get::<_, i64>("timestamp")? as u64,
+        data: Bundle {
+            identity_key: row.try_get("identity_key")?,
+            pre_keys: from_json(row, "pre_keys")?,
+            metadata: from_jsonb(row, "metadata")?,
+        },
+        signature: row.try_get("signature")?,
+    })
+}
+
+fn group_key_from_row(row: &Row) -> Result<Signed<GroupKey>, StoreError> {
+    Ok(Signed {
+        key: row.try_get("key")?,
+        server: row.try_get("server")?,
+        timestamp: row.try_get::<_, i64>("timestamp")? as u64,
+        data: GroupKey {
+            channel: row.try_get("channel")?,
+            epoch: row.try_get::<_, i64>("epoch")? as u64,
+            keys: from_json(row, "keys")?,
+            metadata: from_jsonb(row, "metadata")?,
+        },
+        signature: row.try_get("signature")?,
+    })
+}
+
+fn rotation_from_row(row: &Row) -> Result<Signed<Rotation>, StoreError> {
+    Ok(Signed {
+        key: row.try_get("key")?,
+        server: row.try_get("server")?,
+        timestamp: row.try_get::<_, i64>("timestamp")? as u64,
+        data: Rotation {
+            new_key: row.try_get("new_key")?,
+            countersignature: row.try_get("countersignature")?,
+            metadata: from_jsonb(row, "metadata")?,
+        },
+        signature: row.try_get("signature")?,
+    })
+}
+
+fn revocation_from_row(row: &Row) -> Result<Signed<Revocation>, StoreError> {
+    Ok(Signed {
+        key: row.try_get("key")?,
+        server: row.try_get("server")?,
+        timestamp: row.try_get::<_, i64>("timestamp")? as u64,
+        data: Revocation {
+            reason: row.try_get("reason")?,
+            metadata: from_jsonb(row, "metadata")?,
+        },
+        signature: row.try_get("signature")?,
+    })
+}
+
+fn profile_from_row(row: &Row) -> Result<Signed<Profile>, StoreError> {
+    Ok(Signed {
+        key: row.try_get("key")?,
+        server: row.try_get("server")?,
+        timestamp: row.try_get::<_, i64>("timestamp")? as u64,
+        data: Profile {
+            name: row.try_get("name")?,
+            metadata: from_jsonb(row, "metadata")?,
+        },
+        signature: row.try_get("signature")?,
+    })
+}
+
+fn channel_from_row(row: &Row) -> Result<Signed<Channel>, StoreError> {
+    Ok(Signed {
+        key: row.try_get("key")?,
+        server: row.try_get("server")?,
+        timestamp: row.try_get::<_, i64>("timestamp")? as u64,
+        data: Channel {
+            name: row.try_get("name")?,
+            topic: row.try_get("topic")?,
+            metadata: from_jsonb(row, "metadata")?,
+        },
+        signature: row.try_get("signature")?,
+    })
+}
+
+fn resource_from_row(row: &Row) -> Result<StoredResource, StoreError> {
+    Ok(StoredResource {
+        resource: Signed {
+            key: row.try_get("key")?,
+            server: row.try_get("server")?,
+            timestamp: row.try_get::<_, i64>("timestamp")? as u64,
+            data: Resource {
+                id: row.try_get("id")?,
+                metadata: from_jsonb(row, "metadata")?,
+            },
+            signature: row.try_get("signature")?,
+        },
+        size: row.try_get::<_, i64>("size")? as u64,
+    })
+}
+
+/// PostgreSQL backend with a connection pool, selected by a `postgres://` or
+/// `postgresql://` url.
+pub struct PostgresStore {
+    pool: Pool,
+}
+
+impl PostgresStore {
+    /// Connects to `url`, see [`Store::migrate`] for the schema.
+    pub async fn connect(url: &str, pool_size: usize) -> Result<Self, StoreError> {
+        let mut config = Config::new();
+        config.url = Some(url.to_string());
+        config.pool = Some(PoolConfig::new(pool_size));
+
+        let pool = config
+            .create_pool(Some(Runtime::Tokio1), NoTls)
+            .map_err(|it| StoreError(it.to_string()))?;
+
+        Ok(Self { pool })
+    }
+}
+
+/// Fills in `posts.id` of rows written before migration 2, replies came later.
+async fn backfill_post_ids(tx: &Transaction<'_>) -> Result<(), StoreError> {
+    let rows = tx
+        .query(
+            "select id, key, server, timestamp, channel, content, null::text as reply_to, null::text as thread, metadata, signature from posts where id is null;",
+            &[],
+        )
+        .await?;
+
+    for row in &rows {
+        let entry = post_from_row(row)?;
+
+        tx.execute(
+            "update posts set id = $1 where signature = $2;",
+            &[&entry.id, &entry.post.signature],
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl Store for PostgresStore {
+    async fn migrate(&self) -> Result<Migrated, StoreError> {
+        let mut client = self.pool.get().await?;
+        client.batch_execute(SCHEMA_VERSION_TABLE).await?;
+
+        // DDL is transactional here, so all pending migrations either apply
+        // together or not at all, and the lock keeps concurrent servers out
+        let tx = client.transaction().await?;
+        tx.batch_execute("lock table schema_version in exclusive mode;")
+            .await?;
+
+        let from = tx
+            .query_one("select coalesce(max(version), 0) from schema_version;", &[])
+            .await?
+            .try_get::<_, i32>(0)? as u32;
+
+        migrations::check_supported(from, POSTGRES)?;
+
+        for migration in migrations::pending(POSTGRES, from) {
+            for statement in migration.statements {
+                tx.batch_execute(statement).await?;
+            }
+
+            if migration.backfill == Some(Backfill::PostIds) {
+                backfill_post_ids(&tx).await?;
+            }
+
+            tx.execute(
+                "insert into schema_version (version, description, applied) values ($1, $2, $3);",
+                &[
+                    &(migration.version as i32),
+                    &migration.description,
+                    &bigint(migrations::now())?,
+                ],
+            )
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(Migrated {
+            from,
+            to: migrations::latest(POSTGRES).max(from),
+        })
+    }
+
+    async fn insert_post(&self, entry: &PostEntry) -> Result<bool, StoreError> {
+        let post = &entry.post;
+
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .execute(
+                "insert into posts (id, key, server, timestamp, channel, content, reply_to, thread, metadata, signature) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) on conflict do nothing;",
+                &[
+                    &entry.id,
+                    &post.key,
+                    &post.server,
+                    &bigint(post.timestamp)?,
+                    &post.data.channel,
+                    &post.data.content,
+                    &post.data.reply_to,
+                    &entry.thread,
+                    &to_jsonb(&post.data.metadata),
+                    &post.signature,
+                ],
+            )
+            .await?;
+
+        Ok(rows > 0)
+    }
+
+    async fn get_post(&self, id: &str) -> Result<Option<PostEntry>, StoreError> {
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_opt("select * from posts where id = $1;", &[&id])
+            .await?;
+
+        row.as_ref().map(post_from_row).transpose()
+    }
+
+    async fn query_posts(&self, query: &PostQuery) -> Result<Vec<PostEntry>, StoreError> {
+        let mut filters = vec!["channel = $1".to_string()];
+        let mut args: Vec<Box<dyn ToSql + Sync + Send>> = vec![Box::new(query.channel.clone())];
+
+        // placeholder for the next argument
+        let next = |args: &Vec<Box<dyn ToSql + Sync + Send>>| format!("${}", args.len() + 1);
+
+        if let Some(since) = query.since {
+            filters.push(format!("timestamp >= {}", next(&args)));
+            args.push(Box::new(bound(since)));
+        }
+
+        if let Some(until) = query.until {
+            filters.push(format!("timestamp <= {}", next(&args)));
+            args.push(Box::new(bound(until)));
+        }
+
+        if let Some(author) = &query.author {
+            filters.push(format!("key = {}", next(&args)));
+            args.push(Box::new(author.clone()));
+        }
+
+        if let Some(thread) = &query.thread {
+            let id = next(&args);
+            filters.push(format!("(id = {id} or thread = {id})"));
+            args.push(Box::new(thread.clone()));
+        }
+
+        for (cursor, op) in [(&query.before, "<"), (&query.after, ">")] {
+            let Some(cursor) = cursor else {
+                continue;
+            };
+
+            let timestamp = next(&args);
+            args.push(Box::new(bound(cursor.timestamp)));
+            let signature = next(&args);
+            args.push(Box::new(cursor.signature.clone()));
+
+            filters.push(format!(
+                "(timestamp, signature) {op} ({timestamp}, {signature})"
+            ));
+        }
+
+        // Page forwards from `after`, otherwise take the newest page and flip it.
+        let order = if query.after.is_some() { "asc" } else { "desc" };
+        let limit = next(&args);
+        args.push(Box::new(bound(query.limit)));
+
+        let sql = format!(
+            "select * from posts where {} order by timestamp {order}, signature {order} limit {limit};",
+            filters.join(" and ")
+        );
+        let args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|it| it.as_ref() as _).collect();
+
+        let rows = self.pool.get().await?.query(&sql, &args).await?;
+
+        let mut posts: Vec<PostEntry> = rows.iter().map(post_from_row).collect::<Result<_, _>>()?;
+
+        if query.after.is_none() {
+            posts.reverse();
+        }
+
+        Ok(posts)
+    }
+
+    async fn insert_edit(&self, id: &str, edit: &Signed<Edit>) -> Result<bool, StoreError> {
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .execute(
+                "insert into edits (id, target, key, server, timestamp, content, metadata, signature) values ($1, $2, $3, $4, $5, $6, $7, $8) on conflict (id) do nothing;",
+                &[
+                    &id,
+                    &edit.data.target,
+                    &edit.key,
+                    &edit.server,
+                    &bigint(edit.timestamp)?,
+                    &edit.data.content,
+                    &to_jsonb(&edit.data.metadata),
+                    &edit.signature,
+                ],
+            )
+            .await?;
+
+        Ok(rows > 0)
+    }
+
+    async fn query_edits(&self, targets: &[String]) -> Result<Vec<Signed<Edit>>, StoreError> {
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query(
+                "select * from edits where target = any($1) order by timestamp, signature;",
+                &[&targets],
+            )
+            .await?;
+
+        rows.iter().map(edit_from_row).collect()
+    }
+
+    async fn retract_post(&self, retraction: &Signed<Retraction>) -> Result<bool, StoreError> {
+        let target = &retraction.data.target;
+        let mut client = self.pool.get().await?;
+        // an early return drops `tx`, which rolls it back
+        let tx = client.transaction().await?;
+
+        let inserted = tx
+            .execute(
+                "insert into retractions (target, key, server, timestamp, metadata, signature) values ($1, $2, $3, $4, $5, $6) on conflict (target) do nothing;",
+                &[
+                    target,
+                    &retraction.key,
+                    &retraction.server,
+                    &bigint(retraction.timestamp)?,
+                    &to_jsonb(&retraction.data.metadata),
+                    &retraction.signature,
+                ],
+            )
+            .await?
+            > 0;
+
+        if inserted {
+            tx.execute(
+                "update posts set content = '', metadata = null where id = $1;",
+                &[target],
+            )
+            .await?;
+            tx.execute("delete from edits where target = $1;", &[target])
+                .await?;
+            tx.execute("delete from reactions where target = $1;", &[target])
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(inserted)
+    }
+
+    async fn query_retractions(
+        &self,
+        targets: &[String],
+    ) -> Result<Vec<Signed<Retraction>>, StoreError> {
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query(
+                "select * from retractions where target = any($1);",
+                &[&targets],
+            )
+            .await?;
+
+        rows.iter().map(retraction_from_row).collect()
+    }
+
+    async fn query_retracted(
+        &self,
+        channel: &str,
+        since: u64,
+    ) -> Result<Vec<PostEntry>, StoreError> {
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query(
+                "select * from posts where channel = $1 and id in (select target from retractions where timestamp >= $2) order by timestamp, signature;",
+                &[&channel, &bound(since)],
+            )
+            .await?;
+
+        rows.iter().map(post_from_row).collect()
+    }
+
+    async fn put_reaction(&self, reaction: &Signed<Reaction>) -> Result<bool, StoreError> {
+        let client = self.pool.get().await?;
+
+        let rows = if reaction.data.remove {
+            client
+                .execute(
+                    "delete from reactions where target = $1 and key = $2 and emoji = $3;",
+                    &[&reaction.data.target, &reaction.key, &reaction.data.emoji],
+                )
+                .await?
+        } else {
+            client
+                .execute(
+                    "insert into reactions (target, key, emoji, server, timestamp, signature) values ($1, $2, $3, $4, $5, $6) on conflict do nothing;",
+                    &[
+                        &reaction.data.target,
+                        &reaction.key,
+                        &reaction.data.emoji,
+                        &reaction.server,
+                        &bigint(reaction.timestamp)?,
+                        &reaction.signature,
+                    ],
+                )
+                .await?
+        };
+
+        Ok(rows > 0)
+    }
+
+    async fn count_reactions(&self, targets: &[String]) -> Result<Vec<ReactionCount>, StoreError> {
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query(
+                "select target, emoji, count(*) as count from reactions where target = any($1) group by target, emoji;",
+                &[&targets],
+            )
+            .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(ReactionCount {
+                    target: row.try_get("target")?,
+                    emoji: row.try_get("emoji")?,
+                    count: row.try_get::<_, i64>("count")? as u64,
+                })
+            })
+            .collect()
+    }
+
+    async fn insert_direct(&self, entry: &DirectEntry) -> Result<bool, StoreError> {
+        let message = &entry.message;
+
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .execute(
+                "insert into direct_messages (id, key, recipient, server, timestamp, content, metadata, signature) values ($1, $2, $3, $4, $5, $6, $7, $8) on conflict do nothing;",
+                &[
+                    &entry.id,
+                    &message.key,
+                    &message.data.recipient,
+                    &message.server,
+                    &bigint(message.timestamp)?,
+                    &message.data.content,
+                    &to_jsonb(&message.data.metadata),
+                    &message.signature,
+                ],
+            )
+            .await?;
+
+        Ok(rows > 0)
+    }
+
+    async fn query_direct(&self, query: &DirectQuery) -> Result<Vec<DirectEntry>, StoreError> {
+        let mut filters =
+            vec!["((key = $1 and recipient = $2) or (key = $2 and recipient = $1))".to_string()];
+        let mut args: Vec<Box<dyn ToSql + Sync + Send>> =
+            vec![Box::new(query.key.clone()), Box::new(query.peer.clone())];
+
+        // placeholder for the next argument
+        let next = |args: &Vec<Box<dyn ToSql + Sync + Send>>| format!("${}", args.len() + 1);
+
+        for (cursor, op) in [(&query.before, "<"), (&query.after, ">")] {
+            let Some(cursor) = cursor else {
+                continue;
+            };
+
+            let timestamp = next(&args);
+            args.push(Box::new(bound(cursor.timestamp)));
+            let signature = next(&args);
+            args.push(Box::new(cursor.signature.clone()));
+
+            filters.push(format!(
+                "(timestamp, signature) {op} ({timestamp}, {signature})"
+            ));
+        }
+
+        // Page forwards from `after`, otherwise take the newest page and flip it.
+        let order = if query.after.is_some() { "asc" } else { "desc" };
+        let limit = next(&args);
+        args.push(Box::new(bound(query.limit)));
+
+        let sql = format!(
+            "select * from direct_messages where {} order by timestamp {order}, signature {order} limit {limit};",
+            filters.join(" and ")
+        );
+        let args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|it| it.as_ref() as _).collect();
+
+        let rows = self.pool.get().await?.query(&sql, &args).await?;
+
+        let mut messages: Vec<DirectEntry> =
+            rows.iter().map(direct_from_row).collect::<Result<_, _>>()?;
+
+        if query.after.is_none() {
+            messages.reverse();
+        }
+
+        Ok(messages)
+    }
+
+    async fn get_profile(&self, key: &str) -> Result<Option<Signed<Profile>>, StoreError> {
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_opt("select * from profiles where key = $1;", &[&key])
+            .await?;
+
+        row.as_ref().map(profile_from_row).transpose()
+    }
+
+    async fn put_profile(&self, profile: &Signed<Profile>) -> Result<(), StoreError> {
+        self.pool
+            .get()
+            .await?
+            .execute(
+                "insert into profiles (key, server, timestamp, name, metadata, signature) values ($1, $2, $3, $4, $5, $6) on conflict (key) do update set server = excluded.server, timestamp = excluded.timestamp, name = excluded.name, metadata = excluded.metadata, signature = excluded.signature;",
+                &[
+                    &profile.key,
+                    &profile.server,
+                    &bigint(profile.timestamp)?,
+                    &profile.data.name,
+                    &to_jsonb(&profile.data.metadata),
+                    &profile.signature,
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_bundle(&self, key: &str) -> Result<Option<Signed<Bundle>>, StoreError> {
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_opt("select * from bundles where key = $1;", &[&key])
+            .await?;
+
+        row.as_ref().map(bundle_from_row).transpose()
+    }
+
+    async fn put_bundle(&self, bundle: &Signed<Bundle>) -> Result<(), StoreError> {
+        let pre_keys =
+            serde_json::to_value(&bundle.data.pre_keys).map_err(|it| StoreError(it.to_string()))?;
+        let ids: Vec<i64> = bundle
+            .data
+            .pre_keys
+            .iter()
+            .map(|it| i64::from(it.id))
+            .collect();
+
+        let mut client = self.pool.get().await?;
+        // an early return drops `tx`, which rolls it back
+        let tx = client.transaction().await?;
+
+        tx.execute(
+            "insert into bundles (key, server, timestamp, identity_key, pre_keys, metadata, signature) values ($1, $2, $3, $4, $5, $6, $7) on conflict (key) do update set server = excluded.server, timestamp = excluded.timestamp, identity_key = excluded.identity_key, pre_keys = excluded.pre_keys, metadata = excluded.metadata, signature = excluded.signature;",
+            &[
+                &bundle.key,
+                &bundle.server,
+                &bigint(bundle.timestamp)?,
+                &bundle.data.identity_key,
+                &pre_keys,
+                &to_jsonb(&bundle.data.metadata),
+                &bundle.signature,
+            ],
+        )
+        .await?;
+        tx.execute("delete from pre_keys where key = $1;", &[&bundle.key])
+            .await?;
+        tx.execute(
+            "insert into pre_keys (key, id) select $1, unnest($2::bigint[]);",
+            &[&bundle.key, &ids],
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn claim_pre_key(&self, key: &str) -> Result<Option<u32>, StoreError> {
+        // rows locked by a concurrent claim are skipped instead of waited for
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_opt(
+                "delete from pre_keys where (key, id) = (select key, id from pre_keys where key = $1 order by id limit 1 for update skip locked) returning id;",
+                &[&key],
+            )
+            .await?;
+
+        Ok(row
+            .map(|it| it.try_get::<_, i64>("id"))
+            .transpose()?
+            .map(|it| it as u32))
+    }
+
+    async fn count_pre_keys(&self, key: &str) -> Result<u64, StoreError> {
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_one(
+                "select count(*) as count from pre_keys where key = $1;",
+                &[&key],
+            )
+            .await?;
+
+        Ok(row.try_get::<_, i64>("count")? as u64)
+    }
+
+    async fn insert_rotation(&self, rotation: &Signed<Rotation>) -> Result<bool, StoreError> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+
+        // under read committed a concurrent rotation of the new key would go
+        // unseen, the lock orders them so the later one sees the earlier
+        tx.batch_execute("lock table rotations in share row exclusive mode;")
+            .await?;
+
+        let rows = tx
+            .execute(
+                "insert into rotations (key, server, timestamp, new_key, countersignature, metadata, signature) select $1, $2, $3, $4, $5, $6, $7 where not exists (select 1 from rotations where key = $4) on conflict (key) do nothing;",
+                &[
+                    &rotation.key,
+                    &rotation.server,
+                    &bigint(rotation.timestamp)?,
+                    &rotation.data.new_key,
+                    &rotation.data.countersignature,
+                    &to_jsonb(&rotation.data.metadata),
+                    &rotation.signature,
+                ],
+            )
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(rows > 0)
+    }
+
+    async fn get_rotation(&self, key: &str) -> Result<Option<Signed<Rotation>>, StoreError> {
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_opt("select * from rotations where key = $1;", &[&key])
+            .await?;
+
+        row.as_ref().map(rotation_from_row).transpose()
+    }
+
+    async fn insert_revocation(&self, revocation: &Signed<Revocation>) -> Result<bool, StoreError> {
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .execute(
+                "insert into revocations (key, server, timestamp, reason, metadata, signature) values ($1, $2, $3, $4, $5, $6) on conflict (key) do nothing;",
+                &[
+                    &revocation.key,
+                    &revocation.server,
+                    &bigint(revocation.timestamp)?,
+                    &revocation.data.reason,
+                    &to_jsonb(&revocation.data.metadata),
+                    &revocation.signature,
+                ],
+            )
+            .await?;
+
+        Ok(rows > 0)
+    }
+
+    async fn get_revocation(&self, key: &str) -> Result<Option<Signed<Revocation>>, StoreError> {
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_opt("select * from revocations where key = $1;", &[&key])
+            .await?;
+
+        row.as_ref().map(revocation_from_row).transpose()
+    }
+
+    async fn advance_last_request(&self, key: &str, timestamp: u64) -> Result<bool, StoreError> {
+        // same single-statement check-and-set as the sqlite backend
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .execute(
+                "insert into users (key, lastrequest) values ($1, $2) on conflict (key) do update set lastrequest = excluded.lastrequest where excluded.lastrequest > users.lastrequest;",
+                &[&key, &bigint(timestamp)?],
+            )
+            .await?;
+
+        Ok(rows > 0)
+    }
+
+    async fn insert_channel(&self, channel: &Signed<Channel>) -> Result<bool, StoreError> {
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .execute(
+                "insert into channels (name, key, server, timestamp, topic, metadata, signature) values ($1, $2, $3, $4, $5, $6, $7) on conflict (name) do nothing;",
+                &[
+                    &channel.data.name,
+                    &channel.key,
+                    &channel.server,
+                    &bigint(channel.timestamp)?,
+                    &channel.data.topic,
+                    &to_jsonb(&channel.data.metadata),
+                    &channel.signature,
+                ],
+            )
+            .await?;
+
+        Ok(rows > 0)
+    }
+
+    async fn insert_group_key(&self, group_key: &Signed<GroupKey>) -> Result<bool, StoreError> {
+        let keys =
+            serde_json::to_value(&group_key.data.keys).map_err(|it| StoreError(it.to_string()))?;
+
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .execute(
+                "insert into group_keys (channel, epoch, key, server, timestamp, keys, metadata, signature) values ($1, $2, $3, $4, $5, $6, $7, $8) on conflict (channel, epoch) do nothing;",
+                &[
+                    &group_key.data.channel,
+                    &bigint(group_key.data.epoch)?,
+                    &group_key.key,
+                    &group_key.server,
+                    &bigint(group_key.timestamp)?,
+                    &keys,
+                    &to_jsonb(&group_key.data.metadata),
+                    &group_key.signature,
+                ],
+            )
+            .await?;
+
+        Ok(rows > 0)
+    }
+
+    async fn get_group_key(
+        &self,
+        channel: &str,
+        epoch: Option<u64>,
+    ) -> Result<Option<Signed<GroupKey>>, StoreError> {
+        let client = self.pool.get().await?;
+
+        let row =
+            match epoch {
+                Some(epoch) => {
+                    client
+                        .query_opt(
+                            "select * from group_keys where channel = $1 and epoch = $2;",
+                            &[&channel, &bound(epoch)],
+                        )
+                        .await?
+                }
+                None => client
+                    .query_opt(
+                        "select * from group_keys where channel = $1 order by epoch desc limit 1;",
+                        &[&channel],
+                    )
+                    .await?,
+            };
+
+        row.as_ref().map(group_key_from_row).transpose()
+    }
+
+    async fn get_channel(&self, name: &str) -> Result<Option<Signed<Channel>>, StoreError> {
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_opt("select * from channels where name = $1;", &[&name])
+            .await?;
+
+        row.as_ref().map(channel_from_row).transpose()
+    }
+
+    async fn list_channels(&self) -> Result<Vec<Signed<Channel>>, StoreError> {
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query("select * from channels order by name;", &[])
+            .await?;
+
+        rows.iter().map(channel_from_row).collect()
+    }
+
+    async fn insert_resource(
+        &self,
+        resource: &Signed<Resource>,
+        size: u64,
+    ) -> Result<(), StoreError> {
+        self.pool
+            .get()
+            .await?
+            .execute(
+                "insert into resources (id, key, server, timestamp, size, metadata, signature) values ($1, $2, $3, $4, $5, $6, $7) on conflict (id) do nothing;",
+                &[
+                    &resource.data.id,
+                    &resource.key,
+                    &resource.server,
+                    &bigint(resource.timestamp)?,
+                    &bigint(size)?,
+                    &to_jsonb(&resource.data.metadata),
+                    &resource.signature,
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_resource(&self, id: &str) -> Result<Option<StoredResource>, StoreError> {
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_opt("select * from resources where id = $1;", &[&id])
+            .await?;
+
+        row.as_ref().map(resource_from_row).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use lay::text::Cursor;
+    use tokio_postgres::Client;
+
+    use super::*;
+    use crate::store::tests::{key, key_pair, post, query, sign};
+
+    /// Url of a database the tests may create schemas in. The tests are
+    /// ignored unless asked for, `scripts/test-postgres.sh` starts a
+    /// throwaway server and runs them.
+    const URL_VAR: &str = "RELAY_TEST_POSTGRES";
+
+    /// An empty schema of its own, so tests do not see each other's rows.
+    struct TempSchema {
+        client: Client,
+        name: String,
+        url: String,
+    }
+
+    impl TempSchema {
+        async fn create() -> Self {
+            let base = std::env::var(URL_VAR)
+                .unwrap_or_else(|_| panic!("{URL_VAR} must point to a PostgreSQL database"));
+
+            let (client, connection) = tokio_postgres::connect(&base, NoTls).await.unwrap();
+            tokio::spawn(connection);
+
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos();
+            let name = format!("relay_test_{nanos}");
+
+            client
+                .batch_execute(&format!("create schema {name};"))
+                .await
+                .unwrap();
+
+            let separator = if base.contains('?') { '&' } else { '?' };
+            let url = format!("{base}{separator}options=-csearch_path%3D{name}");
+
+            Self { client, name, url }
+        }
+
+        async fn store(&self) -> PostgresStore {
+            PostgresStore::connect(&self.url, 2).await.unwrap()
+        }
+
+        async fn drop(self) {
+            self.client
+                .batch_execute(&format!("drop schema {} cascade;", self.name))
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PostgreSQL, run scripts/test-postgres.sh"]
+    async fn conformance() {
+        let schema = TempSchema::create().await;
+
+        crate::store::tests::run(&schema.store().await).await;
+
+        schema.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PostgreSQL, run scripts/test-postgres.sh"]
+    async fn unmigrated_database_is_adopted() {
+        let schema = TempSchema::create().await;
+
+        let entry = post(&key_pair(), 1, "general", "hello");
+        let post = &entry.post;
+
+        schema
+            .client
+            .batch_execute(&format!(
+                "create table {}.posts (key text not null, server text not null, timestamp bigint not null, channel text not null, content text not null, signature text primary key);",
+                schema.name
+            ))
+            .await
+            .unwrap();
+        schema
+            .client
+            .execute(
+                &format!(
+                    "insert into {}.posts (key, server, timestamp, channel, content, signature) values ($1, $2, $3, $4, $5, $6);",
+                    schema.name
+                ),
+                &[
+                    &post.key,
+                    &post.server,
+                    &(post.timestamp as i64),
+                    &post.data.channel,
+                    &post.data.content,
+                    &post.signature,
+                ],
+            )
+            .await
+            .unwrap();
+
+        let store = schema.store().await;
+        assert_eq!(store.migrate().await.unwrap().from, 0);
+
+        let posts = store.query_posts(&query("general", 10)).await.unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].id, entry.id);
+        assert!(posts[0].post.verify());
+
+        schema.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PostgreSQL, run scripts/test-postgres.sh"]
+    async fn corrupt_rows_are_an_error() {
+        let schema = TempSchema::create().await;
+
+        let store = schema.store().await;
+        store.migrate().await.unwrap();
+
+        let key_pair = key_pair();
+        let key = key(&key_pair);
+        let profile = Profile {
+            name: "alice".to_string(),
+            metadata: None,
+        };
+        store
+            .put_profile(&sign(&key_pair, 1, profile))
+            .await
+            .unwrap();
+        let bundle = Bundle {
+            identity_key: key.clone(),
+            pre_keys: Vec::new(),
+            metadata: None,
+        };
+        store.put_bundle(&sign(&key_pair, 1, bundle)).await.unwrap();
+        store
+            .insert_post(&post(&key_pair, 1, "general", "hello"))
+            .await
+            .unwrap();
+
+        schema
+            .client
+            .batch_execute(&format!(
+                "update {0}.profiles set metadata = '[1]'; update {0}.bundles set pre_keys = '{{}}'; alter table {0}.posts alter column timestamp type integer;",
+                schema.name
+            ))
+            .await
+            .unwrap();
+
+        assert!(store.get_profile(&key).await.is_err());
+        assert!(store.get_bundle(&key).await.is_err());
+        assert!(store.query_posts(&query("general", 10)).await.is_err());
+
+        schema.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PostgreSQL, run scripts/test-postgres.sh"]
+    async fn large_integers_are_not_wrapped() {
+        let schema = TempSchema::create().await;
+
+        let store = schema.store().await;
+        store.migrate().await.unwrap();
+
+        let key_pair = key_pair();
+        let entry = post(&key_pair, 1, "general", "hello");
+        store.insert_post(&entry).await.unwrap();
+
+        assert!(store
+            .insert_post(&post(&key_pair, u64::MAX, "general", "later"))
+            .await
+            .is_err());
+
+        let since = PostQuery {
+            since: Some(u64::MAX),
+            ..query("general", 10)
+        };
+        assert!(store.query_posts(&since).await.unwrap().is_empty());
+
+        let until = PostQuery {
+            until: Some(u64::MAX),
+            ..query("general", 10)
+        };
+        assert_eq!(store.query_posts(&until).await.unwrap().len(), 1);
+
+        let before = PostQuery {
+            before: Some(Cursor {
+                timestamp: u64::MAX,
+                signature: String::new(),
+            }),
+            ..query("general", u64::MAX)
+        };
+        assert_eq!(store.query_posts(&before).await.unwrap().len(), 1);
+
+        schema.drop().await;
+    }
+}
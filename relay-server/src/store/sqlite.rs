@@ -0,0 +1,1436 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use lay::{
+    bundle::{Bundle, PreKey},
+    channel::Channel,
+    direct::{DirectEntry, DirectMessage},
+    group::GroupKey,
+    profile::Profile,
+    resource::Resource,
+    rotation::{Revocation, Rotation},
+    text::{Edit, Post, PostEntry, Reaction, Retraction},
+    Signed,
+};
+use rbatis::{executor::RBatisTxExecutor, RBatis};
+use rbs::value;
+use serde::{de::DeserializeOwned, Deserialize};
+
+use super::{
+    migrations::{self, Backfill, Migrated, SCHEMA_VERSION_TABLE, SQLITE},
+    DirectQuery, PostQuery, ReactionCount, Store, StoreError, StoredResource,
+};
+
+/// Default number of pooled connections.
+pub const DEFAULT_POOL_SIZE: usize = 5;
+
+// Text columns are read back as blobs because the sqlite driver otherwise
+// decodes anything that looks like JSON into a map or array.
+
+const POST_COLUMNS: &str = "cast(id as blob) as id, cast(key as blob) as key, cast(server as blob) as server, timestamp, cast(channel as blob) as channel, cast(content as blob) as content, cast(reply_to as blob) as reply_to, cast(thread as blob) as thread, cast(metadata as blob) as metadata, cast(signature as blob) as signature";
+/// Posts as of migration 2, before replies existed.
+const BACKFILL_COLUMNS: &str = "cast(id as blob) as id, cast(key as blob) as key, cast(server as blob) as server, timestamp, cast(channel as blob) as channel, cast(content as blob) as content, null as reply_to, null as thread, cast(metadata as blob) as metadata, cast(signature as blob) as signature";
+const EDIT_COLUMNS: &str = "cast(target as blob) as target, cast(key as blob) as key, cast(server as blob) as server, timestamp, cast(content as blob) as content, cast(metadata as blob) as metadata, cast(signature as blob) as signature";
+const RETRACTION_COLUMNS: &str = "cast(target as blob) as target, cast(key as blob) as key, cast(server as blob) as server, timestamp, cast(metadata as blob) as metadata, cast(signature as blob) as signature";
+const DIRECT_COLUMNS: &str = "cast(id as blob) as id, cast(key as blob) as key, cast(recipient as blob) as recipient, cast(server as blob) as server, timestamp, cast(content as blob) as content, cast(metadata as blob) as metadata, cast(signature as blob) as signature";
+const PROFILE_COLUMNS: &str = "cast(key as blob) as key, cast(server as blob) as server, timestamp, cast(name as blob) as name, cast(metadata as blob) as metadata, cast(signature as blob) as signature";
+const BUNDLE_COLUMNS: &str = "cast(key as blob) as key, cast(server as blob) as server, timestamp, cast(identity_key as blob) as identity_key, cast(pre_keys as blob) as pre_keys, cast(metadata as blob) as metadata, cast(signature as blob) as signature";
+const GROUP_KEY_COLUMNS: &str = "cast(channel as blob) as channel, epoch, cast(key as blob) as key, cast(server as blob) as server, timestamp, cast(keys as blob) as keys, cast(metadata as blob) as metadata, cast(signature as blob) as signature";
+const ROTATION_COLUMNS: &str = "cast(key as blob) as key, cast(server as blob) as server, timestamp, cast(new_key as blob) as new_key, cast(countersignature as blob) as countersignature, cast(metadata as blob) as metadata, cast(signature as blob) as signature";
+const REVOCATION_COLUMNS: &str = "cast(key as blob) as key, cast(server as blob) as server, timestamp, cast(reason as blob) as reason, cast(metadata as blob) as metadata, cast(signature as blob) as signature";
+const CHANNEL_COLUMNS: &str = "cast(name as blob) as name, cast(key as blob) as key, cast(server as blob) as server, timestamp, cast(topic as blob) as topic, cast(metadata as blob) as metadata, cast(signature as blob) as signature";
+const RESOURCE_COLUMNS: &str = "cast(id as blob) as id, cast(key as blob) as key, cast(server as blob) as server, timestamp, size, cast(metadata as blob) as metadata, cast(signature as blob) as signature";
+
+impl From<rbatis::Error> for StoreError {
+    fn from(error: rbatis::Error) -> Self {
+        Self(error.to_string())
+    }
+}
+
+/// Parses a JSON text column, a corrupt value is an error rather than a
+/// default.
+fn from_json<T: DeserializeOwned>(text: &str) -> Result<T, StoreError> {
+    serde_json::from_str(text)
+        .map_err(|it| StoreError(format!("malformed JSON in the database: {it}")))
+}
+
+/// Serializes a metadata map into the JSON text stored in `metadata` columns.
+fn metadata_text<T: serde::Serialize>(metadata: &Option<T>) -> Result<Option<String>, StoreError> {
+    metadata
+        .as_ref()
+        .map(|it| serde_json::to_string(it).map_err(|it| StoreError(it.to_string())))
+        .transpose()
+}
+
+/// A row of the `posts` table, `metadata` is stored as JSON text.
+#[derive(Deserialize)]
+struct PostRow {
+    id: Option<String>,
+    key: String,
+    server: String,
+    timestamp: u64,
+    channel: String,
+    content: String,
+    reply_to: Option<String>,
+    thread: Option<String>,
+    metadata: Option<String>,
+    signature: String,
+}
+
+impl TryFrom<PostRow> for PostEntry {
+    type Error = StoreError;
+
+    fn try_from(row: PostRow) -> Result<Self, StoreError> {
+        let post = Signed {
+            key: row.key,
+            server: row.server,
+            timestamp: row.timestamp,
+            data: Post {
+                channel: row.channel,
+                content: row.content,
+                reply_to: row.reply_to,
+                metadata: row.metadata.as_deref().map(from_json).transpose()?,
+            },
+            signature: row.signature,
+        };
+
+        // only rows that have not been backfilled yet lack an id
+        let id = row
+            .id
+            .or_else(|| post.id())
+            .ok_or_else(|| StoreError("post id cannot be computed".to_string()))?;
+
+        Ok(Self {
+            id,
+            post,
+            thread: row.thread,
+            edits: Vec::new(),
+            retraction: None,
+            reactions: BTreeMap::new(),
+        })
+    }
+}
+
+/// A row of the `edits` table, `metadata` is stored as JSON text.
+#[derive(Deserialize)]
+struct EditRow {
+    target: String,
+    key: String,
+    server: String,
+    timestamp: u64,
+    content: String,
+    metadata: Option<String>,
+    signature: String,
+}
+
+impl TryFrom<EditRow> for Signed<Edit> {
+    type Error = StoreError;
+
+    fn try_from(row: EditRow) -> Result<Self, StoreError> {
+        Ok(Signed {
+            key: row.key,
+            server: row.server,
+            timestamp: row.timestamp,
+            data: Edit {
+                target: row.target,
+                content: row.content,
+                metadata: row.metadata.as_deref().map(from_json).transpose()?,
+            },
+            signature: row.signature,
+        })
+    }
+}
+
+/// A row of the `retractions` table, `metadata` is stored as JSON text.
+#[derive(Deserialize)]
+struct RetractionRow {
+    target: String,
+    key: String,
+    server: String,
+    timestamp: u64,
+    metadata: Option<String>,
+    signature: String,
+}
+
+impl TryFrom<RetractionRow> for Signed<Retraction> {
+    type Error = StoreError;
+
+    fn try_from(row: RetractionRow) -> Result<Self, StoreError> {
+        Ok(Signed {
+            key: row.key,
+            server: row.server,
+            timestamp: row.timestamp,
+            data: Retraction {
+                target: row.target,
+                metadata: row.metadata.as_deref().map(from_json).transpose()?,
+            },
+            signature: row.signature,
+        })
+    }
+}
+
+/// A row of the `direct_messages` table, `metadata` is stored as JSON text.
+#[derive(Deserialize)]
+struct DirectRow {
+    id: String,
+    key: String,
+    recipient: String,
+    server: String,
+    timestamp: u64,
+    content: String,
+    metadata: Option<String>,
+    signature: String,
+}
+
+impl TryFrom<DirectRow> for DirectEntry {
+    type Error = StoreError;
+
+    fn try_from(row: DirectRow) -> Result<Self, StoreError> {
+        Ok(Self {
+            id: row.id,
+            message: Signed {
+                key: row.key,
+                server: row.server,
+                timestamp: row.timestamp,
+                data: DirectMessage {
+                    recipient: row.recipient,
+                    content: row.content,
+                    metadata: row.metadata.as_deref().map(from_json).transpose()?,
+                },
+                signature: row.signature,
+            },
+        })
+    }
+}
+
+/// A group of `reactions` rows with the same target and emoji.
+#[derive(Deserialize)]
+struct ReactionCountRow {
+    target: String,
+    emoji: String,
+    count: u64,
+}
+
+impl From<ReactionCountRow> for ReactionCount {
+    fn from(row: ReactionCountRow) -> Self {
+        Self {
+            target: row.target,
+            emoji: row.emoji,
+            count: row.count,
+        }
+    }
+}
+
+/// A row of the `profiles` table, `metadata` is stored as JSON text.
+#[derive(Deserialize)]
+struct ProfileRow {
+    key: String,
+    server: String,
+    timestamp: u64,
+    name: String,
+    metadata: Option<String>,
+    signature: String,
+}
+
+impl TryFrom<ProfileRow> for Signed<Profile> {
+    type Error = StoreError;
+
+    fn try_from(row: ProfileRow) -> Result<Self, StoreError> {
+        Ok(Signed {
+            key: row.key,
+            server: row.server,
+            timestamp: row.timestamp,
+            data: Profile {
+                name: row.name,
+                metadata: row.metadata.as_deref().map(from_json).transpose()?,
+            },
+            signature: row.signature,
+        })
+    }
+}
+
+/// A row of the `bundles` table, `pre_keys` and `metadata` are stored as
+/// JSON text.
+#[derive(Deserialize)]
+struct BundleRow {
+    key: String,
+    server: String,
+    timestamp: u64,
+    identity_key: String,
+    pre_keys: String,
+    metadata: Option<String>,
+    signature: String,
+}
+
+impl TryFrom<BundleRow> for Signed<Bundle> {
+    type Error = StoreError;
+
+    fn try_from(row: BundleRow) -> Result<Self, StoreError> {
+        Ok(Signed {
+            key: row.key,
+            server: row.server,
+            timestamp: row.timestamp,
+            data: Bundle {
+                identity_key: row.identity_key,
+                pre_keys: from_json::<Vec<PreKey>>(&row.pre_keys)?,
+                metadata: row.metadata.as_deref().map(from_json).transpose()?,
+            },
+            signature: row.signature,
+        })
+    }
+}
+
+/// The lowest unclaimed pre-key id of a key.
+#[derive(Deserialize)]
+struct PreKeyRow {
+    id: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct CountRow {
+    count: u64,
+}
+
+/// A row of the `group_keys` table, `keys` and `metadata` are stored as JSON
+/// text.
+#[derive(Deserialize)]
+struct GroupKeyRow {
+    channel: String,
+    epoch: u64,
+    key: String,
+    server: String,
+    timestamp: u64,
+    keys: String,
+    metadata: Option<String>,
+    signature: String,
+}
+
+impl TryFrom<GroupKeyRow> for Signed<GroupKey> {
+    type Error = StoreError;
+
+    fn try_from(row: GroupKeyRow) -> Result<Self, StoreError> {
+        Ok(Signed {
+            key: row.key,
+            server: row.server,
+            timestamp: row.timestamp,
+            data: GroupKey {
+                channel: row.channel,
+                epoch: row.epoch,
+                keys: from_json(&row.keys)?,
+                metadata: row.metadata.as_deref().map(from_json).transpose()?,
+            },
+            signature: row.signature,
+        })
+    }
+}
+
+/// A row of the `rotations` table, `metadata` is stored as JSON text.
+#[derive(Deserialize)]
+struct RotationRow {
+    key: String,
+    server: String,
+    timestamp: u64,
+    new_key: String,
+    countersignature: String,
+    metadata: Option<String>,
+    signature: String,
+}
+
+impl TryFrom<RotationRow> for Signed<Rotation> {
+    type Error = StoreError;
+
+    fn try_from(row: RotationRow) -> Result<Self, StoreError> {
+        Ok(Signed {
+            key: row.key,
+            server: row.server,
+            timestamp: row.timestamp,
+            data: Rotation {
+                new_key: row.new_key,
+                countersignature: row.countersignature,
+                metadata: row.metadata.as_deref().map(from_json).transpose()?,
+            },
+            signature: row.signature,
+        })
+    }
+}
+
+/// A row of the `revocations` table, `metadata` is stored as JSON text.
+#[derive(Deserialize)]
+struct RevocationRow {
+    key: String,
+    server: String,
+    timestamp: u64,
+    reason: Option<String>,
+    metadata: Option<String>,
+    signature: String,
+}
+
+impl TryFrom<RevocationRow> for Signed<Revocation> {
+    type Error = StoreError;
+
+    fn try_from(row: RevocationRow) -> Result<Self, StoreError> {
+        Ok(Signed {
+            key: row.key,
+            server: row.server,
+            timestamp: row.timestamp,
+            data: Revocation {
+                reason: row.reason,
+                metadata: row.metadata.as_deref().map(from_json).transpose()?,
+            },
+            signature: row.signature,
+        })
+    }
+}
+
+/// A row of the `channels` table, `key` is the creator and `metadata` is
+/// stored as JSON text.
+#[derive(Deserialize)]
+struct ChannelRow {
+    name: String,
+    key: String,
+    server: String,
+    timestamp: u64,
+    topic: Option<String>,
+    metadata: Option<String>,
+    signature: String,
+}
+
+impl TryFrom<ChannelRow> for Signed<Channel> {
+    type Error = StoreError;
+
+    fn try_from(row: ChannelRow) -> Result<Self, StoreError> {
+        Ok(Signed {
+            key: row.key,
+            server: row.server,
+            timestamp: row.timestamp,
+            data: Channel {
+                name: row.name,
+                topic: row.topic,
+                metadata: row.metadata.as_deref().map(from_json).transpose()?,
+            },
+            signature: row.signature,
+        })
+    }
+}
+
+/// A row of the `resources` table, `key` is the first uploader.
+#[derive(Deserialize)]
+struct ResourceRow {
+    id: String,
+    key: String,
+    server: String,
+    timestamp: u64,
+    size: u64,
+    metadata: Option<String>,
+    signature: String,
+}
+
+impl TryFrom<ResourceRow> for StoredResource {
+    type Error = StoreError;
+
+    fn try_from(row: ResourceRow) -> Result<Self, StoreError> {
+        Ok(StoredResource {
+            resource: Signed {
+                key: row.key,
+                server: row.server,
+                timestamp: row.timestamp,
+                data: Resource {
+                    id: row.id,
+                    metadata: row.metadata.as_deref().map(from_json).transpose()?,
+                },
+                signature: row.signature,
+            },
+            size: row.size,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct VersionRow {
+    version: Option<u32>,
+}
+
+/// The default backend, a SQLite database through `rbatis`.
+pub struct SqliteStore {
+    db: RBatis,
+}
+
+impl SqliteStore {
+    /// Opens the database at `url`, see [`Store::migrate`] for the schema.
+    pub async fn connect(url: &str, pool_size: usize) -> Result<Self, StoreError> {
+        let db = RBatis::new();
+        db.init(rbdc_sqlite::driver::SqliteDriver {}, url)?;
+        db.get_pool()?.set_max_open_conns(pool_size as u64).await;
+
+        Ok(Self { db })
+    }
+}
+
+/// Column added by an `alter table .. add column` statement, if it is one.
+fn added_column(statement: &str) -> Option<(&str, &str)> {
+    match statement.split_whitespace().collect::<Vec<_>>()[..] {
+        ["alter", "table", table, "add", "column", column, ..] => Some((table, column)),
+        _ => None,
+    }
+}
+
+/// Runs one migration statement inside `tx`.
+///
+/// SQLite has no `add column if not exists`, so adding a column that exists
+/// already is skipped here, the same as the Postgres statements spell out.
+async fn apply(tx: &RBatisTxExecutor, statement: &str) -> Result<(), rbatis::Error> {
+    if let Some((table, column)) = added_column(statement) {
+        let rows: Vec<CountRow> = tx
+            .exec_decode(
+                "select count(*) as count from pragma_table_info(?1) where name = ?2;",
+                vec![value!(table), value!(column)],
+            )
+            .await?;
+
+        if rows.first().is_some_and(|it| it.count > 0) {
+            return Ok(());
+        }
+    }
+
+    tx.exec(statement, vec![]).await?;
+
+    Ok(())
+}
+
+/// Fills in `posts.id` of rows written before migration 2, replies came later.
+async fn backfill_post_ids(tx: &RBatisTxExecutor) -> Result<(), StoreError> {
+    let rows: Vec<PostRow> = tx
+        .exec_decode(
+            &format!("select {BACKFILL_COLUMNS} from posts where id is null;"),
+            vec![],
+        )
+        .await?;
+
+    for row in rows {
+        let entry = PostEntry::try_from(row)?;
+
+        tx.exec(
+            "update posts set id = ?1 where signature = ?2;",
+            vec![value!(entry.id), value!(entry.post.signature)],
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    async fn migrate(&self) -> Result<Migrated, StoreError> {
+        self.db.exec(SCHEMA_VERSION_TABLE, vec![]).await?;
+
+        let rows: Vec<VersionRow> = self
+            .db
+            .exec_decode(
+                "select max(version) as version from schema_version;",
+                vec![],
+            )
+            .await?;
+        let from = rows.first().and_then(|it| it.version).unwrap_or(0);
+
+        migrations::check_supported(from, SQLITE)?;
+
+        for migration in migrations::pending(SQLITE, from) {
+            let tx = self.db.acquire_begin().await?;
+
+            let result = async {
+                for statement in migration.statements {
+                    apply(&tx, statement).await?;
+                }
+
+                if migration.backfill == Some(Backfill::PostIds) {
+                    backfill_post_ids(&tx).await?;
+                }
+
+                tx.exec(
+                    "insert into schema_version (version, description, applied) values (?1, ?2, ?3);",
+                    vec![
+                        value!(migration.version),
+                        value!(migration.description),
+                        value!(migrations::now()),
+                    ],
+                )
+                .await?;
+
+                Ok::<_, StoreError>(())
+            }
+            .await;
+
+            match result {
+                Ok(()) => tx.commit().await?,
+                Err(error) => {
+                    let _ = tx.rollback().await;
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(Migrated {
+            from,
+            to: migrations::latest(SQLITE).max(from),
+        })
+    }
+
+    async fn insert_post(&self, entry: &PostEntry) -> Result<bool, StoreError> {
+        let post = &entry.post;
+
+        let result = self
+            .db
+            .exec(
+                "insert into posts (id, key, server, timestamp, channel, content, reply_to, thread, metadata, signature) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10) on conflict do nothing;",
+                vec![
+                    value!(entry.id.clone()),
+                    value!(post.key.clone()),
+                    value!(post.server.clone()),
+                    value!(post.timestamp),
+                    value!(post.data.channel.clone()),
+                    value!(post.data.content.clone()),
+                    value!(post.data.reply_to.clone()),
+                    value!(entry.thread.clone()),
+                    value!(metadata_text(&post.data.metadata)?),
+                    value!(post.signature.clone()),
+                ],
+            )
+            .await?;
+
+        Ok(result.rows_affected > 0)
+    }
+
+    async fn get_post(&self, id: &str) -> Result<Option<PostEntry>, StoreError> {
+        let rows: Vec<PostRow> = self
+            .db
+            .exec_decode(
+                &format!("select {POST_COLUMNS} from posts where id = ?1;"),
+                vec![value!(id)],
+            )
+            .await?;
+
+        rows.into_iter().next().map(PostEntry::try_from).transpose()
+    }
+
+    async fn query_posts(&self, query: &PostQuery) -> Result<Vec<PostEntry>, StoreError> {
+        let mut filters = vec!["channel = ?".to_string()];
+        let mut args = vec![value!(query.channel.clone())];
+
+        if let Some(since) = query.since {
+            filters.push("timestamp >= ?".to_string());
+            args.push(value!(since));
+        }
+
+        if let Some(until) = query.until {
+            filters.push("timestamp <= ?".to_string());
+            args.push(value!(until));
+        }
+
+        if let Some(author) = &query.author {
+            filters.push("key = ?".to_string());
+            args.push(value!(author.clone()));
+        }
+
+        if let Some(thread) = &query.thread {
+            filters.push("(id = ? or thread = ?)".to_string());
+            args.push(value!(thread.clone()));
+            args.push(value!(thread.clone()));
+        }
+
+        for (cursor, op) in [(&query.before, "<"), (&query.after, ">")] {
+            let Some(cursor) = cursor else {
+                continue;
+            };
+
+            filters.push(format!(
+                "(timestamp {op} ? or (timestamp = ? and signature {op} ?))"
+            ));
+            args.push(value!(cursor.timestamp));
+            args.push(value!(cursor.timestamp));
+            args.push(value!(cursor.signature.clone()));
+        }
+
+        // Page forwards from `after`, otherwise take the newest page and flip it.
+        let order = if query.after.is_some() { "asc" } else { "desc" };
+        args.push(value!(query.limit));
+
+        let rows: Vec<PostRow> = self
+            .db
+            .exec_decode(
+                &format!(
+                    "select {POST_COLUMNS} from posts where {} order by timestamp {order}, signature {order} limit ?;",
+                    filters.join(" and ")
+                ),
+                args,
+            )
+            .await?;
+
+        let mut posts: Vec<PostEntry> = rows
+            .into_iter()
+            .map(PostEntry::try_from)
+            .collect::<Result<_, _>>()?;
+
+        if query.after.is_none() {
+            posts.reverse();
+        }
+
+        Ok(posts)
+    }
+
+    async fn insert_edit(&self, id: &str, edit: &Signed<Edit>) -> Result<bool, StoreError> {
+        let result = self
+            .db
+            .exec(
+                "insert into edits (id, target, key, server, timestamp, content, metadata, signature) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8) on conflict(id) do nothing;",
+                vec![
+                    value!(id),
+                    value!(edit.data.target.clone()),
+                    value!(edit.key.clone()),
+                    value!(edit.server.clone()),
+                    value!(edit.timestamp),
+                    value!(edit.data.content.clone()),
+                    value!(metadata_text(&edit.data.metadata)?),
+                    value!(edit.signature.clone()),
+                ],
+            )
+            .await?;
+
+        Ok(result.rows_affected > 0)
+    }
+
+    async fn query_edits(&self, targets: &[String]) -> Result<Vec<Signed<Edit>>, StoreError> {
+        if targets.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let placeholders = vec!["?"; targets.len()].join(", ");
+        let args = targets.iter().map(|it| value!(it.clone())).collect();
+
+        let rows: Vec<EditRow> = self
+            .db
+            .exec_decode(
+                &format!(
+                    "select {EDIT_COLUMNS} from edits where target in ({placeholders}) order by timestamp, signature;"
+                ),
+                args,
+            )
+            .await?;
+
+        rows.into_iter().map(Signed::try_from).collect()
+    }
+
+    async fn retract_post(&self, retraction: &Signed<Retraction>) -> Result<bool, StoreError> {
+        let target = &retraction.data.target;
+        let tx = self.db.acquire_begin().await?;
+
+        let result = async {
+            let inserted = tx
+                .exec(
+                    "insert into retractions (target, key, server, timestamp, metadata, signature) values (?1, ?2, ?3, ?4, ?5, ?6) on conflict(target) do nothing;",
+                    vec![
+                        value!(target.clone()),
+                        value!(retraction.key.clone()),
+                        value!(retraction.server.clone()),
+                        value!(retraction.timestamp),
+                        value!(metadata_text(&retraction.data.metadata)?),
+                        value!(retraction.signature.clone()),
+                    ],
+                )
+                .await?
+                .rows_affected
+                > 0;
+
+            if inserted {
+                tx.exec(
+                    "update posts set content = '', metadata = null where id = ?1;",
+                    vec![value!(target.clone())],
+                )
+                .await?;
+                tx.exec(
+                    "delete from edits where target = ?1;",
+                    vec![value!(target.clone())],
+                )
+                .await?;
+                tx.exec(
+                    "delete from reactions where target = ?1;",
+                    vec![value!(target.clone())],
+                )
+                .await?;
+            }
+
+            Ok::<_, StoreError>(inserted)
+        }
+        .await;
+
+        match result {
+            Ok(inserted) => {
+                tx.commit().await?;
+                Ok(inserted)
+            }
+            Err(error) => {
+                let _ = tx.rollback().await;
+                Err(error)
+            }
+        }
+    }
+
+    async fn query_retractions(
+        &self,
+        targets: &[String],
+    ) -> Result<Vec<Signed<Retraction>>, StoreError> {
+        if targets.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let placeholders = vec!["?"; targets.len()].join(", ");
+        let args = targets.iter().map(|it| value!(it.clone())).collect();
+
+        let rows: Vec<RetractionRow> = self
+            .db
+            .exec_decode(
+                &format!(
+                    "select {RETRACTION_COLUMNS} from retractions where target in ({placeholders});"
+                ),
+                args,
+            )
+            .await?;
+
+        rows.into_iter().map(Signed::try_from).collect()
+    }
+
+    async fn query_retracted(
+        &self,
+        channel: &str,
+        since: u64,
+    ) -> Result<Vec<PostEntry>, StoreError> {
+        let rows: Vec<PostRow> = self
+            .db
+            .exec_decode(
+                &format!(
+                    "select {POST_COLUMNS} from posts where channel = ?1 and id in (select target from retractions where timestamp >= ?2) order by timestamp, signature;"
+                ),
+                vec![value!(channel), value!(since)],
+            )
+            .await?;
+
+        rows.into_iter().map(PostEntry::try_from).collect()
+    }
+
+    async fn put_reaction(&self, reaction: &Signed<Reaction>) -> Result<bool, StoreError> {
+        let result = if reaction.data.remove {
+            self.db
+                .exec(
+                    "delete from reactions where target = ?1 and key = ?2 and emoji = ?3;",
+                    vec![
+                        value!(reaction.data.target.clone()),
+                        value!(reaction.key.clone()),
+                        value!(reaction.data.emoji.clone()),
+                    ],
+                )
+                .await?
+        } else {
+            self.db
+                .exec(
+                    "insert into reactions (target, key, emoji, server, timestamp, signature) values (?1, ?2, ?3, ?4, ?5, ?6) on conflict do nothing;",
+                    vec![
+                        value!(reaction.data.target.clone()),
+                        value!(reaction.key.clone()),
+                        value!(reaction.data.emoji.clone()),
+                        value!(reaction.server.clone()),
+                        value!(reaction.timestamp),
+                        value!(reaction.signature.clone()),
+                    ],
+                )
+                .await?
+        };
+
+        Ok(result.rows_affected > 0)
+    }
+
+    async fn count_reactions(&self, targets: &[String]) -> Result<Vec<ReactionCount>, StoreError> {
+        if targets.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let placeholders = vec!["?"; targets.len()].join(", ");
+        let args = targets.iter().map(|it| value!(it.clone())).collect();
+
+        let rows: Vec<ReactionCountRow> = self
+            .db
+            .exec_decode(
+                &format!(
+                    "select cast(target as blob) as target, cast(emoji as blob) as emoji, count(*) as count from reactions where target in ({placeholders}) group by target, emoji;"
+                ),
+                args,
+            )
+            .await?;
+
+        Ok(rows.into_iter().map(ReactionCount::from).collect())
+    }
+
+    async fn insert_direct(&self, entry: &DirectEntry) -> Result<bool, StoreError> {
+        let message = &entry.message;
+
+        let result = self
+            .db
+            .exec(
+                "insert into direct_messages (id, key, recipient, server, timestamp, content, metadata, signature) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8) on conflict do nothing;",
+                vec![
+                    value!(entry.id.clone()),
+                    value!(message.key.clone()),
+                    value!(message.data.recipient.clone()),
+                    value!(message.server.clone()),
+                    value!(message.timestamp),
+                    value!(message.data.content.clone()),
+                    value!(metadata_text(&message.data.metadata)?),
+                    value!(message.signature.clone()),
+                ],
+            )
+            .await?;
+
+        Ok(result.rows_affected > 0)
+    }
+
+    async fn query_direct(&self, query: &DirectQuery) -> Result<Vec<DirectEntry>, StoreError> {
+        let mut filters =
+            vec!["((key = ? and recipient = ?) or (key = ? and recipient = ?))".to_string()];
+        let mut args = vec![
+            value!(query.key.clone()),
+            value!(query.peer.clone()),
+            value!(query.peer.clone()),
+            value!(query.key.clone()),
+        ];
+
+        for (cursor, op) in [(&query.before, "<"), (&query.after, ">")] {
+            let Some(cursor) = cursor else {
+                continue;
+            };
+
+            filters.push(format!(
+                "(timestamp {op} ? or (timestamp = ? and signature {op} ?))"
+            ));
+            args.push(value!(cursor.timestamp));
+            args.push(value!(cursor.timestamp));
+            args.push(value!(cursor.signature.clone()));
+        }
+
+        // Page forwards from `after`, otherwise take the newest page and flip it.
+        let order = if query.after.is_some() { "asc" } else { "desc" };
+        args.push(value!(query.limit));
+
+        let rows: Vec<DirectRow> = self
+            .db
+            .exec_decode(
+                &format!(
+                    "select {DIRECT_COLUMNS} from direct_messages where {} order by timestamp {order}, signature {order} limit ?;",
+                    filters.join(" and ")
+                ),
+                args,
+            )
+            .await?;
+
+        let mut messages: Vec<DirectEntry> = rows
+            .into_iter()
+            .map(DirectEntry::try_from)
+            .collect::<Result<_, _>>()?;
+
+        if query.after.is_none() {
+            messages.reverse();
+        }
+
+        Ok(messages)
+    }
+
+    async fn get_profile(&self, key: &str) -> Result<Option<Signed<Profile>>, StoreError> {
+        let rows: Vec<ProfileRow> = self
+            .db
+            .exec_decode(
+                &format!("select {PROFILE_COLUMNS} from profiles where key=?;"),
+                vec![value!(key)],
+            )
+            .await?;
+
+        rows.into_iter().next().map(Signed::try_from).transpose()
+    }
+
+    async fn put_profile(&self, profile: &Signed<Profile>) -> Result<(), StoreError> {
+        self.db
+            .exec(
+                "insert into profiles (key, server, timestamp, name, metadata, signature) values (?1, ?2, ?3, ?4, ?5, ?6) on conflict(key) do update set server=excluded.server, timestamp=excluded.timestamp, name=excluded.name, metadata=excluded.metadata, signature=excluded.signature;",
+                vec![
+                    value!(profile.key.clone()),
+                    value!(profile.server.clone()),
+                    value!(profile.timestamp),
+                    value!(profile.data.name.clone()),
+                    value!(metadata_text(&profile.data.metadata)?),
+                    value!(profile.signature.clone()),
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_bundle(&self, key: &str) -> Result<Option<Signed<Bundle>>, StoreError> {
+        let rows: Vec<BundleRow> = self
+            .db
+            .exec_decode(
+                &format!("select {BUNDLE_COLUMNS} from bundles where key = ?1;"),
+                vec![value!(key)],
+            )
+            .await?;
+
+        rows.into_iter().next().map(Signed::try_from).transpose()
+    }
+
+    async fn put_bundle(&self, bundle: &Signed<Bundle>) -> Result<(), StoreError> {
+        let pre_keys = serde_json::to_string(&bundle.data.pre_keys)
+            .map_err(|it| StoreError(it.to_string()))?;
+        let tx = self.db.acquire_begin().await?;
+
+        let result = async {
+            tx.exec(
+                "insert into bundles (key, server, timestamp, identity_key, pre_keys, metadata, signature) values (?1, ?2, ?3, ?4, ?5, ?6, ?7) on conflict(key) do update set server=excluded.server, timestamp=excluded.timestamp, identity_key=excluded.identity_key, pre_keys=excluded.pre_keys, metadata=excluded.metadata, signature=excluded.signature;",
+                vec![
+                    value!(bundle.key.clone()),
+                    value!(bundle.server.clone()),
+                    value!(bundle.timestamp),
+                    value!(bundle.data.identity_key.clone()),
+                    value!(pre_keys),
+                    value!(metadata_text(&bundle.data.metadata)?),
+                    value!(bundle.signature.clone()),
+                ],
+            )
+            .await?;
+            tx.exec(
+                "delete from pre_keys where key = ?1;",
+                vec![value!(bundle.key.clone())],
+            )
+            .await?;
+
+            if !bundle.data.pre_keys.is_empty() {
+                let placeholders = vec!["(?, ?)"; bundle.data.pre_keys.len()].join(", ");
+                let args = bundle
+                    .data
+                    .pre_keys
+                    .iter()
+                    .flat_map(|it| [value!(bundle.key.clone()), value!(it.id)])
+                    .collect();
+
+                tx.exec(
+                    &format!("insert into pre_keys (key, id) values {placeholders};"),
+                    args,
+                )
+                .await?;
+            }
+
+            Ok::<_, StoreError>(())
+        }
+        .await;
+
+        match result {
+            Ok(()) => {
+                tx.commit().await?;
+                Ok(())
+            }
+            Err(error) => {
+                let _ = tx.rollback().await;
+                Err(error)
+            }
+        }
+    }
+
+    async fn claim_pre_key(&self, key: &str) -> Result<Option<u32>, StoreError> {
+        // a concurrent claim may take the key in between, then try the next one
+        loop {
+            let rows: Vec<PreKeyRow> = self
+                .db
+                .exec_decode(
+                    "select min(id) as id from pre_keys where key = ?1;",
+                    vec![value!(key)],
+                )
+                .await?;
+
+            let Some(id) = rows.first().and_then(|it| it.id) else {
+                return Ok(None);
+            };
+
+            let result = self
+                .db
+                .exec(
+                    "delete from pre_keys where key = ?1 and id = ?2;",
+                    vec![value!(key), value!(id)],
+                )
+                .await?;
+
+            if result.rows_affected > 0 {
+                return Ok(Some(id));
+            }
+        }
+    }
+
+    async fn count_pre_keys(&self, key: &str) -> Result<u64, StoreError> {
+        let rows: Vec<CountRow> = self
+            .db
+            .exec_decode(
+                "select count(*) as count from pre_keys where key = ?1;",
+                vec![value!(key)],
+            )
+            .await?;
+
+        Ok(rows.first().map_or(0, |it| it.count))
+    }
+
+    async fn insert_rotation(&self, rotation: &Signed<Rotation>) -> Result<bool, StoreError> {
+        let result = self
+            .db
+            .exec(
+                "insert into rotations (key, server, timestamp, new_key, countersignature, metadata, signature) select ?1, ?2, ?3, ?4, ?5, ?6, ?7 where not exists (select 1 from rotations where key = ?4) on conflict(key) do nothing;",
+                vec![
+                    value!(rotation.key.clone()),
+                    value!(rotation.server.clone()),
+                    value!(rotation.timestamp),
+                    value!(rotation.data.new_key.clone()),
+                    value!(rotation.data.countersignature.clone()),
+                    value!(metadata_text(&rotation.data.metadata)?),
+                    value!(rotation.signature.clone()),
+                ],
+            )
+            .await?;
+
+        Ok(result.rows_affected > 0)
+    }
+
+    async fn get_rotation(&self, key: &str) -> Result<Option<Signed<Rotation>>, StoreError> {
+        let rows: Vec<RotationRow> = self
+            .db
+            .exec_decode(
+                &format!("select {ROTATION_COLUMNS} from rotations where key = ?1;"),
+                vec![value!(key)],
+            )
+            .await?;
+
+        rows.into_iter().next().map(Signed::try_from).transpose()
+    }
+
+    async fn insert_revocation(&self, revocation: &Signed<Revocation>) -> Result<bool, StoreError> {
+        let result = self
+            .db
+            .exec(
+                "insert into revocations (key, server, timestamp, reason, metadata, signature) values (?1, ?2, ?3, ?4, ?5, ?6) on conflict(key) do nothing;",
+                vec![
+                    value!(revocation.key.clone()),
+                    value!(revocation.server.clone()),
+                    value!(revocation.timestamp),
+                    value!(revocation.data.reason.clone()),
+                    value!(metadata_text(&revocation.data.metadata)?),
+                    value!(revocation.signature.clone()),
+                ],
+            )
+            .await?;
+
+        Ok(result.rows_affected > 0)
+    }
+
+    async fn get_revocation(&self, key: &str) -> Result<Option<Signed<Revocation>>, StoreError> {
+        let rows: Vec<RevocationRow> = self
+            .db
+            .exec_decode(
+                &format!("select {REVOCATION_COLUMNS} from revocations where key = ?1;"),
+                vec![value!(key)],
+            )
+            .await?;
+
+        rows.into_iter().next().map(Signed::try_from).transpose()
+    }
+
+    async fn advance_last_request(&self, key: &str, timestamp: u64) -> Result<bool, StoreError> {
+        // The conditional upsert only touches the row if the timestamp moves the
+        // high-water mark forward, which makes check-and-set a single statement.
+        let result = self
+            .db
+            .exec(
+                "insert into users (key, lastrequest) values (?1, ?2) on conflict(key) do update set lastrequest=excluded.lastrequest where excluded.lastrequest > users.lastrequest;",
+                vec![value!(key), value!(timestamp)],
+            )
+            .await?;
+
+        Ok(result.rows_affected > 0)
+    }
+
+    async fn insert_channel(&self, channel: &Signed<Channel>) -> Result<bool, StoreError> {
+        // the name is the primary key, a second creation is simply ignored
+        let result = self
+            .db
+            .exec(
+                "insert into channels (name, key, server, timestamp, topic, metadata, signature) values (?1, ?2, ?3, ?4, ?5, ?6, ?7) on conflict(name) do nothing;",
+                vec![
+                    value!(channel.data.name.clone()),
+                    value!(channel.key.clone()),
+                    value!(channel.server.clone()),
+                    value!(channel.timestamp),
+                    value!(channel.data.topic.clone()),
+                    value!(metadata_text(&channel.data.metadata)?),
+                    value!(channel.signature.clone()),
+                ],
+            )
+            .await?;
+
+        Ok(result.rows_affected > 0)
+    }
+
+    async fn insert_group_key(&self, group_key: &Signed<GroupKey>) -> Result<bool, StoreError> {
+        let keys =
+            serde_json::to_string(&group_key.data.keys).map_err(|it| StoreError(it.to_string()))?;
+
+        let result = self
+            .db
+            .exec(
+                "insert into group_keys (channel, epoch, key, server, timestamp, keys, metadata, signature) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8) on conflict(channel, epoch) do nothing;",
+                vec![
+                    value!(group_key.data.channel.clone()),
+                    value!(group_key.data.epoch),
+                    value!(group_key.key.clone()),
+                    value!(group_key.server.clone()),
+                    value!(group_key.timestamp),
+                    value!(keys),
+                    value!(metadata_text(&group_key.data.metadata)?),
+                    value!(group_key.signature.clone()),
+                ],
+            )
+            .await?;
+
+        Ok(result.rows_affected > 0)
+    }
+
+    async fn get_group_key(
+        &self,
+        channel: &str,
+        epoch: Option<u64>,
+    ) -> Result<Option<Signed<GroupKey>>, StoreError> {
+        let rows: Vec<GroupKeyRow> = match epoch {
+            Some(epoch) => {
+                self.db
+                    .exec_decode(
+                        &format!(
+                            "select {GROUP_KEY_COLUMNS} from group_keys where channel = ?1 and epoch = ?2;"
+                        ),
+                        vec![value!(channel), value!(epoch)],
+                    )
+                    .await?
+            }
+            None => {
+                self.db
+                    .exec_decode(
+                        &format!(
+                            "select {GROUP_KEY_COLUMNS} from group_keys where channel = ?1 order by epoch desc limit 1;"
+                        ),
+                        vec![value!(channel)],
+                    )
+                    .await?
+            }
+        };
+
+        rows.into_iter().next().map(Signed::try_from).transpose()
+    }
+
+    async fn get_channel(&self, name: &str) -> Result<Option<Signed<Channel>>, StoreError> {
+        let rows: Vec<ChannelRow> = self
+            .db
+            .exec_decode(
+                &format!("select {CHANNEL_COLUMNS} from channels where name=?;"),
+                vec![value!(name)],
+            )
+            .await?;
+
+        rows.into_iter().next().map(Signed::try_from).transpose()
+    }
+
+    async fn list_channels(&self) -> Result<Vec<Signed<Channel>>, StoreError> {
+        let rows: Vec<ChannelRow> = self
+            .db
+            .exec_decode(
+                &format!("select {CHANNEL_COLUMNS} from channels order by name;"),
+                vec![],
+            )
+            .await?;
+
+        rows.into_iter().map(Signed::try_from).collect()
+    }
+
+    async fn insert_resource(
+        &self,
+        resource: &Signed<Resource>,
+        size: u64,
+    ) -> Result<(), StoreError> {
+        // the first upload of a blob owns its metadata
+        self.db
+            .exec(
+                "insert into resources (id, key, server, timestamp, size, metadata, signature) values (?1, ?2, ?3, ?4, ?5, ?6, ?7) on conflict(id) do nothing;",
+                vec![
+                    value!(resource.data.id.clone()),
+                    value!(resource.key.clone()),
+                    value!(resource.server.clone()),
+                    value!(resource.timestamp),
+                    value!(size),
+                    value!(metadata_text(&resource.data.metadata)?),
+                    value!(resource.signature.clone()),
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_resource(&self, id: &str) -> Result<Option<StoredResource>, StoreError> {
+        let rows: Vec<ResourceRow> = self
+            .db
+            .exec_decode(
+                &format!("select {RESOURCE_COLUMNS} from resources where id=?;"),
+                vec![value!(id)],
+            )
+            .await?;
+
+        rows.into_iter()
+            .next()
+            .map(StoredResource::try_from)
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use super::*;
+    use crate::store::tests::{key, key_pair, post, query, sign};
+
+    /// Schema written by releases from before numbered migrations.
+    const BASELINE: &[&str] = &[
+        "create table if not exists posts (key varchar(48) not null, server varchar(48) not null, timestamp bigint not null, channel text not null, content text, signature varchar(96) primary key);",
+        "create table if not exists users (key varchar(48) primary key, lastrequest bigint not null)",
+        "create table if not exists profiles (key varchar(48) primary key, server varchar(48) not null, timestamp bigint not null, name varchar(255) not null, signature varchar(96) not null)",
+    ];
+
+    /// A database file of its own, removed again on drop.
+    struct TempDatabase {
+        path: PathBuf,
+    }
+
+    impl TempDatabase {
+        fn create() -> Self {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos();
+
+            Self {
+                path: std::env::temp_dir().join(format!("relay_test_{nanos}.db")),
+            }
+        }
+
+        async fn store(&self) -> SqliteStore {
+            SqliteStore::connect(&format!("sqlite://{}", self.path.display()), 2)
+                .await
+                .unwrap()
+        }
+    }
+
+    impl Drop for TempDatabase {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    #[tokio::test]
+    async fn conformance() {
+        let database = TempDatabase::create();
+
+        crate::store::tests::run(&database.store().await).await;
+    }
+
+    #[tokio::test]
+    async fn baseline_database_is_adopted() {
+        let database = TempDatabase::create();
+        let store = database.store().await;
+
+        for statement in BASELINE {
+            store.db.exec(statement, vec![]).await.unwrap();
+        }
+
+        let entry = post(&key_pair(), 1, "general", "hello");
+        let post = &entry.post;
+
+        store
+            .db
+            .exec(
+                "insert into posts (key, server, timestamp, channel, content, signature) values (?1, ?2, ?3, ?4, ?5, ?6);",
+                vec![
+                    value!(post.key.clone()),
+                    value!(post.server.clone()),
+                    value!(post.timestamp),
+                    value!(post.data.channel.clone()),
+                    value!(post.data.content.clone()),
+                    value!(post.signature.clone()),
+                ],
+            )
+            .await
+            .unwrap();
+
+        let migrated = store.migrate().await.unwrap();
+        assert_eq!(migrated.from, 0);
+        assert_eq!(migrated.to, migrations::latest(SQLITE));
+
+        let posts = store.query_posts(&query("general", 10)).await.unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].id, entry.id);
+        assert!(posts[0].post.verify());
+
+        assert_eq!(
+            store.migrate().await.unwrap().from,
+            migrations::latest(SQLITE)
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupt_json_is_an_error() {
+        let database = TempDatabase::create();
+        let store = database.store().await;
+        store.migrate().await.unwrap();
+
+        let key_pair = key_pair();
+        let key = key(&key_pair);
+        let profile = Profile {
+            name: "alice".to_string(),
+            metadata: None,
+        };
+        store
+            .put_profile(&sign(&key_pair, 1, profile))
+            .await
+            .unwrap();
+        let bundle = Bundle {
+            identity_key: key.clone(),
+            pre_keys: Vec::new(),
+            metadata: None,
+        };
+        store.put_bundle(&sign(&key_pair, 1, bundle)).await.unwrap();
+
+        store
+            .db
+            .exec("update profiles set metadata = '{';", vec![])
+            .await
+            .unwrap();
+        store
+            .db
+            .exec("update bundles set pre_keys = '{}';", vec![])
+            .await
+            .unwrap();
+
+        assert!(store.get_profile(&key).await.is_err());
+        assert!(store.get_bundle(&key).await.is_err());
+    }
+}
@@ -0,0 +1,412 @@
+//! Behaviour every [`Store`] backend has to share, run against each of them.
+
+use lay::{
+    bundle::{Bundle, PreKey},
+    channel::Channel,
+    crypto::KeyPair,
+    direct::{DirectEntry, DirectMessage},
+    group::GroupKey,
+    profile::Profile,
+    resource::Resource,
+    rotation::{Revocation, Rotation},
+    text::{Cursor, Edit, Post, PostEntry, Reaction, Retraction},
+    Signed,
+};
+use serde::Serialize;
+
+use super::{DirectQuery, PostQuery, Store};
+
+pub fn key_pair() -> KeyPair {
+    KeyPair::from_pkcs8(&KeyPair::generate_pkcs8().unwrap()).unwrap()
+}
+
+pub fn key(key_pair: &KeyPair) -> String {
+    key_pair.public_key().unwrap().to_base64()
+}
+
+pub fn sign<T: Clone + Serialize>(key_pair: &KeyPair, timestamp: u64, data: T) -> Signed<T> {
+    Signed::new(key_pair, "test".to_string(), timestamp, data).unwrap()
+}
+
+pub fn post(key_pair: &KeyPair, timestamp: u64, channel: &str, content: &str) -> PostEntry {
+    PostEntry::new(sign(
+        key_pair,
+        timestamp,
+        Post {
+            channel: channel.to_string(),
+            content: content.to_string(),
+            reply_to: None,
+            metadata: None,
+        },
+    ))
+    .unwrap()
+}
+
+pub fn query(channel: &str, limit: u64) -> PostQuery {
+    PostQuery {
+        channel: channel.to_string(),
+        since: None,
+        until: None,
+        author: None,
+        before: None,
+        after: None,
+        thread: None,
+        limit,
+    }
+}
+
+fn contents(entries: &[PostEntry]) -> Vec<&str> {
+    entries
+        .iter()
+        .map(|it| it.post.data.content.as_str())
+        .collect()
+}
+
+/// Runs every check against a freshly migrated, empty `store`.
+pub async fn run(store: &dyn Store) {
+    store.migrate().await.unwrap();
+
+    posts(store).await;
+    directs(store).await;
+    keys(store).await;
+    channels(store).await;
+}
+
+async fn posts(store: &dyn Store) {
+    let alice = key_pair();
+    let bob = key_pair();
+
+    let first = post(&alice, 1, "a", "first");
+    let second = post(&bob, 2, "a", "second");
+    let third = post(&alice, 3, "a", "third");
+
+    for entry in [&first, &second, &third, &post(&alice, 4, "b", "other")] {
+        assert!(store.insert_post(entry).await.unwrap());
+    }
+    assert!(!store.insert_post(&first).await.unwrap());
+
+    let stored = store.get_post(&second.id).await.unwrap().unwrap();
+    assert_eq!(stored.post.signature, second.post.signature);
+    assert!(store.get_post("missing").await.unwrap().is_none());
+
+    let all = store.query_posts(&query("a", 10)).await.unwrap();
+    assert_eq!(contents(&all), ["first", "second", "third"]);
+
+    // without `after` the newest ones, with it the oldest ones following it
+    let newest = store.query_posts(&query("a", 2)).await.unwrap();
+    assert_eq!(contents(&newest), ["second", "third"]);
+
+    let after = PostQuery {
+        after: Some(Cursor::of(&first.post)),
+        ..query("a", 1)
+    };
+    assert_eq!(
+        contents(&store.query_posts(&after).await.unwrap()),
+        ["second"]
+    );
+
+    let by_alice = PostQuery {
+        author: Some(key(&alice)),
+        until: Some(2),
+        ..query("a", 10)
+    };
+    assert_eq!(
+        contents(&store.query_posts(&by_alice).await.unwrap()),
+        ["first"]
+    );
+
+    let edit = sign(
+        &alice,
+        5,
+        Edit {
+            target: first.id.clone(),
+            content: "edited".to_string(),
+            metadata: None,
+        },
+    );
+    let edit_id = edit.id().unwrap();
+    assert!(store.insert_edit(&edit_id, &edit).await.unwrap());
+    assert!(!store.insert_edit(&edit_id, &edit).await.unwrap());
+
+    let targets = [first.id.clone()];
+
+    let edits = store.query_edits(&targets).await.unwrap();
+    assert_eq!(edits.len(), 1);
+    assert_eq!(edits[0].data.content, "edited");
+
+    let reaction = |key_pair: &KeyPair, timestamp: u64, remove: bool| {
+        sign(
+            key_pair,
+            timestamp,
+            Reaction {
+                target: first.id.clone(),
+                emoji: "+1".to_string(),
+                remove,
+                metadata: None,
+            },
+        )
+    };
+
+    assert!(store
+        .put_reaction(&reaction(&alice, 6, false))
+        .await
+        .unwrap());
+    assert!(!store
+        .put_reaction(&reaction(&alice, 7, false))
+        .await
+        .unwrap());
+    assert!(store.put_reaction(&reaction(&bob, 8, false)).await.unwrap());
+    assert!(store.put_reaction(&reaction(&bob, 9, true)).await.unwrap());
+    assert!(!store.put_reaction(&reaction(&bob, 10, true)).await.unwrap());
+
+    let counts = store.count_reactions(&targets).await.unwrap();
+    assert_eq!(counts.len(), 1);
+    assert_eq!((counts[0].emoji.as_str(), counts[0].count), ("+1", 1));
+
+    let retraction = sign(
+        &alice,
+        11,
+        Retraction {
+            target: first.id.clone(),
+            metadata: None,
+        },
+    );
+    assert!(store.retract_post(&retraction).await.unwrap());
+    assert!(!store.retract_post(&retraction).await.unwrap());
+
+    // the tombstone keeps its id but loses everything attached to it
+    let tombstone = store.get_post(&first.id).await.unwrap().unwrap();
+    assert!(tombstone.post.data.content.is_empty());
+    assert!(store.query_edits(&targets).await.unwrap().is_empty());
+    assert!(store.count_reactions(&targets).await.unwrap().is_empty());
+    assert_eq!(store.query_retractions(&targets).await.unwrap().len(), 1);
+
+    let retracted = store.query_retracted("a", 11).await.unwrap();
+    assert_eq!(retracted.len(), 1);
+    assert_eq!(retracted[0].id, first.id);
+    assert!(store.query_retracted("a", 12).await.unwrap().is_empty());
+}
+
+async fn directs(store: &dyn Store) {
+    let alice = key_pair();
+    let bob = key_pair();
+    let carol = key_pair();
+
+    let message = |from: &KeyPair, timestamp: u64, to: &KeyPair| {
+        DirectEntry::new(sign(
+            from,
+            timestamp,
+            DirectMessage {
+                recipient: key(to),
+                content: format!("{timestamp}"),
+                metadata: None,
+            },
+        ))
+        .unwrap()
+    };
+
+    let entries = [
+        message(&alice, 1, &bob),
+        message(&bob, 2, &alice),
+        message(&alice, 3, &carol),
+    ];
+
+    for entry in &entries {
+        assert!(store.insert_direct(entry).await.unwrap());
+    }
+    assert!(!store.insert_direct(&entries[0]).await.unwrap());
+
+    let conversation = DirectQuery {
+        key: key(&bob),
+        peer: key(&alice),
+        before: None,
+        after: None,
+        limit: 10,
+    };
+    let stored = store.query_direct(&conversation).await.unwrap();
+    let ids: Vec<&str> = stored.iter().map(|it| it.id.as_str()).collect();
+    assert_eq!(ids, [entries[0].id.as_str(), entries[1].id.as_str()]);
+
+    let after = DirectQuery {
+        after: Some(Cursor::of(&entries[0].message)),
+        ..conversation
+    };
+    assert_eq!(store.query_direct(&after).await.unwrap().len(), 1);
+}
+
+async fn keys(store: &dyn Store) {
+    let alice = key_pair();
+    let bob = key_pair();
+    let alice_key = key(&alice);
+
+    let profile = |timestamp: u64, name: &str| {
+        sign(
+            &alice,
+            timestamp,
+            Profile {
+                name: name.to_string(),
+                metadata: None,
+            },
+        )
+    };
+
+    assert!(store.get_profile(&alice_key).await.unwrap().is_none());
+    store.put_profile(&profile(1, "alice")).await.unwrap();
+    store.put_profile(&profile(2, "alicia")).await.unwrap();
+    let stored = store.get_profile(&alice_key).await.unwrap().unwrap();
+    assert_eq!(stored.data.name, "alicia");
+
+    let bundle = |timestamp: u64, ids: &[u32]| {
+        sign(
+            &alice,
+            timestamp,
+            Bundle {
+                identity_key: "identity".to_string(),
+                pre_keys: ids
+                    .iter()
+                    .map(|&id| PreKey {
+                        id,
+                        key: format!("pre-key {id}"),
+                    })
+                    .collect(),
+                metadata: None,
+            },
+        )
+    };
+
+    store.put_bundle(&bundle(3, &[2, 1])).await.unwrap();
+    assert_eq!(store.count_pre_keys(&alice_key).await.unwrap(), 2);
+    assert_eq!(store.claim_pre_key(&alice_key).await.unwrap(), Some(1));
+    assert_eq!(store.claim_pre_key(&alice_key).await.unwrap(), Some(2));
+    assert_eq!(store.claim_pre_key(&alice_key).await.unwrap(), None);
+
+    // a new bundle replaces the unclaimed pre-keys of the old one
+    store.put_bundle(&bundle(4, &[3, 4])).await.unwrap();
+    assert_eq!(store.count_pre_keys(&alice_key).await.unwrap(), 2);
+    let stored = store.get_bundle(&alice_key).await.unwrap().unwrap();
+    assert_eq!(stored.data.pre_keys.len(), 2);
+
+    let rotation = |from: &KeyPair, to: &KeyPair| {
+        sign(
+            from,
+            5,
+            Rotation {
+                new_key: key(to),
+                countersignature: String::new(),
+                metadata: None,
+            },
+        )
+    };
+
+    assert!(store
+        .insert_rotation(&rotation(&alice, &bob))
+        .await
+        .unwrap());
+    assert!(!store
+        .insert_rotation(&rotation(&alice, &bob))
+        .await
+        .unwrap());
+    let stored = store.get_rotation(&alice_key).await.unwrap().unwrap();
+    assert_eq!(stored.data.new_key, key(&bob));
+    assert!(store.get_rotation(&key(&bob)).await.unwrap().is_none());
+
+    // rotating to a retired key would close a cycle
+    assert!(!store
+        .insert_rotation(&rotation(&bob, &alice))
+        .await
+        .unwrap());
+    assert!(store.get_rotation(&key(&bob)).await.unwrap().is_none());
+
+    // of two rotations racing into a cycle only one is stored
+    let (carol, dave) = (key_pair(), key_pair());
+    let (there, back) = (rotation(&carol, &dave), rotation(&dave, &carol));
+    let (there, back) = tokio::join!(store.insert_rotation(&there), store.insert_rotation(&back));
+    assert!(there.unwrap() ^ back.unwrap());
+
+    let revocation = sign(&bob, 6, Revocation::default());
+    assert!(store.insert_revocation(&revocation).await.unwrap());
+    assert!(!store.insert_revocation(&revocation).await.unwrap());
+    assert!(store.get_revocation(&key(&bob)).await.unwrap().is_some());
+    assert!(store.get_revocation(&alice_key).await.unwrap().is_none());
+
+    assert!(store.advance_last_request(&alice_key, 10).await.unwrap());
+    assert!(!store.advance_last_request(&alice_key, 10).await.unwrap());
+    assert!(!store.advance_last_request(&alice_key, 9).await.unwrap());
+    assert!(store.advance_last_request(&alice_key, 11).await.unwrap());
+}
+
+async fn channels(store: &dyn Store) {
+    let alice = key_pair();
+
+    let channel = |timestamp: u64, name: &str| {
+        sign(
+            &alice,
+            timestamp,
+            Channel {
+                name: name.to_string(),
+                topic: None,
+                metadata: None,
+            },
+        )
+    };
+
+    assert!(store.insert_channel(&channel(1, "b")).await.unwrap());
+    assert!(store.insert_channel(&channel(2, "a")).await.unwrap());
+    assert!(!store.insert_channel(&channel(3, "a")).await.unwrap());
+
+    let stored = store.get_channel("a").await.unwrap().unwrap();
+    assert_eq!(stored.timestamp, 2);
+    assert!(store.get_channel("c").await.unwrap().is_none());
+
+    let names: Vec<String> = store
+        .list_channels()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|it| it.data.name)
+        .collect();
+    assert_eq!(names, ["a", "b"]);
+
+    let group_key = |timestamp: u64, epoch: u64| {
+        sign(
+            &alice,
+            timestamp,
+            GroupKey {
+                channel: "a".to_string(),
+                epoch,
+                keys: [(key(&alice), format!("sealed {epoch}"))].into(),
+                metadata: None,
+            },
+        )
+    };
+
+    assert!(store.insert_group_key(&group_key(4, 0)).await.unwrap());
+    assert!(store.insert_group_key(&group_key(5, 1)).await.unwrap());
+    assert!(!store.insert_group_key(&group_key(6, 1)).await.unwrap());
+
+    let latest = store.get_group_key("a", None).await.unwrap().unwrap();
+    assert_eq!(latest.data.epoch, 1);
+    let first = store.get_group_key("a", Some(0)).await.unwrap().unwrap();
+    assert_eq!(first.timestamp, 4);
+    assert!(store.get_group_key("b", None).await.unwrap().is_none());
+
+    let id = Resource::id_of(b"blob");
+    let resource = |timestamp: u64| {
+        sign(
+            &alice,
+            timestamp,
+            Resource {
+                id: id.clone(),
+                metadata: None,
+            },
+        )
+    };
+
+    store.insert_resource(&resource(7), 4).await.unwrap();
+    store.insert_resource(&resource(8), 4).await.unwrap();
+
+    // the first upload is kept
+    let stored = store.get_resource(&id).await.unwrap().unwrap();
+    assert_eq!((stored.resource.timestamp, stored.size), (7, 4));
+    assert!(store.get_resource("missing").await.unwrap().is_none());
+}
@@ -0,0 +1,154 @@
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::IntoResponse,
+};
+use lay::{
+    text::{PostEntry, Subscribe},
+    Error, ErrorCode, Signed,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::replay::Replay;
+
+/// Updates buffered per subscriber before it is considered too slow.
+const FEED_CAPACITY: usize = 256;
+
+/// A change to the posts of a channel.
+#[derive(Debug, Clone)]
+pub enum Update {
+    /// A newly inserted post.
+    Post(PostEntry),
+    /// An edited post, with all of its edits.
+    Edit(PostEntry),
+    /// The tombstone of a retracted post.
+    Retract(PostEntry),
+    /// A post whose reactions changed.
+    React(PostEntry),
+}
+
+impl Update {
+    pub fn entry(&self) -> &PostEntry {
+        match self {
+            Self::Post(it) | Self::Edit(it) | Self::Retract(it) | Self::React(it) => it,
+        }
+    }
+}
+
+/// Fan-out of updates to every open stream.
+#[derive(Clone)]
+pub struct Feed(broadcast::Sender<Update>);
+
+impl Default for Feed {
+    fn default() -> Self {
+        Self(broadcast::channel(FEED_CAPACITY).0)
+    }
+}
+
+impl Feed {
+    pub fn publish(&self, update: Update) {
+        // no subscribers is not an error
+        let _ = self.0.send(update);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Update> {
+        self.0.subscribe()
+    }
+}
+
+pub async fn stream(
+    ws: WebSocketUpgrade,
+    State(replay): State<Replay>,
+    State(feed): State<Feed>,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_stream(socket, replay, feed))
+}
+
+async fn handle_stream(mut socket: WebSocket, replay: Replay, feed: Feed) {
+    // authenticate with the first message
+    let Some(Ok(Message::Text(handshake))) = socket.recv().await else {
+        return;
+    };
+
+    let mut channels = match authenticate(&replay, &handshake).await {
+        Ok(it) => it.data.channels,
+        Err(error) => {
+            if let Ok(text) = serde_json::to_string(&error) {
+                let _ = socket.send(Message::Text(text)).await;
+            }
+            return;
+        }
+    };
+
+    // subscribe only after authenticating, then acknowledge so the client
+    // knows that anything inserted from now on will be pushed
+    let mut updates = feed.subscribe();
+
+    let ack = Subscribe {
+        channels: channels.clone(),
+        metadata: None,
+    };
+
+    let Ok(ack) = serde_json::to_string(&ack) else {
+        return;
+    };
+
+    if socket.send(Message::Text(ack)).await.is_err() {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    if let Ok(subscribe) = serde_json::from_str::<Subscribe>(&text) {
+                        channels = subscribe.channels;
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            update = updates.recv() => match update {
+                Ok(update) => {
+                    // posts are sent again in full when they change
+                    let post = update.entry();
+
+                    if !channels.contains(&post.post.data.channel) {
+                        continue;
+                    }
+
+                    let text = match serde_json::to_string(&post) {
+                        Ok(it) => it,
+                        Err(error) => {
+                            tracing::error!(%error, id = post.id, "failed to serialize post");
+                            continue;
+                        }
+                    };
+
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                // the client missed posts, make it resync through `/text`
+                Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => break,
+            },
+        }
+    }
+
+    let _ = socket.close().await;
+}
+
+async fn authenticate(replay: &Replay, handshake: &str) -> Result<Signed<Subscribe>, Error> {
+    let req: Signed<Subscribe> = serde_json::from_str(handshake).map_err(|_| {
+        Error::new(
+            ErrorCode::InvalidHandshake,
+            "Expected a signed subscription!",
+        )
+    })?;
+
+    replay.authorize(&req).await?;
+
+    Ok(req)
+}
@@ -0,0 +1,477 @@
+//! Requests against the whole router, backed by a [`MemoryStore`] unless a
+//! test brings its own store.
+
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+
+use axum::{
+    body::{Body, HttpBody},
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use lay::{
+    bundle::Bundle,
+    channel::Channel,
+    crypto::KeyPair,
+    direct::DirectEntry,
+    group::GroupKey,
+    profile::{Profile, ProfileRequest},
+    resource::Resource,
+    rotation::{Revocation, Rotation},
+    text::{Edit, Post, PostEntry, PostRequest, Reaction, Retraction},
+    ErrorCode, Signed,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tempfile::TempDir;
+use tower::ServiceExt;
+
+use crate::{
+    replay::{Replay, DEFAULT_WINDOW},
+    resource::{Blobs, DEFAULT_MAX_SIZE},
+    router,
+    store::{
+        tests::key_pair, DirectQuery, MemoryStore, Migrated, PostQuery, ReactionCount, SharedStore,
+        Store, StoreError, StoredResource,
+    },
+    stream::Feed,
+    text::Moderators,
+    AppState,
+};
+
+pub struct TestServer {
+    pub store: SharedStore,
+    router: Router,
+    _blobs: TempDir,
+}
+
+impl TestServer {
+    pub fn new() -> Self {
+        Self::with_store(Arc::new(MemoryStore::default()))
+    }
+
+    pub fn with_store(store: SharedStore) -> Self {
+        let blobs = tempfile::tempdir().unwrap();
+
+        let router = router(
+            AppState {
+                replay: Replay::new(store.clone(), DEFAULT_WINDOW),
+                feed: Feed::default(),
+                blobs: Blobs::new(blobs.path().to_path_buf(), DEFAULT_MAX_SIZE),
+                moderators: Moderators::default(),
+                store: store.clone(),
+            },
+            DEFAULT_MAX_SIZE,
+        );
+
+        Self {
+            store,
+            router,
+            _blobs: blobs,
+        }
+    }
+
+    /// Sends `body` as JSON, returns the status and the JSON answer.
+    pub async fn call(
+        &self,
+        method: Method,
+        path: &str,
+        body: &impl Serialize,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(body).unwrap()))
+            .unwrap();
+
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+}
+
+/// Signs like a client does, with a timestamp that moves forward on every
+/// request.
+pub struct Client {
+    pub key_pair: KeyPair,
+    last_timestamp: u64,
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Self {
+            key_pair: key_pair(),
+            last_timestamp: 0,
+        }
+    }
+
+    pub fn key(&self) -> String {
+        self.key_pair.public_key().unwrap().to_base64()
+    }
+
+    pub fn sign<T: Clone + Serialize>(&mut self, data: T) -> Signed<T> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        self.last_timestamp = now.max(self.last_timestamp + 1);
+
+        Signed::new(
+            &self.key_pair,
+            "test".to_string(),
+            self.last_timestamp,
+            data,
+        )
+        .unwrap()
+    }
+
+    pub fn channel(&mut self, name: &str) -> Signed<Channel> {
+        self.sign(Channel {
+            name: name.to_string(),
+            topic: None,
+            metadata: None,
+        })
+    }
+
+    pub fn post(&mut self, channel: &str, content: &str) -> Signed<Post> {
+        self.sign(Post {
+            channel: channel.to_string(),
+            content: content.to_string(),
+            reply_to: None,
+            metadata: None,
+        })
+    }
+}
+
+/// A store whose database is gone, every call fails.
+struct FailingStore;
+
+fn unavailable<T>() -> Result<T, StoreError> {
+    Err(StoreError("connection refused".to_string()))
+}
+
+#[async_trait]
+impl Store for FailingStore {
+    async fn migrate(&self) -> Result<Migrated, StoreError> {
+        unavailable()
+    }
+
+    async fn insert_post(&self, _: &PostEntry) -> Result<bool, StoreError> {
+        unavailable()
+    }
+
+    async fn get_post(&self, _: &str) -> Result<Option<PostEntry>, StoreError> {
+        unavailable()
+    }
+
+    async fn query_posts(&self, _: &PostQuery) -> Result<Vec<PostEntry>, StoreError> {
+        unavailable()
+    }
+
+    async fn insert_edit(&self, _: &str, _: &Signed<Edit>) -> Result<bool, StoreError> {
+        unavailable()
+    }
+
+    async fn query_edits(&self, _: &[String]) -> Result<Vec<Signed<Edit>>, StoreError> {
+        unavailable()
+    }
+
+    async fn retract_post(&self, _: &Signed<Retraction>) -> Result<bool, StoreError> {
+        unavailable()
+    }
+
+    async fn query_retractions(&self, _: &[String]) -> Result<Vec<Signed<Retraction>>, StoreError> {
+        unavailable()
+    }
+
+    async fn query_retracted(&self, _: &str, _: u64) -> Result<Vec<PostEntry>, StoreError> {
+        unavailable()
+    }
+
+    async fn put_reaction(&self, _: &Signed<Reaction>) -> Result<bool, StoreError> {
+        unavailable()
+    }
+
+    async fn count_reactions(&self, _: &[String]) -> Result<Vec<ReactionCount>, StoreError> {
+        unavailable()
+    }
+
+    async fn insert_direct(&self, _: &DirectEntry) -> Result<bool, StoreError> {
+        unavailable()
+    }
+
+    async fn query_direct(&self, _: &DirectQuery) -> Result<Vec<DirectEntry>, StoreError> {
+        unavailable()
+    }
+
+    async fn get_profile(&self, _: &str) -> Result<Option<Signed<Profile>>, StoreError> {
+        unavailable()
+    }
+
+    async fn put_profile(&self, _: &Signed<Profile>) -> Result<(), StoreError> {
+        unavailable()
+    }
+
+    async fn get_bundle(&self, _: &str) -> Result<Option<Signed<Bundle>>, StoreError> {
+        unavailable()
+    }
+
+    async fn put_bundle(&self, _: &Signed<Bundle>) -> Result<(), StoreError> {
+        unavailable()
+    }
+
+    async fn claim_pre_key(&self, _: &str) -> Result<Option<u32>, StoreError> {
+        unavailable()
+    }
+
+    async fn count_pre_keys(&self, _: &str) -> Result<u64, StoreError> {
+        unavailable()
+    }
+
+    async fn insert_rotation(&self, _: &Signed<Rotation>) -> Result<bool, StoreError> {
+        unavailable()
+    }
+
+    async fn get_rotation(&self, _: &str) -> Result<Option<Signed<Rotation>>, StoreError> {
+        unavailable()
+    }
+
+    async fn insert_revocation(&self, _: &Signed<Revocation>) -> Result<bool, StoreError> {
+        unavailable()
+    }
+
+    async fn get_revocation(&self, _: &str) -> Result<Option<Signed<Revocation>>, StoreError> {
+        unavailable()
+    }
+
+    async fn advance_last_request(&self, _: &str, _: u64) -> Result<bool, StoreError> {
+        unavailable()
+    }
+
+    async fn insert_group_key(&self, _: &Signed<GroupKey>) -> Result<bool, StoreError> {
+        unavailable()
+    }
+
+    async fn get_group_key(
+        &self,
+        _: &str,
+        _: Option<u64>,
+    ) -> Result<Option<Signed<GroupKey>>, StoreError> {
+        unavailable()
+    }
+
+    async fn insert_channel(&self, _: &Signed<Channel>) -> Result<bool, StoreError> {
+        unavailable()
+    }
+
+    async fn get_channel(&self, _: &str) -> Result<Option<Signed<Channel>>, StoreError> {
+        unavailable()
+    }
+
+    async fn list_channels(&self) -> Result<Vec<Signed<Channel>>, StoreError> {
+        unavailable()
+    }
+
+    async fn insert_resource(&self, _: &Signed<Resource>, _: u64) -> Result<(), StoreError> {
+        unavailable()
+    }
+
+    async fn get_resource(&self, _: &str) -> Result<Option<StoredResource>, StoreError> {
+        unavailable()
+    }
+}
+
+/// The status code `answer` reports.
+pub fn status(answer: &Value) -> ErrorCode {
+    ErrorCode::parse(answer["status"].as_str().unwrap_or_default())
+}
+
+#[tokio::test]
+async fn posts_round_trip() {
+    let server = TestServer::new();
+    let mut alice = Client::new();
+
+    let (code, _) = server
+        .call(Method::POST, "/channel", &alice.channel("general"))
+        .await;
+    assert_eq!(code, StatusCode::OK);
+
+    let post = alice.post("general", "hello");
+    let (code, answer) = server.call(Method::POST, "/text", &post).await;
+    assert_eq!(code, StatusCode::OK);
+
+    let id = PostEntry::new(post).unwrap().id;
+    assert_eq!(answer["id"], id);
+    assert!(server.store.get_post(&id).await.unwrap().is_some());
+
+    let request = alice.sign(PostRequest {
+        channel: "general".to_string(),
+        ..Default::default()
+    });
+    let (code, answer) = server.call(Method::GET, "/text", &request).await;
+    assert_eq!(code, StatusCode::OK);
+
+    let entries: Vec<PostEntry> = serde_json::from_value(answer).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].id, id);
+    assert!(entries[0].post.verify());
+}
+
+#[tokio::test]
+async fn resubmitted_posts_are_stored_once() {
+    let server = TestServer::new();
+    let mut alice = Client::new();
+
+    server
+        .call(Method::POST, "/channel", &alice.channel("general"))
+        .await;
+
+    let post = alice.post("general", "hello");
+    let (_, first) = server.call(Method::POST, "/text", &post).await;
+    let (code, second) = server.call(Method::POST, "/text", &post).await;
+    assert_eq!(code, StatusCode::OK);
+    assert_eq!(first, second);
+
+    let request = alice.sign(PostRequest {
+        channel: "general".to_string(),
+        ..Default::default()
+    });
+    let (_, answer) = server.call(Method::GET, "/text", &request).await;
+    assert_eq!(answer.as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn storage_failures_are_unavailable() {
+    let server = TestServer::with_store(Arc::new(FailingStore));
+    let mut alice = Client::new();
+
+    let profile = alice.sign(Profile {
+        name: "alice".to_string(),
+        metadata: None,
+    });
+    let request = alice.sign(PostRequest {
+        channel: "general".to_string(),
+        ..Default::default()
+    });
+
+    let answers = [
+        (
+            "POST /text",
+            server
+                .call(Method::POST, "/text", &alice.post("general", "hello"))
+                .await,
+        ),
+        (
+            "GET /text",
+            server.call(Method::GET, "/text", &request).await,
+        ),
+        (
+            "POST /profile",
+            server.call(Method::POST, "/profile", &profile).await,
+        ),
+    ];
+
+    for (endpoint, (code, answer)) in answers {
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE, "{endpoint}");
+        assert_eq!(status(&answer), ErrorCode::StorageUnavailable);
+        // the cause stays in the server log
+        assert!(!answer["message"].as_str().unwrap().contains("refused"));
+    }
+}
+
+#[tokio::test]
+async fn posts_need_a_channel() {
+    let server = TestServer::new();
+    let mut alice = Client::new();
+
+    let (code, answer) = server
+        .call(Method::POST, "/text", &alice.post("nowhere", "hello"))
+        .await;
+
+    assert_eq!(code, StatusCode::NOT_FOUND);
+    assert_eq!(status(&answer), ErrorCode::ChannelNotFound);
+}
+
+#[tokio::test]
+async fn nul_is_refused_before_storing() {
+    let server = TestServer::new();
+    let mut alice = Client::new();
+
+    server
+        .call(Method::POST, "/channel", &alice.channel("general"))
+        .await;
+
+    let (code, answer) = server
+        .call(Method::POST, "/text", &alice.post("general", "he\0llo"))
+        .await;
+    assert_eq!(code, StatusCode::BAD_REQUEST);
+    assert_eq!(status(&answer), ErrorCode::InvalidString);
+
+    let request = alice.sign(PostRequest {
+        channel: "general".to_string(),
+        ..Default::default()
+    });
+    let (_, answer) = server.call(Method::GET, "/text", &request).await;
+    assert_eq!(answer, serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn profiles_round_trip() {
+    let server = TestServer::new();
+    let mut alice = Client::new();
+    let mut bob = Client::new();
+
+    let request = bob.sign(ProfileRequest {
+        target_key: alice.key(),
+    });
+    let (code, answer) = server.call(Method::GET, "/profile", &request).await;
+    assert_eq!(code, StatusCode::NOT_FOUND);
+    assert_eq!(status(&answer), ErrorCode::ProfileNotFound);
+
+    let profile = alice.sign(Profile {
+        name: "alice".to_string(),
+        metadata: None,
+    });
+    let (code, _) = server.call(Method::POST, "/profile", &profile).await;
+    assert_eq!(code, StatusCode::OK);
+
+    let request = bob.sign(ProfileRequest {
+        target_key: alice.key(),
+    });
+    let (code, answer) = server.call(Method::GET, "/profile", &request).await;
+    assert_eq!(code, StatusCode::OK);
+
+    let stored: Signed<Profile> = serde_json::from_value(answer).unwrap();
+    assert_eq!(stored.data.name, "alice");
+    assert!(stored.verify());
+}
+
+#[tokio::test]
+async fn replayed_requests_are_refused() {
+    let server = TestServer::new();
+    let mut alice = Client::new();
+
+    let profile = alice.sign(Profile {
+        name: "alice".to_string(),
+        metadata: None,
+    });
+    server.call(Method::POST, "/profile", &profile).await;
+
+    let (code, answer) = server.call(Method::POST, "/profile", &profile).await;
+    assert_eq!(code, StatusCode::UNAUTHORIZED);
+    assert_eq!(status(&answer), ErrorCode::ImpossibleTimestamp);
+}
@@ -1,86 +1,395 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use axum::{extract::State, Json};
 use lay::{
-    text::{Post, PostRequest},
-    Error, Signed,
+    text::{Cursor, Edit, Post, PostEntry, PostRequest, Reaction, Retraction},
+    Error, ErrorCode, Signed,
 };
-use rbatis::RBatis;
-use rbs::to_value;
-use serde_json::json;
+use serde_json::{json, Value};
 
-pub async fn get_text(
-    State(db): State<RBatis>,
-    Json(req): Json<Signed<PostRequest>>,
-) -> impl IntoResponse {
-    if !req.verify() {
-        let error = serde_json::to_value(Error {
-            status: "FAILED_VERIFY_SIGNATURE".to_string(),
-            message: "Signature verification failed!".to_string(),
-            details: None,
-        })
-        .unwrap();
+use crate::{
+    channel::channel_not_found,
+    error::{storage, ApiError},
+    group::check_member,
+    replay::Replay,
+    resource::resource_not_found,
+    store::{PostQuery, SharedStore},
+    stream::{Feed, Update},
+};
+
+/// Number of posts returned when a request does not set `limit`.
+pub const DEFAULT_LIMIT: u64 = 100;
+/// Upper bound for `limit`, larger values are clamped.
+pub const MAX_LIMIT: u64 = 500;
+/// Longest accepted reaction, in bytes.
+const MAX_EMOJI_LEN: usize = 32;
+
+/// Keys allowed to retract posts of others.
+#[derive(Clone, Default)]
+pub struct Moderators(Arc<HashSet<String>>);
 
-        return (StatusCode::BAD_REQUEST, Json(error));
+impl Moderators {
+    /// Parses a comma separated list of base64 public keys.
+    pub fn parse(keys: &str) -> Self {
+        Self(Arc::new(
+            keys.split(',')
+                .map(str::trim)
+                .filter(|it| !it.is_empty())
+                .map(str::to_string)
+                .collect(),
+        ))
     }
 
-    if let Ok(last_req) = db
-        .query_decode::<u64>(
-            "select lastrequest from users where key='?';",
-            vec![to_value!(req.key)],
-        )
+    pub fn contains(&self, key: &str) -> bool {
+        self.0.contains(key)
+    }
+}
+
+/// Parses an optional pagination cursor of a request.
+pub fn cursor(cursor: &Option<String>) -> Result<Option<Cursor>, Error> {
+    cursor
+        .as_deref()
+        .map(|it| {
+            Cursor::parse(it)
+                .ok_or_else(|| Error::new(ErrorCode::InvalidCursor, "Malformed pagination cursor!"))
+        })
+        .transpose()
+}
+
+/// Validates a [`PostRequest`] into a [`PostQuery`].
+pub fn post_query(req: &PostRequest) -> Result<PostQuery, Error> {
+    Ok(PostQuery {
+        channel: req.channel.clone(),
+        since: req.since,
+        until: req.until,
+        author: req.author.clone(),
+        before: cursor(&req.before)?,
+        after: cursor(&req.after)?,
+        thread: req.thread.clone(),
+        limit: req.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT),
+    })
+}
+
+/// Runs a [`PostRequest`] against the store.
+pub async fn query_posts(store: &SharedStore, req: &PostRequest) -> Result<Vec<PostEntry>, Error> {
+    fetch_posts(store, &post_query(req)?).await
+}
+
+/// Runs a [`PostQuery`] against the store.
+pub async fn fetch_posts(store: &SharedStore, query: &PostQuery) -> Result<Vec<PostEntry>, Error> {
+    let mut entries = store
+        .query_posts(query)
         .await
-    {
-        if req.timestamp <= last_req {
-            let error = serde_json::to_value(Error {
-                status: "IMPOSSIBLE_TIMESTAMP".to_string(),
-                message: "Non-unique timestamp for request!".to_string(),
-                details: None,
-            })
-            .unwrap();
-
-            return (StatusCode::BAD_REQUEST, Json(error));
+        .map_err(storage("failed to query posts"))?;
+
+    attach_extras(store, &mut entries).await?;
+
+    Ok(entries)
+}
+
+/// Fills in what is stored apart from the posts: [`PostEntry::edits`],
+/// [`PostEntry::retraction`] and [`PostEntry::reactions`].
+async fn attach_extras(store: &SharedStore, entries: &mut [PostEntry]) -> Result<(), Error> {
+    let targets: Vec<String> = entries.iter().map(|it| it.id.clone()).collect();
+
+    let edits = store
+        .query_edits(&targets)
+        .await
+        .map_err(storage("failed to query edits"))?;
+    let retractions = store
+        .query_retractions(&targets)
+        .await
+        .map_err(storage("failed to query retractions"))?;
+    let reactions = store
+        .count_reactions(&targets)
+        .await
+        .map_err(storage("failed to count reactions"))?;
+
+    let index: HashMap<String, usize> = targets.into_iter().zip(0..).collect();
+
+    for edit in edits {
+        if let Some(&i) = index.get(&edit.data.target) {
+            entries[i].edits.push(edit);
         }
     }
 
-    // TODO: Add proper filters.
-    let messages: Vec<Signed<Post>> = db
-        .query_decode("select * from posts;", vec![])
+    for retraction in retractions {
+        if let Some(&i) = index.get(&retraction.data.target) {
+            entries[i].retraction = Some(retraction);
+        }
+    }
+
+    for reaction in reactions {
+        if let Some(&i) = index.get(&reaction.target) {
+            entries[i].reactions.insert(reaction.emoji, reaction.count);
+        }
+    }
+
+    Ok(())
+}
+
+/// Loads the post `id` with everything attached to it.
+async fn load_post(store: &SharedStore, id: &str) -> Result<PostEntry, Error> {
+    let entry = store
+        .get_post(id)
         .await
-        .unwrap();
+        .map_err(storage("failed to look up post"))?
+        .ok_or_else(post_not_found)?;
+
+    let mut entries = [entry];
+    attach_extras(store, &mut entries).await?;
+    let [entry] = entries;
+
+    Ok(entry)
+}
 
-    (
-        StatusCode::OK,
-        Json(serde_json::to_value(messages).unwrap()),
+/// Error for requests that reference a post which does not exist.
+pub fn post_not_found() -> Error {
+    Error::new(ErrorCode::PostNotFound, "Requested post does not exist!")
+}
+
+/// Error for changes to a post that has been retracted.
+pub fn post_retracted() -> Error {
+    Error::new(
+        ErrorCode::PostRetracted,
+        "Requested post has been retracted!",
     )
 }
 
+pub async fn get_text(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    Json(req): Json<Signed<PostRequest>>,
+) -> Result<Json<Vec<PostEntry>>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let mut entries = query_posts(&store, &req.data).await?;
+
+    if let Some(since) = req.data.retracted_since {
+        let mut retracted = store
+            .query_retracted(&req.data.channel, since)
+            .await
+            .map_err(storage("failed to query retracted posts"))?;
+        attach_extras(&store, &mut retracted).await?;
+
+        let page: HashSet<String> = entries.iter().map(|it| it.id.clone()).collect();
+        entries.extend(retracted.into_iter().filter(|it| !page.contains(&it.id)));
+    }
+
+    Ok(Json(entries))
+}
+
 pub async fn post_text(
-    State(db): State<RBatis>,
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    State(feed): State<Feed>,
     Json(req): Json<Signed<Post>>,
-) -> impl IntoResponse {
-    if !req.verify() {
-        let error = serde_json::to_value(Error {
-            status: "FAILED_VERIFY_SIGNATURE".to_string(),
-            message: "Signature verification failed!".to_string(),
-            details: None,
-        })
-        .unwrap();
+) -> Result<Json<Value>, ApiError> {
+    let Some(mut entry) = PostEntry::new(req) else {
+        return Err(ApiError::new(
+            ErrorCode::FailedVerifySignature,
+            "Signature verification failed!",
+        ));
+    };
+
+    // The id covers the signature, so a stored post with the same id is this
+    // exact post. Resubmissions succeed before the replay check, which would
+    // reject their timestamp.
+    let existing = store
+        .get_post(&entry.id)
+        .await
+        .map_err(storage("failed to look up post"))?;
 
-        return (StatusCode::BAD_REQUEST, Json(error));
+    if existing.is_some() {
+        return Ok(Json(json!({ "id": entry.id })));
     }
 
-    db.exec(
-        "insert into posts (key, server, timestamp, channel, content, signature) values (?1, ?2, ?3, ?4, ?5, ?6);",
-        vec![
-            to_value!(req.key),
-            to_value!(req.server),
-            to_value!(req.timestamp),
-            to_value!(req.data.channel),
-            to_value!(req.data.content),
-            to_value!(req.signature),
-        ],
-    )
-    .await
-    .unwrap();
+    let req = &entry.post;
+
+    replay.authorize(req).await?;
+
+    let channel = store
+        .get_channel(&req.data.channel)
+        .await
+        .map_err(storage("failed to look up channel"))?
+        .ok_or_else(channel_not_found)?;
+
+    if channel.data.is_encrypted() {
+        check_member(&store, &channel.data.name, &req.key, &req.data.metadata).await?;
+    }
+
+    if let Some(parent) = &req.data.reply_to {
+        let parent = store
+            .get_post(parent)
+            .await
+            .map_err(storage("failed to look up post"))?
+            .filter(|it| it.post.data.channel == req.data.channel)
+            .ok_or_else(|| {
+                ApiError::new(
+                    ErrorCode::PostNotFound,
+                    "Replied to post does not exist in this channel!",
+                )
+            })?;
+
+        // replies to a reply join the thread of their parent
+        entry.thread = Some(parent.thread.unwrap_or(parent.id));
+    }
+
+    for id in req.data.resources() {
+        if store
+            .get_resource(id)
+            .await
+            .map_err(storage("failed to look up resource"))?
+            .is_none()
+        {
+            return Err(resource_not_found().into());
+        }
+    }
+
+    let inserted = store
+        .insert_post(&entry)
+        .await
+        .map_err(storage("failed to insert post"))?;
+
+    // a concurrent submission of the same post won the race
+    if inserted {
+        feed.publish(Update::Post(entry.clone()));
+    }
+
+    Ok(Json(json!({ "id": entry.id })))
+}
+
+/// Adds a revision to a post of the same key.
+///
+/// The post is published again with all of its edits, so open streams can
+/// replace what they show.
+pub async fn post_edit(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    State(feed): State<Feed>,
+    Json(req): Json<Signed<Edit>>,
+) -> Result<Json<Value>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let Some(id) = req.id() else {
+        return Err(ApiError::new(
+            ErrorCode::FailedVerifySignature,
+            "Signature verification failed!",
+        ));
+    };
+
+    let target = load_post(&store, &req.data.target).await?;
+
+    if target.is_retracted() {
+        return Err(post_retracted().into());
+    }
+
+    if target.post.key != req.key {
+        return Err(ApiError::new(
+            ErrorCode::NotAuthor,
+            "Only the author of a post can edit it!",
+        ));
+    }
+
+    let channel = &target.post.data.channel;
+
+    // edits of encrypted posts are sealed like new posts
+    if store
+        .get_channel(channel)
+        .await
+        .map_err(storage("failed to look up channel"))?
+        .is_some_and(|it| it.data.is_encrypted())
+    {
+        check_member(&store, channel, &req.key, &req.data.metadata).await?;
+    }
+
+    store
+        .insert_edit(&id, &req)
+        .await
+        .map_err(storage("failed to insert edit"))?;
+
+    feed.publish(Update::Edit(load_post(&store, &target.id).await?));
+
+    Ok(Json(json!({ "id": id })))
+}
+
+/// Retracts a post, signed by its author or one of the [`Moderators`].
+///
+/// The tombstone is published, so open streams can drop the post.
+pub async fn post_retraction(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    State(feed): State<Feed>,
+    State(moderators): State<Moderators>,
+    Json(req): Json<Signed<Retraction>>,
+) -> Result<Json<Value>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let target = load_post(&store, &req.data.target).await?;
+
+    if target.is_retracted() {
+        return Err(post_retracted().into());
+    }
+
+    if target.post.key != req.key && !moderators.contains(&req.key) {
+        return Err(ApiError::new(
+            ErrorCode::NotAuthor,
+            "Only the author of a post or a moderator can retract it!",
+        ));
+    }
+
+    let retracted = store
+        .retract_post(&req)
+        .await
+        .map_err(storage("failed to retract post"))?;
+
+    // a concurrent retraction won the race
+    if !retracted {
+        return Err(post_retracted().into());
+    }
+
+    feed.publish(Update::Retract(load_post(&store, &target.id).await?));
+
+    Ok(Json(json!({})))
+}
+
+/// Adds or takes back a reaction to a post.
+///
+/// The post is published again with the new counts.
+pub async fn post_reaction(
+    State(store): State<SharedStore>,
+    State(replay): State<Replay>,
+    State(feed): State<Feed>,
+    Json(req): Json<Signed<Reaction>>,
+) -> Result<Json<Value>, ApiError> {
+    replay.authorize(&req).await?;
+
+    let emoji = &req.data.emoji;
+
+    if emoji.is_empty() || emoji.len() > MAX_EMOJI_LEN || emoji.chars().any(char::is_whitespace) {
+        return Err(ApiError::new(
+            ErrorCode::InvalidReaction,
+            format!("Reactions must be 1 to {MAX_EMOJI_LEN} bytes without whitespace!"),
+        ));
+    }
+
+    let target = load_post(&store, &req.data.target).await?;
+
+    if target.is_retracted() {
+        return Err(post_retracted().into());
+    }
+
+    let changed = store
+        .put_reaction(&req)
+        .await
+        .map_err(storage("failed to store reaction"))?;
+
+    if changed {
+        feed.publish(Update::React(load_post(&store, &target.id).await?));
+    }
 
-    (StatusCode::OK, Json(json!({})))
+    Ok(Json(json!({})))
 }
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::Signed;
+
+/// The keys others encrypt to, signed by the identity key that owns them.
+///
+/// `identity_key` is the long-term X25519 key of
+/// [`crate::crypto::EncryptionKeyPair::derive`], each of `pre_keys` is handed
+/// out at most once by the server. Uploading a bundle replaces the previous
+/// one together with all of its unclaimed pre-keys, so ids should not be
+/// reused.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bundle {
+    #[serde(rename = "identityKey")]
+    pub identity_key: String,
+    #[serde(rename = "preKeys")]
+    pub pre_keys: Vec<PreKey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+impl Bundle {
+    pub fn pre_key(&self, id: u32) -> Option<&PreKey> {
+        self.pre_keys.iter().find(|it| it.id == id)
+    }
+}
+
+/// A one-time X25519 key from [`crate::crypto::EncryptionKeyPair::generate`],
+/// its secret only exists on the device that published it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreKey {
+    pub id: u32,
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BundleRequest {
+    #[serde(rename = "targetKey")]
+    pub target_key: String,
+    /// Whether to claim one of the pre-keys, which is then never handed out
+    /// again.
+    #[serde(
+        rename = "claimPreKey",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub claim_pre_key: bool,
+}
+
+/// A stored bundle as returned by the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleEntry {
+    #[serde(flatten)]
+    pub bundle: Signed<Bundle>,
+    /// Id of the pre-key claimed for this request, if one was asked for and
+    /// any were left.
+    #[serde(rename = "preKey", skip_serializing_if = "Option::is_none")]
+    pub pre_key: Option<u32>,
+    /// Number of pre-keys that have not been claimed yet.
+    pub remaining: u64,
+}
+
+impl BundleEntry {
+    /// Whether the bundle is signed by `identity` and the claimed pre-key is
+    /// part of it. Nothing in an unverified bundle may be encrypted to.
+    pub fn verify(&self, identity: &str) -> bool {
+        self.bundle.key == identity
+            && self.bundle.verify()
+            && self
+                .pre_key
+                .is_none_or(|it| self.bundle.data.pre_key(it).is_some())
+    }
+
+    /// The claimed pre-key.
+    pub fn claimed(&self) -> Option<&PreKey> {
+        self.bundle.data.pre_key(self.pre_key?)
+    }
+}
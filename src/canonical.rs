@@ -0,0 +1,242 @@
+//! Canonical JSON encoding (RFC 8785, JSON Canonicalization Scheme).
+//!
+//! Signatures are computed over this encoding instead of whatever
+//! `serde_json::to_string` happens to emit, so that any implementation
+//! following the RFC produces the exact same bytes for the same value:
+//!
+//! - object members are sorted by the UTF-16 code units of their keys,
+//! - no insignificant whitespace is emitted,
+//! - strings only escape `"`, `\` and control characters,
+//! - numbers are written the way ECMAScript's `Number.prototype.toString` does,
+//!   except for integers, which are written exactly.
+//!
+//! Test vectors live in `vectors/canonical.json` and `vectors/signed.json`.
+
+use std::cmp::Ordering;
+
+use serde::Serialize;
+use serde_json::{Map, Number, Value};
+
+/// Serializes `value` into its canonical JSON string.
+pub fn to_string<T: Serialize>(value: &T) -> Result<String, serde_json::Error> {
+    let value = serde_json::to_value(value)?;
+
+    let mut out = String::new();
+    write_value(&mut out, &value);
+
+    Ok(out)
+}
+
+/// Serializes `value` into its canonical JSON bytes.
+pub fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, serde_json::Error> {
+    to_string(value).map(String::into_bytes)
+}
+
+fn write_value(out: &mut String, value: &Value) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(true) => out.push_str("true"),
+        Value::Bool(false) => out.push_str("false"),
+        Value::Number(number) => write_number(out, number),
+        Value::String(string) => write_string(out, string),
+        Value::Array(array) => {
+            out.push('[');
+
+            for (i, element) in array.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+
+                write_value(out, element);
+            }
+
+            out.push(']');
+        }
+        Value::Object(object) => write_object(out, object),
+    }
+}
+
+fn write_object(out: &mut String, object: &Map<String, Value>) {
+    let mut members: Vec<(&String, &Value)> = object.iter().collect();
+    members.sort_by(|a, b| compare_keys(a.0, b.0));
+
+    out.push('{');
+
+    for (i, (key, value)) in members.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+
+        write_string(out, key);
+        out.push(':');
+        write_value(out, value);
+    }
+
+    out.push('}');
+}
+
+/// Keys are ordered by their UTF-16 code units, not by their UTF-8 bytes.
+fn compare_keys(a: &str, b: &str) -> Ordering {
+    a.encode_utf16().cmp(b.encode_utf16())
+}
+
+fn write_string(out: &mut String, string: &str) {
+    out.push('"');
+
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{08}' => out.push_str("\\b"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\u{0C}' => out.push_str("\\f"),
+            '\r' => out.push_str("\\r"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+}
+
+/// Numbers are IEEE 754 doubles in JCS, which would map distinct integers
+/// above 2^53 (timestamps, epochs) to the same bytes and let a signature be
+/// moved to another value. Integers are therefore written with all their
+/// digits, which agrees with JCS wherever a double can hold them.
+fn write_number(out: &mut String, number: &Number) {
+    if let Some(integer) = number.as_u64() {
+        out.push_str(&integer.to_string());
+        return;
+    }
+
+    if let Some(integer) = number.as_i64() {
+        out.push_str(&integer.to_string());
+        return;
+    }
+
+    let value = number.as_f64().unwrap_or_default();
+
+    if value == 0.0 {
+        out.push('0');
+        return;
+    }
+
+    if value < 0.0 {
+        out.push('-');
+    }
+
+    // `{:e}` yields the shortest round-trip digits, e.g. `1.2345e-7`.
+    let scientific = format!("{:e}", value.abs());
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+
+    // value = 0.digits * 10^n
+    let k = digits.len() as i32;
+    let n = exponent + 1;
+
+    if k <= n && n <= 21 {
+        out.push_str(&digits);
+        out.extend(std::iter::repeat_n('0', (n - k) as usize));
+    } else if 0 < n && n <= 21 {
+        out.push_str(&digits[..n as usize]);
+        out.push('.');
+        out.push_str(&digits[n as usize..]);
+    } else if -6 < n && n <= 0 {
+        out.push_str("0.");
+        out.extend(std::iter::repeat_n('0', -n as usize));
+        out.push_str(&digits);
+    } else {
+        out.push_str(&digits[..1]);
+
+        if k > 1 {
+            out.push('.');
+            out.push_str(&digits[1..]);
+        }
+
+        out.push('e');
+        out.push(if n - 1 < 0 { '-' } else { '+' });
+        out.push_str(&(n - 1).abs().to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use serde_json::{json, Value};
+
+    use crate::{crypto::KeyPair, Signed};
+
+    const CANONICAL: &str = include_str!("../vectors/canonical.json");
+    const SIGNED: &str = include_str!("../vectors/signed.json");
+
+    #[test]
+    fn canonical_vectors() {
+        let vectors: Vec<Value> = serde_json::from_str(CANONICAL).unwrap();
+
+        for vector in vectors {
+            let input: Value = serde_json::from_str(vector["input"].as_str().unwrap()).unwrap();
+
+            assert_eq!(
+                super::to_string(&input).unwrap(),
+                vector["canonical"].as_str().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn large_integers_are_exact() {
+        for (integer, canonical) in [
+            (json!(9007199254740993u64), "9007199254740993"),
+            (json!(u64::MAX), "18446744073709551615"),
+            (json!(i64::MIN), "-9223372036854775808"),
+        ] {
+            assert_eq!(super::to_string(&integer).unwrap(), canonical);
+        }
+
+        let signing_input = |timestamp: u64| {
+            super::to_string(&json!({ "key": "k", "timestamp": timestamp })).unwrap()
+        };
+
+        assert_ne!(signing_input(1 << 53), signing_input((1 << 53) + 1));
+    }
+
+    #[test]
+    fn signed_vectors() {
+        let file: Value = serde_json::from_str(SIGNED).unwrap();
+        let pkcs8 = BASE64_STANDARD
+            .decode(file["pkcs8"].as_str().unwrap())
+            .unwrap();
+        let key_pair = KeyPair::from_pkcs8(&pkcs8).unwrap();
+
+        assert_eq!(
+            key_pair.public_key().unwrap().to_base64(),
+            file["publicKey"].as_str().unwrap()
+        );
+
+        for vector in file["vectors"].as_array().unwrap() {
+            let signed: Signed<Value> = serde_json::from_value(vector["signed"].clone()).unwrap();
+
+            let mut unsigned = signed.clone();
+            unsigned.signature = String::new();
+
+            assert_eq!(
+                super::to_string(&unsigned).unwrap(),
+                vector["signingInput"].as_str().unwrap()
+            );
+            assert!(signed.verify());
+            assert_eq!(signed.id().unwrap(), vector["id"].as_str().unwrap());
+
+            // Ed25519 is deterministic, re-signing gives the same signature
+            let resigned = Signed::new(
+                &key_pair,
+                signed.server.clone(),
+                signed.timestamp,
+                signed.data.clone(),
+            )
+            .unwrap();
+            assert_eq!(resigned.signature, signed.signature);
+        }
+    }
+}
@@ -1,8 +1,37 @@
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
+/// A channel, signed by the key that created it.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Channel {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+/// Metadata key marking a channel as end-to-end encrypted, see
+/// [`crate::group`].
+pub const ENCRYPTED_KEY: &str = "encrypted";
+
+impl Channel {
+    /// Whether [`ENCRYPTED_KEY`] is set, which only its creator can do.
+    pub fn is_encrypted(&self) -> bool {
+        self.metadata
+            .as_ref()
+            .and_then(|it| it.get(ENCRYPTED_KEY))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+}
+
+/// Looks up a single channel by `name` on `/channel`, `name` is ignored when
+/// listing all channels on `/channels`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ChannelRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<Map<String, Value>>,
 }
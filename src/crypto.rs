@@ -1,13 +1,26 @@
+use std::num::NonZeroU32;
+
 use base64::{prelude::BASE64_STANDARD, Engine};
 use ring::{
-    rand::SystemRandom,
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    digest::{digest, SHA256},
+    hkdf::{self, HKDF_SHA256},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
     signature::{Ed25519KeyPair, UnparsedPublicKey, ED25519},
 };
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
 
 /// Ed25519 Constants
 pub const ED25519_SIGNATURE_LEN: usize = 64;
 pub const ED25519_PUBLIC_KEY_LEN: usize = 32;
 
+/// X25519 Constants
+pub const X25519_KEY_LEN: usize = 32;
+
+/// Length of the secret behind a [`SharedKey`].
+pub const SHARED_SECRET_LEN: usize = 32;
+
 /// Ed25519 Signature
 pub struct Signature([u8; ED25519_SIGNATURE_LEN]);
 
@@ -25,16 +38,14 @@ impl AsRef<[u8]> for Signature {
 
 impl Signature {
     pub fn to_base64(&self) -> String {
-        BASE64_STANDARD.encode(&self.0)
+        BASE64_STANDARD.encode(self.0)
     }
 
+    /// `None` unless `base64` holds exactly one signature, so it is safe to
+    /// call on untrusted input.
     pub fn from_base64(base64: &str) -> Option<Self> {
-        let mut bytes = [0; ED25519_SIGNATURE_LEN];
-
-        match BASE64_STANDARD.decode_slice_unchecked(base64, &mut bytes) {
-            Err(_) => return None,
-            _ => {}
-        }
+        let bytes: [u8; ED25519_SIGNATURE_LEN] =
+            BASE64_STANDARD.decode(base64).ok()?.try_into().ok()?;
 
         Some(bytes.into())
     }
@@ -57,16 +68,14 @@ impl AsRef<[u8]> for PublicKey {
 
 impl PublicKey {
     pub fn to_base64(&self) -> String {
-        BASE64_STANDARD.encode(&self.0)
+        BASE64_STANDARD.encode(self.0)
     }
 
+    /// `None` unless `base64` holds exactly one key, so it is safe to call
+    /// on untrusted input.
     pub fn from_base64(base64: &str) -> Option<Self> {
-        let mut bytes = [0; ED25519_PUBLIC_KEY_LEN];
-
-        match BASE64_STANDARD.decode_slice_unchecked(base64, &mut bytes) {
-            Err(_) => return None,
-            _ => {}
-        }
+        let bytes: [u8; ED25519_PUBLIC_KEY_LEN] =
+            BASE64_STANDARD.decode(base64).ok()?.try_into().ok()?;
 
         Some(bytes.into())
     }
@@ -78,8 +87,8 @@ impl PublicKey {
     }
 }
 
-/// Ed25519 Key Pair
-pub struct KeyPair(Ed25519KeyPair);
+/// Ed25519 Key Pair, keeps its PKCS#8 document so it can be stored again.
+pub struct KeyPair(Ed25519KeyPair, Vec<u8>);
 
 impl KeyPair {
     pub fn generate_pkcs8() -> Option<Vec<u8>> {
@@ -99,7 +108,11 @@ impl KeyPair {
             Err(_) => return None,
         };
 
-        Some(Self(key_pair))
+        Some(Self(key_pair, document.into()))
+    }
+
+    pub fn to_pkcs8(&self) -> &[u8] {
+        &self.1
     }
 
     pub fn sign(&self, data: &[u8]) -> Option<Signature> {
@@ -122,3 +135,477 @@ impl KeyPair {
         Some(public_key.into())
     }
 }
+
+/// X25519 Key Pair for encryption.
+///
+/// The identity key is derived from the PKCS#8 document of a signing
+/// [`KeyPair`], so it needs no storage of its own and is the same wherever
+/// the identity is loaded. One-time pre-keys are random instead.
+///
+/// Built on x25519-dalek because ring's agreement API only has ephemeral
+/// keys, which are consumed by a single agreement and cannot be held as a
+/// long-term secret. The derivation is unchanged by the switch, existing
+/// key files keep their encryption key (see `vectors/signed.json`).
+pub struct EncryptionKeyPair(StaticSecret);
+
+impl EncryptionKeyPair {
+    /// The long-term key published as [`crate::bundle::Bundle::identity_key`].
+    pub fn derive(key_pair: &KeyPair) -> Option<Self> {
+        let secret = hkdf_bytes(b"lay encryption key", key_pair.to_pkcs8(), b"")?;
+
+        Some(Self(secret.into()))
+    }
+
+    /// A random key, for the one-time [`crate::bundle::PreKey`]s. Unlike the
+    /// derived identity key it has to be stored, see [`Self::to_base64`].
+    pub fn generate() -> Option<Self> {
+        let mut secret = [0; X25519_KEY_LEN];
+        SystemRandom::new().fill(&mut secret).ok()?;
+
+        Some(Self(secret.into()))
+    }
+
+    /// The secret key, to be kept as safe as the signing key.
+    pub fn to_base64(&self) -> String {
+        BASE64_STANDARD.encode(self.0.as_bytes())
+    }
+
+    pub fn from_base64(base64: &str) -> Option<Self> {
+        let secret: [u8; X25519_KEY_LEN] = BASE64_STANDARD.decode(base64).ok()?.try_into().ok()?;
+
+        Some(Self(secret.into()))
+    }
+
+    pub fn public_key(&self) -> Option<String> {
+        let public_key = X25519PublicKey::from(&self.0);
+
+        Some(BASE64_STANDARD.encode(public_key.as_bytes()))
+    }
+
+    /// Diffie-Hellman with the base64 X25519 `public_key`.
+    fn agree(&self, public_key: &str) -> Option<[u8; X25519_KEY_LEN]> {
+        let peer: [u8; X25519_KEY_LEN] =
+            BASE64_STANDARD.decode(public_key).ok()?.try_into().ok()?;
+
+        // a low order peer key would force a secret anyone can compute
+        let secret = self.0.diffie_hellman(&X25519PublicKey::from(peer));
+        if !secret.was_contributory() {
+            return None;
+        }
+
+        Some(secret.to_bytes())
+    }
+
+    /// Key shared with the owner of the base64 X25519 `public_key`. Both ends
+    /// derive the same key, so either can read what the other sealed.
+    pub fn shared_key(&self, public_key: &str) -> Option<SharedKey> {
+        let secret = self.agree(public_key)?;
+        let info = key_info(&[&self.public_key()?, public_key]);
+
+        SharedKey::from_secret(&hkdf_bytes(b"lay shared key", &secret, info.as_bytes())?)
+    }
+
+    /// Key shared with the owner of the identity key `identity`, also sealed
+    /// to their one-time `pre_key`, so it cannot be derived from the two
+    /// identity keys alone.
+    pub fn shared_key_to_pre_key(&self, identity: &str, pre_key: &str) -> Option<SharedKey> {
+        let secret = [self.agree(identity)?, self.agree(pre_key)?].concat();
+        let info = key_info(&[&self.public_key()?, identity]) + pre_key;
+
+        SharedKey::from_secret(&hkdf_bytes(b"lay pre key", &secret, info.as_bytes())?)
+    }
+
+    /// The other end of [`Self::shared_key_to_pre_key`], for the owner of
+    /// `pre_key` and the identity key `identity` that sealed to it.
+    pub fn shared_key_from_pre_key(
+        &self,
+        identity: &str,
+        pre_key: &EncryptionKeyPair,
+    ) -> Option<SharedKey> {
+        let secret = [self.agree(identity)?, pre_key.agree(identity)?].concat();
+        let info = key_info(&[&self.public_key()?, identity]) + &pre_key.public_key()?;
+
+        SharedKey::from_secret(&hkdf_bytes(b"lay pre key", &secret, info.as_bytes())?)
+    }
+}
+
+/// Binds a key to both public keys, in an order both ends agree on.
+fn key_info(public_keys: &[&str; 2]) -> String {
+    let mut public_keys = *public_keys;
+    public_keys.sort_unstable();
+
+    public_keys.concat()
+}
+
+/// Symmetric key of two [`EncryptionKeyPair`]s, or of a group that was
+/// handed the same secret.
+///
+/// Sealed messages are the base64 of a random ChaCha20-Poly1305 nonce
+/// followed by the ciphertext and its tag.
+pub struct SharedKey(LessSafeKey);
+
+impl SharedKey {
+    /// Key only the owner of `key_pair` can derive, for sealing secrets kept
+    /// next to it.
+    pub fn derive_local(key_pair: &KeyPair) -> Option<Self> {
+        Self::from_secret(&hkdf_bytes(b"lay local key", key_pair.to_pkcs8(), b"")?)
+    }
+
+    /// `None` unless `secret` is [`SHARED_SECRET_LEN`] bytes long.
+    pub fn from_secret(secret: &[u8]) -> Option<Self> {
+        let key = UnboundKey::new(&CHACHA20_POLY1305, secret).ok()?;
+
+        Some(Self(LessSafeKey::new(key)))
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Option<String> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).ok()?;
+
+        let mut in_out = plaintext.to_vec();
+        self.0
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut in_out,
+            )
+            .ok()?;
+
+        Some(BASE64_STANDARD.encode([&nonce[..], &in_out].concat()))
+    }
+
+    /// `None` if `sealed` is malformed or was not sealed with this key.
+    pub fn open(&self, sealed: &str) -> Option<Vec<u8>> {
+        let sealed = BASE64_STANDARD.decode(sealed).ok()?;
+
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+
+        let mut in_out = ciphertext.to_vec();
+        let plaintext = self
+            .0
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .ok()?;
+
+        Some(plaintext.to_vec())
+    }
+}
+
+/// A new secret for [`SharedKey::from_secret`].
+pub fn random_secret() -> Option<[u8; SHARED_SECRET_LEN]> {
+    let mut secret = [0; SHARED_SECRET_LEN];
+    SystemRandom::new().fill(&mut secret).ok()?;
+
+    Some(secret)
+}
+
+/// Whether `base64` holds exactly one X25519 public key.
+pub fn is_x25519_public_key(base64: &str) -> bool {
+    BASE64_STANDARD
+        .decode(base64)
+        .is_ok_and(|it| it.len() == X25519_KEY_LEN)
+}
+
+/// 32 bytes of HKDF-SHA256 output.
+fn hkdf_bytes(salt: &[u8], secret: &[u8], info: &[u8]) -> Option<[u8; 32]> {
+    let mut out = [0; 32];
+
+    hkdf::Salt::new(HKDF_SHA256, salt)
+        .extract(secret)
+        .expand(&[info], HKDF_SHA256)
+        .ok()?
+        .fill(&mut out)
+        .ok()?;
+
+    Some(out)
+}
+
+/// Lowercase hex SHA-256 of `data`, the form of every content address.
+pub fn sha256_hex(data: &[u8]) -> String {
+    digest(&SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|it| format!("{it:02x}"))
+        .collect()
+}
+
+/// Key file constants
+pub const KEY_FILE_MAGIC: &[u8; 6] = b"LAYKEY";
+pub const KEY_FILE_VERSION: u8 = 1;
+pub const KEY_FILE_SALT_LEN: usize = 16;
+pub const DEFAULT_KDF_ITERATIONS: u32 = 600_000;
+/// Most iterations a key file may ask for, so a crafted file cannot keep us
+/// busy deriving for hours.
+pub const MAX_KDF_ITERATIONS: u32 = 10_000_000;
+
+const KEY_FILE_HEADER_LEN: usize = KEY_FILE_MAGIC.len() + 1 + 4 + KEY_FILE_SALT_LEN + NONCE_LEN;
+
+/// Failure to open a passphrase-encrypted key file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFileError {
+    /// Not a key file, or truncated.
+    Malformed,
+    /// Written by a newer version of the format.
+    UnsupportedVersion(u8),
+    /// The passphrase does not match, or the file was tampered with.
+    WrongPassphrase,
+    /// Decrypted fine, but does not hold a valid Ed25519 key.
+    InvalidKey,
+}
+
+impl std::fmt::Display for KeyFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "not a valid key file"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported key file version {version}")
+            }
+            Self::WrongPassphrase => write!(f, "wrong passphrase"),
+            Self::InvalidKey => write!(f, "key file does not contain a valid key"),
+        }
+    }
+}
+
+impl std::error::Error for KeyFileError {}
+
+/// Passphrase-encrypted key files.
+///
+/// Layout, integers big-endian:
+///
+/// | field      | size | notes                                   |
+/// |------------|------|-----------------------------------------|
+/// | magic      | 6    | `LAYKEY`                                |
+/// | version    | 1    | [`KEY_FILE_VERSION`]                    |
+/// | iterations | 4    | PBKDF2-HMAC-SHA256 work factor          |
+/// | salt       | 16   | PBKDF2 salt                             |
+/// | nonce      | 12   | ChaCha20-Poly1305 nonce                 |
+/// | ciphertext | rest | PKCS#8 document followed by the tag     |
+///
+/// The header is authenticated as associated data, so the parameters cannot
+/// be changed without failing decryption. Files asking for more than
+/// [`MAX_KDF_ITERATIONS`] are rejected as malformed before deriving anything.
+impl KeyPair {
+    pub fn is_sealed(data: &[u8]) -> bool {
+        data.starts_with(KEY_FILE_MAGIC)
+    }
+
+    pub fn seal(&self, passphrase: &str) -> Option<Vec<u8>> {
+        self.seal_with_iterations(passphrase, DEFAULT_KDF_ITERATIONS)
+    }
+
+    pub fn seal_with_iterations(&self, passphrase: &str, iterations: u32) -> Option<Vec<u8>> {
+        if iterations > MAX_KDF_ITERATIONS {
+            return None;
+        }
+
+        let rng = SystemRandom::new();
+
+        let mut salt = [0; KEY_FILE_SALT_LEN];
+        let mut nonce = [0; NONCE_LEN];
+        rng.fill(&mut salt).ok()?;
+        rng.fill(&mut nonce).ok()?;
+
+        let mut sealed = Vec::with_capacity(KEY_FILE_HEADER_LEN + self.1.len() + 16);
+        sealed.extend_from_slice(KEY_FILE_MAGIC);
+        sealed.push(KEY_FILE_VERSION);
+        sealed.extend_from_slice(&iterations.to_be_bytes());
+        sealed.extend_from_slice(&salt);
+        sealed.extend_from_slice(&nonce);
+
+        let key = key_file_key(passphrase, NonZeroU32::new(iterations)?, &salt)?;
+
+        let mut in_out = self.1.clone();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(&sealed[..]),
+            &mut in_out,
+        )
+        .ok()?;
+
+        sealed.extend_from_slice(&in_out);
+
+        Some(sealed)
+    }
+
+    pub fn unseal(sealed: &[u8], passphrase: &str) -> Result<Self, KeyFileError> {
+        if sealed.len() < KEY_FILE_HEADER_LEN || !Self::is_sealed(sealed) {
+            return Err(KeyFileError::Malformed);
+        }
+
+        let (header, ciphertext) = sealed.split_at(KEY_FILE_HEADER_LEN);
+        let (version, rest) = header[KEY_FILE_MAGIC.len()..].split_at(1);
+
+        if version[0] != KEY_FILE_VERSION {
+            return Err(KeyFileError::UnsupportedVersion(version[0]));
+        }
+
+        let (iterations, rest) = rest.split_at(4);
+        let (salt, nonce) = rest.split_at(KEY_FILE_SALT_LEN);
+
+        let iterations = u32::from_be_bytes(iterations.try_into().unwrap());
+        let iterations = NonZeroU32::new(iterations)
+            .filter(|it| it.get() <= MAX_KDF_ITERATIONS)
+            .ok_or(KeyFileError::Malformed)?;
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| KeyFileError::Malformed)?;
+
+        let key = key_file_key(passphrase, iterations, salt).ok_or(KeyFileError::Malformed)?;
+
+        let mut in_out = ciphertext.to_vec();
+        let document = key
+            .open_in_place(nonce, Aad::from(header), &mut in_out)
+            .map_err(|_| KeyFileError::WrongPassphrase)?;
+
+        Self::from_pkcs8(document).ok_or(KeyFileError::InvalidKey)
+    }
+}
+
+fn key_file_key(passphrase: &str, iterations: NonZeroU32, salt: &[u8]) -> Option<LessSafeKey> {
+    let mut key = [0; 32];
+
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key).ok()?;
+
+    Some(LessSafeKey::new(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_pair() -> KeyPair {
+        KeyPair::from_pkcs8(&KeyPair::generate_pkcs8().unwrap()).unwrap()
+    }
+
+    /// The key of `vectors/signed.json`.
+    fn vector_key_pair() -> (KeyPair, serde_json::Value) {
+        let vectors: serde_json::Value =
+            serde_json::from_str(include_str!("../vectors/signed.json")).unwrap();
+        let pkcs8 = BASE64_STANDARD
+            .decode(vectors["pkcs8"].as_str().unwrap())
+            .unwrap();
+
+        (KeyPair::from_pkcs8(&pkcs8).unwrap(), vectors)
+    }
+
+    #[test]
+    fn derived_keys_are_stable() {
+        let (key_pair, vectors) = vector_key_pair();
+
+        // an identity must keep its encryption key across versions, or
+        // everything sealed to it becomes unreadable
+        let derived = EncryptionKeyPair::derive(&key_pair).unwrap();
+        assert_eq!(
+            derived.public_key().as_deref(),
+            vectors["encryptionKey"].as_str()
+        );
+
+        // sealed with the local key of this identity, so must keep opening
+        let sealed = "xl8jXtGa+UV3998r7BYo62/buy73/Wf5FhEqYYUIt/B2";
+        let opened = SharedKey::derive_local(&key_pair).unwrap().open(sealed);
+        assert_eq!(opened.as_deref(), Some(&b"relay"[..]));
+    }
+
+    #[test]
+    fn signature_length_is_checked() {
+        let signature = key_pair().sign(b"message").unwrap();
+        let encoded = signature.to_base64();
+        assert!(Signature::from_base64(&encoded).is_some());
+
+        let long = BASE64_STANDARD.encode([0; ED25519_SIGNATURE_LEN + 3]);
+        assert!(Signature::from_base64(&long).is_none());
+
+        let short = BASE64_STANDARD.encode([0; ED25519_SIGNATURE_LEN - 1]);
+        assert!(Signature::from_base64(&short).is_none());
+
+        assert!(Signature::from_base64("not base64!").is_none());
+        assert!(Signature::from_base64(&encoded.replace('=', "")).is_none());
+    }
+
+    #[test]
+    fn shared_keys_agree() {
+        let alice = EncryptionKeyPair::derive(&key_pair()).unwrap();
+        let bob = EncryptionKeyPair::derive(&key_pair()).unwrap();
+
+        let sealed = alice
+            .shared_key(&bob.public_key().unwrap())
+            .unwrap()
+            .seal(b"hello")
+            .unwrap();
+        let opened = bob
+            .shared_key(&alice.public_key().unwrap())
+            .unwrap()
+            .open(&sealed);
+        assert_eq!(opened.as_deref(), Some(&b"hello"[..]));
+
+        let low_order = BASE64_STANDARD.encode([0; X25519_KEY_LEN]);
+        assert!(alice.shared_key(&low_order).is_none());
+        assert!(alice.shared_key("AAAA").is_none());
+    }
+
+    #[test]
+    fn pre_key_shared_keys_agree() {
+        let alice = EncryptionKeyPair::derive(&key_pair()).unwrap();
+        let bob = EncryptionKeyPair::derive(&key_pair()).unwrap();
+        let pre_key = EncryptionKeyPair::generate().unwrap();
+
+        let stored = EncryptionKeyPair::from_base64(&pre_key.to_base64()).unwrap();
+        assert_eq!(stored.public_key(), pre_key.public_key());
+
+        let sealed = alice
+            .shared_key_to_pre_key(&bob.public_key().unwrap(), &pre_key.public_key().unwrap())
+            .unwrap()
+            .seal(b"hello")
+            .unwrap();
+        let opened = bob
+            .shared_key_from_pre_key(&alice.public_key().unwrap(), &stored)
+            .unwrap()
+            .open(&sealed);
+        assert_eq!(opened.as_deref(), Some(&b"hello"[..]));
+
+        // without the pre-key the identity keys are not enough
+        let identity_only = bob.shared_key(&alice.public_key().unwrap()).unwrap();
+        assert!(identity_only.open(&sealed).is_none());
+    }
+
+    #[test]
+    fn key_file_round_trip() {
+        let key_pair = key_pair();
+        let sealed = key_pair.seal_with_iterations("secret", 1).unwrap();
+
+        let opened = KeyPair::unseal(&sealed, "secret").unwrap();
+        assert_eq!(opened.to_pkcs8(), key_pair.to_pkcs8());
+
+        assert_eq!(
+            KeyPair::unseal(&sealed, "wrong").err(),
+            Some(KeyFileError::WrongPassphrase)
+        );
+    }
+
+    #[test]
+    fn key_file_iterations_are_capped() {
+        let key_pair = key_pair();
+        assert!(key_pair
+            .seal_with_iterations("secret", MAX_KDF_ITERATIONS + 1)
+            .is_none());
+
+        let mut sealed = key_pair.seal_with_iterations("secret", 1).unwrap();
+        let offset = KEY_FILE_MAGIC.len() + 1;
+        sealed[offset..offset + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        assert_eq!(
+            KeyPair::unseal(&sealed, "secret").err(),
+            Some(KeyFileError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::Signed;
+
+/// A message only its sender and `recipient` can read.
+///
+/// `content` is sealed with the [`crate::crypto::SharedKey`] of the sender
+/// and the [`crate::bundle::Bundle::identity_key`] of `recipient`, the server
+/// only stores it and hands it to the two of them. If [`PRE_KEY_KEY`] names
+/// a one-time pre-key of either of them, the key is also sealed to that, see
+/// [`crate::crypto::EncryptionKeyPair::shared_key_to_pre_key`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectMessage {
+    pub recipient: String,
+    pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+impl DirectMessage {
+    /// The [`PRE_KEY_KEY`] of the message.
+    pub fn pre_key(&self) -> Option<PreKeyRef> {
+        let value = self.metadata.as_ref()?.get(PRE_KEY_KEY)?;
+
+        serde_json::from_value(value.clone()).ok()
+    }
+}
+
+/// Metadata key of direct messages, the pre-key their content is sealed to.
+///
+/// A conversation is sealed to one pre-key of the end that did not start it,
+/// claimed by the other end with its first message.
+pub const PRE_KEY_KEY: &str = "preKey";
+
+/// One of the [`crate::bundle::Bundle::pre_keys`] of `owner`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreKeyRef {
+    pub owner: String,
+    pub id: u32,
+}
+
+/// A stored direct message together with its [`Signed::id`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectEntry {
+    pub id: String,
+    #[serde(flatten)]
+    pub message: Signed<DirectMessage>,
+}
+
+impl DirectEntry {
+    pub fn new(message: Signed<DirectMessage>) -> Option<Self> {
+        Some(Self {
+            id: message.id()?,
+            message,
+        })
+    }
+}
+
+/// Query for the direct messages between the signing key and `peer`, in
+/// both directions.
+///
+/// Paged like [`crate::text::PostRequest`]: ordered oldest to newest, the
+/// newest `limit` without `after`, the oldest ones following it otherwise.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DirectRequest {
+    pub peer: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+    /// Only messages strictly older than this [`crate::text::Cursor`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+    /// Only messages strictly newer than this [`crate::text::Cursor`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
@@ -0,0 +1,263 @@
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Machine readable reason of an [`Error`].
+///
+/// Serialized as the stable `SCREAMING_SNAKE_CASE` strings of the protocol,
+/// codes this version does not know yet are kept as [`ErrorCode::Unknown`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    FailedVerifySignature,
+    ImpossibleTimestamp,
+    MissingRequest,
+    InvalidHandshake,
+    InvalidCursor,
+    InvalidString,
+    ProfileNotFound,
+    ChannelNotFound,
+    ChannelExists,
+    InvalidChannelName,
+    ResourceNotFound,
+    ResourceTooLarge,
+    ResourceHashMismatch,
+    PostNotFound,
+    PostRetracted,
+    NotAuthor,
+    InvalidReaction,
+    InvalidRecipient,
+    BundleNotFound,
+    InvalidBundle,
+    NotMember,
+    InvalidGroupKey,
+    GroupKeyNotFound,
+    RotationNotFound,
+    RevocationNotFound,
+    InvalidRotation,
+    KeyRotated,
+    KeyRevoked,
+    StorageUnavailable,
+    Unknown(String),
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::FailedVerifySignature => "FAILED_VERIFY_SIGNATURE",
+            Self::ImpossibleTimestamp => "IMPOSSIBLE_TIMESTAMP",
+            Self::MissingRequest => "MISSING_REQUEST",
+            Self::InvalidHandshake => "INVALID_HANDSHAKE",
+            Self::InvalidCursor => "INVALID_CURSOR",
+            Self::InvalidString => "INVALID_STRING",
+            Self::ProfileNotFound => "PROFILE_NOT_FOUND",
+            Self::ChannelNotFound => "CHANNEL_NOT_FOUND",
+            Self::ChannelExists => "CHANNEL_EXISTS",
+            Self::InvalidChannelName => "INVALID_CHANNEL_NAME",
+            Self::ResourceNotFound => "RESOURCE_NOT_FOUND",
+            Self::ResourceTooLarge => "RESOURCE_TOO_LARGE",
+            Self::ResourceHashMismatch => "RESOURCE_HASH_MISMATCH",
+            Self::PostNotFound => "POST_NOT_FOUND",
+            Self::PostRetracted => "POST_RETRACTED",
+            Self::NotAuthor => "NOT_AUTHOR",
+            Self::InvalidReaction => "INVALID_REACTION",
+            Self::InvalidRecipient => "INVALID_RECIPIENT",
+            Self::BundleNotFound => "BUNDLE_NOT_FOUND",
+            Self::InvalidBundle => "INVALID_BUNDLE",
+            Self::NotMember => "NOT_MEMBER",
+            Self::InvalidGroupKey => "INVALID_GROUP_KEY",
+            Self::GroupKeyNotFound => "GROUP_KEY_NOT_FOUND",
+            Self::RotationNotFound => "ROTATION_NOT_FOUND",
+            Self::RevocationNotFound => "REVOCATION_NOT_FOUND",
+            Self::InvalidRotation => "INVALID_ROTATION",
+            Self::KeyRotated => "KEY_ROTATED",
+            Self::KeyRevoked => "KEY_REVOKED",
+            Self::StorageUnavailable => "STORAGE_UNAVAILABLE",
+            Self::Unknown(code) => code,
+        }
+    }
+
+    pub fn parse(code: &str) -> Self {
+        match code {
+            "FAILED_VERIFY_SIGNATURE" => Self::FailedVerifySignature,
+            "IMPOSSIBLE_TIMESTAMP" => Self::ImpossibleTimestamp,
+            "MISSING_REQUEST" => Self::MissingRequest,
+            "INVALID_HANDSHAKE" => Self::InvalidHandshake,
+            "INVALID_CURSOR" => Self::InvalidCursor,
+            "INVALID_STRING" => Self::InvalidString,
+            "PROFILE_NOT_FOUND" => Self::ProfileNotFound,
+            "CHANNEL_NOT_FOUND" => Self::ChannelNotFound,
+            "CHANNEL_EXISTS" => Self::ChannelExists,
+            "INVALID_CHANNEL_NAME" => Self::InvalidChannelName,
+            "RESOURCE_NOT_FOUND" => Self::ResourceNotFound,
+            "RESOURCE_TOO_LARGE" => Self::ResourceTooLarge,
+            "RESOURCE_HASH_MISMATCH" => Self::ResourceHashMismatch,
+            "POST_NOT_FOUND" => Self::PostNotFound,
+            "POST_RETRACTED" => Self::PostRetracted,
+            "NOT_AUTHOR" => Self::NotAuthor,
+            "INVALID_REACTION" => Self::InvalidReaction,
+            "INVALID_RECIPIENT" => Self::InvalidRecipient,
+            "BUNDLE_NOT_FOUND" => Self::BundleNotFound,
+            "INVALID_BUNDLE" => Self::InvalidBundle,
+            "NOT_MEMBER" => Self::NotMember,
+            "INVALID_GROUP_KEY" => Self::InvalidGroupKey,
+            "GROUP_KEY_NOT_FOUND" => Self::GroupKeyNotFound,
+            "ROTATION_NOT_FOUND" => Self::RotationNotFound,
+            "REVOCATION_NOT_FOUND" => Self::RevocationNotFound,
+            "INVALID_ROTATION" => Self::InvalidRotation,
+            "KEY_ROTATED" => Self::KeyRotated,
+            "KEY_REVOKED" => Self::KeyRevoked,
+            "STORAGE_UNAVAILABLE" => Self::StorageUnavailable,
+            code => Self::Unknown(code.to_string()),
+        }
+    }
+
+    /// HTTP status code a server answers with.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            Self::FailedVerifySignature | Self::ImpossibleTimestamp => 401,
+            Self::NotAuthor | Self::NotMember => 403,
+            Self::ProfileNotFound
+            | Self::ChannelNotFound
+            | Self::ResourceNotFound
+            | Self::PostNotFound
+            | Self::BundleNotFound
+            | Self::GroupKeyNotFound
+            | Self::RotationNotFound
+            | Self::RevocationNotFound => 404,
+            Self::ChannelExists | Self::KeyRotated | Self::KeyRevoked => 409,
+            Self::PostRetracted => 410,
+            Self::ResourceTooLarge => 413,
+            Self::MissingRequest
+            | Self::InvalidHandshake
+            | Self::InvalidCursor
+            | Self::InvalidString
+            | Self::InvalidChannelName
+            | Self::ResourceHashMismatch
+            | Self::InvalidReaction
+            | Self::InvalidRecipient
+            | Self::InvalidBundle
+            | Self::InvalidGroupKey
+            | Self::InvalidRotation => 400,
+            Self::StorageUnavailable => 503,
+            Self::Unknown(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self::parse(&String::deserialize(deserializer)?))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Error {
+    pub status: ErrorCode,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl Error {
+    pub fn new(status: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            details: None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: '{}'", self.status, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::ErrorCode;
+
+    /// Every known code with its wire string and HTTP status, these are part
+    /// of the protocol and must not change.
+    const CODES: &[(ErrorCode, &str, u16)] = &[
+        (
+            ErrorCode::FailedVerifySignature,
+            "FAILED_VERIFY_SIGNATURE",
+            401,
+        ),
+        (ErrorCode::ImpossibleTimestamp, "IMPOSSIBLE_TIMESTAMP", 401),
+        (ErrorCode::MissingRequest, "MISSING_REQUEST", 400),
+        (ErrorCode::InvalidHandshake, "INVALID_HANDSHAKE", 400),
+        (ErrorCode::InvalidCursor, "INVALID_CURSOR", 400),
+        (ErrorCode::InvalidString, "INVALID_STRING", 400),
+        (ErrorCode::ProfileNotFound, "PROFILE_NOT_FOUND", 404),
+        (ErrorCode::ChannelNotFound, "CHANNEL_NOT_FOUND", 404),
+        (ErrorCode::ChannelExists, "CHANNEL_EXISTS", 409),
+        (ErrorCode::InvalidChannelName, "INVALID_CHANNEL_NAME", 400),
+        (ErrorCode::ResourceNotFound, "RESOURCE_NOT_FOUND", 404),
+        (ErrorCode::ResourceTooLarge, "RESOURCE_TOO_LARGE", 413),
+        (
+            ErrorCode::ResourceHashMismatch,
+            "RESOURCE_HASH_MISMATCH",
+            400,
+        ),
+        (ErrorCode::PostNotFound, "POST_NOT_FOUND", 404),
+        (ErrorCode::PostRetracted, "POST_RETRACTED", 410),
+        (ErrorCode::NotAuthor, "NOT_AUTHOR", 403),
+        (ErrorCode::InvalidReaction, "INVALID_REACTION", 400),
+        (ErrorCode::InvalidRecipient, "INVALID_RECIPIENT", 400),
+        (ErrorCode::BundleNotFound, "BUNDLE_NOT_FOUND", 404),
+        (ErrorCode::InvalidBundle, "INVALID_BUNDLE", 400),
+        (ErrorCode::NotMember, "NOT_MEMBER", 403),
+        (ErrorCode::InvalidGroupKey, "INVALID_GROUP_KEY", 400),
+        (ErrorCode::GroupKeyNotFound, "GROUP_KEY_NOT_FOUND", 404),
+        (ErrorCode::RotationNotFound, "ROTATION_NOT_FOUND", 404),
+        (ErrorCode::RevocationNotFound, "REVOCATION_NOT_FOUND", 404),
+        (ErrorCode::InvalidRotation, "INVALID_ROTATION", 400),
+        (ErrorCode::KeyRotated, "KEY_ROTATED", 409),
+        (ErrorCode::KeyRevoked, "KEY_REVOKED", 409),
+        (ErrorCode::StorageUnavailable, "STORAGE_UNAVAILABLE", 503),
+    ];
+
+    #[test]
+    fn known_codes() {
+        for (code, name, status) in CODES {
+            assert_eq!(code.as_str(), *name);
+            assert_eq!(ErrorCode::parse(name), *code);
+            assert_eq!(code.http_status(), *status, "{name}");
+
+            let json = serde_json::to_string(code).unwrap();
+            assert_eq!(json, format!("\"{name}\""));
+            assert_eq!(serde_json::from_str::<ErrorCode>(&json).unwrap(), *code);
+        }
+    }
+
+    #[test]
+    fn unknown_codes_pass_through() {
+        let code = ErrorCode::parse("SOMETHING_NEW");
+
+        assert_eq!(code, ErrorCode::Unknown("SOMETHING_NEW".to_string()));
+        assert_eq!(code.as_str(), "SOMETHING_NEW");
+        assert_eq!(code.http_status(), 500);
+        assert_eq!(
+            serde_json::from_str::<ErrorCode>("\"SOMETHING_NEW\"").unwrap(),
+            code
+        );
+        assert_eq!(serde_json::to_string(&code).unwrap(), "\"SOMETHING_NEW\"");
+    }
+}
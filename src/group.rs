@@ -0,0 +1,141 @@
+//! End-to-end encrypted channels.
+//!
+//! A channel created with [`crate::channel::ENCRYPTED_KEY`] has its members
+//! and their secret set by its creator, one [`GroupKey`] per epoch. Posts and
+//! edits carry the epoch they are sealed for in [`EPOCH_KEY`] and their
+//! content is the [`crate::crypto::SharedKey`] seal of the plain text, so the
+//! server relays them like any other post without being able to read them.
+//!
+//! A member leaves with a post marked [`LEFT_KEY`], which the creator answers
+//! with a new epoch without them.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{text::Post, Signed};
+
+/// Metadata key of posts and edits in an encrypted channel, the epoch whose
+/// secret their content is sealed with.
+pub const EPOCH_KEY: &str = "epoch";
+
+/// The [`EPOCH_KEY`] of a post or edit.
+pub fn epoch_of(metadata: &Option<Map<String, Value>>) -> Option<u64> {
+    metadata.as_ref()?.get(EPOCH_KEY)?.as_u64()
+}
+
+/// Metadata key of a post in an encrypted channel whose author leaves it,
+/// set to `true`. Its sealed content is empty.
+pub const LEFT_KEY: &str = "left";
+
+/// Whether a post with `metadata` is marked with [`LEFT_KEY`].
+pub fn is_leave(metadata: &Option<Map<String, Value>>) -> bool {
+    metadata.as_ref().and_then(|it| it.get(LEFT_KEY)) == Some(&Value::Bool(true))
+}
+
+/// Whether `post` is a member leaving the epoch `latest`, so the creator has
+/// to rekey without its author. A leave sealed for an earlier epoch is stale,
+/// its author may have been invited again since.
+pub fn is_leaving(post: &Signed<Post>, latest: &GroupKey) -> bool {
+    let metadata = &post.data.metadata;
+
+    is_leave(metadata)
+        && epoch_of(metadata) == Some(latest.epoch)
+        && latest.is_member(&post.key)
+        && post.verify()
+}
+
+/// The secret of one epoch of an encrypted channel, signed by the channel's
+/// creator.
+///
+/// `keys` maps every member to the secret sealed with the
+/// [`crate::crypto::SharedKey`] of the creator's and the member's
+/// [`crate::bundle::Bundle::identity_key`]. Every change of members starts
+/// a new epoch with a new secret, so removed members cannot read what is
+/// posted afterwards and new ones cannot read what was posted before.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupKey {
+    pub channel: String,
+    pub epoch: u64,
+    pub keys: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+impl GroupKey {
+    pub fn is_member(&self, key: &str) -> bool {
+        self.keys.contains_key(key)
+    }
+}
+
+/// Looks up the [`GroupKey`] of `epoch` in `channel`, the latest one without
+/// `epoch`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GroupKeyRequest {
+    pub channel: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub epoch: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::crypto::KeyPair;
+
+    fn key_pair() -> KeyPair {
+        KeyPair::from_pkcs8(&KeyPair::generate_pkcs8().unwrap()).unwrap()
+    }
+
+    fn key(key_pair: &KeyPair) -> String {
+        key_pair.public_key().unwrap().to_base64()
+    }
+
+    fn leave(key_pair: &KeyPair, epoch: u64) -> Signed<Post> {
+        let post = Post {
+            channel: "secret".to_string(),
+            content: String::new(),
+            reply_to: None,
+            metadata: Some(Map::from_iter([
+                (EPOCH_KEY.to_string(), json!(epoch)),
+                (LEFT_KEY.to_string(), json!(true)),
+            ])),
+        };
+
+        Signed::new(key_pair, "test".to_string(), 1, post).unwrap()
+    }
+
+    fn group_key(epoch: u64, members: &[&KeyPair]) -> GroupKey {
+        GroupKey {
+            channel: "secret".to_string(),
+            epoch,
+            keys: members.iter().map(|it| (key(it), String::new())).collect(),
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn members_leave_the_latest_epoch() {
+        let (alice, bob) = (key_pair(), key_pair());
+        let latest = group_key(3, &[&alice]);
+
+        assert!(is_leaving(&leave(&alice, 3), &latest));
+
+        // replayed after being invited again, or never a member
+        assert!(!is_leaving(&leave(&alice, 2), &latest));
+        assert!(!is_leaving(&leave(&bob, 3), &latest));
+
+        let mut forged = leave(&bob, 3);
+        forged.key = key(&alice);
+        assert!(!is_leaving(&forged, &latest));
+
+        let mut post = leave(&alice, 3).data;
+        post.metadata.as_mut().unwrap().remove(LEFT_KEY);
+        let message = Signed::new(&alice, "test".to_string(), 1, post).unwrap();
+        assert!(!is_leaving(&message, &latest));
+    }
+}
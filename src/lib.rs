@@ -1,12 +1,23 @@
+pub mod bundle;
+pub mod canonical;
 pub mod channel;
 pub mod crypto;
+pub mod direct;
+pub mod error;
+pub mod group;
 pub mod profile;
 pub mod resource;
+pub mod rotation;
 pub mod text;
 
-use crypto::{KeyPair, PublicKey, Signature};
+use crypto::{sha256_hex, KeyPair, PublicKey, Signature};
+pub use error::{Error, ErrorCode};
 use serde::{Deserialize, Serialize};
 
+/// A value signed by `key`.
+///
+/// The signature covers the canonical encoding (see [`canonical`]) of the
+/// whole object with the `signature` field omitted.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Signed<T: Clone + Serialize> {
     pub key: String,
@@ -20,9 +31,7 @@ pub struct Signed<T: Clone + Serialize> {
 
 impl<T: Clone + Serialize> Signed<T> {
     pub fn new(key_pair: &KeyPair, server: String, timestamp: u64, data: T) -> Option<Self> {
-        let Some(public_key) = key_pair.public_key() else {
-            return None;
-        };
+        let public_key = key_pair.public_key()?;
 
         let mut signed = Signed {
             key: public_key.to_base64(),
@@ -32,13 +41,11 @@ impl<T: Clone + Serialize> Signed<T> {
             signature: String::new(),
         };
 
-        let Ok(serialized) = serde_json::to_string(&signed) else {
+        let Ok(serialized) = canonical::to_vec(&signed) else {
             return None;
         };
 
-        let Some(signature) = key_pair.sign(serialized.as_bytes()) else {
-            return None;
-        };
+        let signature = key_pair.sign(&serialized)?;
 
         signed.signature = signature.to_base64();
 
@@ -57,26 +64,16 @@ impl<T: Clone + Serialize> Signed<T> {
         let mut signed = self.clone();
         signed.signature = String::new();
 
-        let Ok(serialized) = serde_json::to_string(&signed) else {
+        let Ok(serialized) = canonical::to_vec(&signed) else {
             return false;
         };
 
-        public_key.verify(serialized.as_bytes(), &signature)
+        public_key.verify(&serialized, &signature)
     }
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Error {
-    pub status: String,
-    pub message: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub details: Option<serde_json::Value>,
-}
-
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}: '{}'", self.status, self.message)
+    /// Content address of the object, the lowercase hex SHA-256 of its
+    /// canonical encoding with the signature included.
+    pub fn id(&self) -> Option<String> {
+        canonical::to_vec(self).ok().map(|it| sha256_hex(&it))
     }
 }
-
-impl std::error::Error for Error {}
@@ -1,6 +1,12 @@
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
+use crate::crypto::sha256_hex;
+
+/// An uploaded blob, `id` is the lowercase hex SHA-256 of its content.
+///
+/// `metadata` conventionally carries `name` (file name) and `type` (MIME
+/// type), both optional.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Resource {
     pub id: String,
@@ -8,6 +14,18 @@ pub struct Resource {
     pub metadata: Option<Map<String, Value>>,
 }
 
+impl Resource {
+    /// Content address of `data`.
+    pub fn id_of(data: &[u8]) -> String {
+        sha256_hex(data)
+    }
+
+    /// Whether `id` has the shape of a content address.
+    pub fn is_valid_id(id: &str) -> bool {
+        id.len() == 64 && id.bytes().all(|it| matches!(it, b'0'..=b'9' | b'a'..=b'f'))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ResourceRequest {
     pub id: String,
@@ -0,0 +1,98 @@
+//! Moving an identity to a new key.
+//!
+//! A [`Rotation`] signed by the old key names the key that replaces it, a
+//! [`Revocation`] signed by a key marks it as compromised. Each key can be
+//! rotated and revoked at most once, and a revoked key can no longer be
+//! rotated, so following rotations from any key ends after a finite chain.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    canonical,
+    crypto::{KeyPair, PublicKey, Signature},
+    Signed,
+};
+
+/// Statement of `key` that `new_key` replaces it.
+///
+/// `countersignature` is the signature of `new_key` over the canonical
+/// encoding of the statement with both signatures omitted, proving that the
+/// holder of `new_key` agreed to take over. Without it anyone could claim
+/// someone else's key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rotation {
+    #[serde(rename = "newKey")]
+    pub new_key: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub countersignature: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+impl Signed<Rotation> {
+    /// Rotation from `old` to `new`, countersigned by `new`.
+    pub fn countersigned(
+        old: &KeyPair,
+        new: &KeyPair,
+        server: String,
+        timestamp: u64,
+    ) -> Option<Self> {
+        let mut rotation = Rotation {
+            new_key: new.public_key()?.to_base64(),
+            countersignature: String::new(),
+            metadata: None,
+        };
+
+        let unsigned = Signed {
+            key: old.public_key()?.to_base64(),
+            server: server.clone(),
+            timestamp,
+            data: rotation.clone(),
+            signature: String::new(),
+        };
+
+        let serialized = canonical::to_vec(&unsigned).ok()?;
+        rotation.countersignature = new.sign(&serialized)?.to_base64();
+
+        Signed::new(old, server, timestamp, rotation)
+    }
+
+    /// Whether `new_key` countersigned the rotation.
+    pub fn verify_countersignature(&self) -> bool {
+        let Some(public_key) = PublicKey::from_base64(&self.data.new_key) else {
+            return false;
+        };
+
+        let Some(signature) = Signature::from_base64(&self.data.countersignature) else {
+            return false;
+        };
+
+        let mut unsigned = self.clone();
+        unsigned.signature = String::new();
+        unsigned.data.countersignature = String::new();
+
+        let Ok(serialized) = canonical::to_vec(&unsigned) else {
+            return false;
+        };
+
+        public_key.verify(&serialized, &signature)
+    }
+}
+
+/// Statement of `key` that it is compromised, nothing it signed can be
+/// trusted anymore.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Revocation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+/// Looks up the [`Rotation`] or [`Revocation`] of `targetKey`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyStatusRequest {
+    #[serde(rename = "targetKey")]
+    pub target_key: String,
+}
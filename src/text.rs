@@ -1,17 +1,319 @@
+use std::{collections::BTreeMap, fmt};
+
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
+use crate::Signed;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Post {
     pub channel: String,
     pub content: String,
+    /// Id of the post in the same channel this one replies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+/// A new revision of the post with id `target`.
+///
+/// Only valid when signed by the key of that post, the latest edit replaces
+/// the content of the original.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Edit {
+    pub target: String,
+    pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+/// Removes the post with id `target`.
+///
+/// Only valid when signed by the key of that post or by a moderator of the
+/// server. The post is kept as a tombstone without content, so its id stays
+/// valid for anything referencing it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Retraction {
+    pub target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+/// Reacts to the post with id `target` with `emoji`, an emoji or a
+/// `:shortcode:`.
+///
+/// Each key counts once per emoji and post, setting `remove` takes an earlier
+/// reaction back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reaction {
+    pub target: String,
+    pub emoji: String,
+    /// Left out when `false`, so the signed encoding of adding a reaction
+    /// does not depend on it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub remove: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<Map<String, Value>>,
 }
 
+/// A stored post together with its [`Signed::id`] and revisions.
+///
+/// Serialized as the signed post with extra `id`, `thread`, `edits`,
+/// `retraction` and `reactions` members, which are not covered by the
+/// signature, so it can be read as a plain `Signed<Post>` too. A retracted
+/// post no longer verifies, its content and metadata are gone.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostEntry {
+    pub id: String,
+    #[serde(flatten)]
+    pub post: Signed<Post>,
+    /// Id of the post that starts the thread of a reply, set by the server
+    /// by following `reply_to`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread: Option<String>,
+    /// Edits of the post, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub edits: Vec<Signed<Edit>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retraction: Option<Signed<Retraction>>,
+    /// Number of keys that reacted with each emoji.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<String, u64>,
+}
+
+impl PostEntry {
+    pub fn new(post: Signed<Post>) -> Option<Self> {
+        Some(Self {
+            id: post.id()?,
+            post,
+            thread: None,
+            edits: Vec::new(),
+            retraction: None,
+            reactions: BTreeMap::new(),
+        })
+    }
+
+    /// Content of the latest revision.
+    pub fn content(&self) -> &str {
+        self.edits
+            .last()
+            .map_or(&self.post.data.content, |it| &it.data.content)
+    }
+
+    /// Drops edits that are not signed by the author of the post or belong to
+    /// another one, servers are not trusted to have checked.
+    pub fn retain_valid_edits(&mut self) {
+        let (key, id) = (&self.post.key, &self.id);
+
+        self.edits
+            .retain(|it| it.key == *key && it.data.target == *id && it.verify());
+    }
+
+    /// Whether `post.key` really wrote this post: it verifies and has this
+    /// id, or for a tombstone, which no longer verifies, the retraction is
+    /// signed by that key. Servers are not trusted to have checked.
+    pub fn is_authentic(&self) -> bool {
+        match &self.retraction {
+            Some(it) => it.key == self.post.key && it.data.target == self.id && it.verify(),
+            None => self.post.verify() && self.post.id().as_ref() == Some(&self.id),
+        }
+    }
+
+    /// Whether at least one edit has been applied.
+    pub fn is_edited(&self) -> bool {
+        !self.edits.is_empty()
+    }
+
+    pub fn is_retracted(&self) -> bool {
+        self.retraction.is_some()
+    }
+
+    /// Whether this is the post `root` or a reply somewhere below it.
+    pub fn is_in_thread(&self, root: &str) -> bool {
+        self.id == root || self.thread.as_deref() == Some(root)
+    }
+}
+
+/// Metadata key listing the ids of the resources attached to a post.
+pub const RESOURCES_KEY: &str = "resources";
+
+impl Post {
+    /// Ids of the attached resources, see [`RESOURCES_KEY`].
+    pub fn resources(&self) -> Vec<&str> {
+        self.metadata
+            .as_ref()
+            .and_then(|it| it.get(RESOURCES_KEY))
+            .and_then(Value::as_array)
+            .map(|it| it.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Query for posts of a channel.
+///
+/// Results are always ordered oldest to newest. Without `after` the newest
+/// `limit` matching posts are returned, with `after` the oldest ones following
+/// that cursor, so `before` pages backwards and `after` pages forwards.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct PostRequest {
     pub channel: String,
+    /// Only posts with a timestamp at or after this one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+    /// Only posts with a timestamp at or before this one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<u64>,
+    /// Only posts signed by this key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+    /// Only posts strictly older than this [`Cursor`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+    /// Only posts strictly newer than this [`Cursor`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+    /// Only the post with this id and the replies of its thread at any
+    /// depth. Threads are keyed by their first post, see [`PostEntry::thread`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread: Option<String>,
+    /// Also return the tombstones of posts retracted at or after this
+    /// timestamp, appended to the page regardless of the other filters, so
+    /// polling clients learn about retractions of posts they already have.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retracted_since: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+/// Stable position of a post in a channel (or of any other signed object in
+/// its list), ordered by timestamp and then signature. Serialized as
+/// `<timestamp>:<signature>`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor {
+    pub timestamp: u64,
+    pub signature: String,
+}
+
+impl Cursor {
+    pub fn of<T: Clone + Serialize>(signed: &Signed<T>) -> Self {
+        Self {
+            timestamp: signed.timestamp,
+            signature: signed.signature.clone(),
+        }
+    }
+
+    pub fn parse(cursor: &str) -> Option<Self> {
+        let (timestamp, signature) = cursor.split_once(':')?;
+
+        Some(Self {
+            timestamp: timestamp.parse().ok()?,
+            signature: signature.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.timestamp, self.signature)
+    }
+}
+
+/// Sent over the `/stream` socket to choose which channels to receive.
+///
+/// The first message on a socket must be a `Signed<Subscribe>`, later ones
+/// may be sent unsigned to change the subscribed channels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscribe {
+    pub channels: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<Map<String, Value>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KeyPair;
+
+    fn key_pair() -> KeyPair {
+        KeyPair::from_pkcs8(&KeyPair::generate_pkcs8().unwrap()).unwrap()
+    }
+
+    fn edit(key_pair: &KeyPair, target: &str, content: &str) -> Signed<Edit> {
+        let edit = Edit {
+            target: target.to_string(),
+            content: content.to_string(),
+            metadata: None,
+        };
+
+        Signed::new(key_pair, "test".to_string(), 2, edit).unwrap()
+    }
+
+    #[test]
+    fn only_edits_by_the_author_are_kept() {
+        let (author, other) = (key_pair(), key_pair());
+        let post = Post {
+            channel: "general".to_string(),
+            content: "hello".to_string(),
+            reply_to: None,
+            metadata: None,
+        };
+        let mut entry =
+            PostEntry::new(Signed::new(&author, "test".to_string(), 1, post).unwrap()).unwrap();
+
+        let mut forged = edit(&author, &entry.id, "forged");
+        forged.data.content = "tampered".to_string();
+
+        entry.edits = vec![
+            edit(&author, &entry.id, "fixed"),
+            edit(&other, &entry.id, "impostor"),
+            edit(&author, "another post", "elsewhere"),
+            forged,
+        ];
+        entry.retain_valid_edits();
+
+        assert_eq!(entry.edits.len(), 1);
+        assert_eq!(entry.content(), "fixed");
+    }
+
+    #[test]
+    fn only_authentic_posts_are_trusted() {
+        let (author, other) = (key_pair(), key_pair());
+        let post = Post {
+            channel: "general".to_string(),
+            content: "hello".to_string(),
+            reply_to: None,
+            metadata: None,
+        };
+        let entry =
+            PostEntry::new(Signed::new(&author, "test".to_string(), 1, post).unwrap()).unwrap();
+        assert!(entry.is_authentic());
+
+        let mut impostor = entry.clone();
+        impostor.post.key = other.public_key().unwrap().to_base64();
+        assert!(!impostor.is_authentic());
+
+        let mut moved = entry.clone();
+        moved.id = "another post".to_string();
+        assert!(!moved.is_authentic());
+
+        let retraction = |key_pair: &KeyPair| {
+            let retraction = Retraction {
+                target: entry.id.clone(),
+                metadata: None,
+            };
+
+            Some(Signed::new(key_pair, "test".to_string(), 2, retraction).unwrap())
+        };
+
+        let mut tombstone = entry.clone();
+        tombstone.post.data.content.clear();
+        tombstone.retraction = retraction(&author);
+        assert!(tombstone.is_authentic());
+
+        tombstone.retraction = retraction(&other);
+        assert!(!tombstone.is_authentic());
+    }
+}